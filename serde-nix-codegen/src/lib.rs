@@ -0,0 +1,500 @@
+//! Generates Rust types for the NixOS options under a prefix such as `services.nginx`, from the
+//! `options.json` that the NixOS manual is built from. It's at
+//! `share/doc/nixos/options.json` in `config.system.build.manual.optionsJSON`.
+//!
+//! The generated code has one struct per attrset of options, with a field for each option, and
+//! one enum per option that can be one of a few strings. All of them serialize with serde, so
+//! `serde_nix::to_string` writes configuration that has the types the module expects:
+//!
+//! ```ignore
+//! let nginx = Nginx {
+//!     enable: Some(true),
+//!     ..Nginx::default()
+//! };
+//! let config = serde_nix::options::Nested::new(["services", "nginx"], nginx);
+//! assert_eq!(
+//!     serde_nix::to_string(&config).unwrap(),
+//!     "{ services = { nginx = { enable = true; }; }; }",
+//! );
+//! ```
+//!
+//! Every field is an `Option`, and unset ones are left out, so that the defaults of the module
+//! still apply to them. Options that can be null have an `Option` in an `Option`. Option types
+//! without a Rust counterpart, such as `anything` or a choice between types, become a
+//! [`serde_nix::Value`], and packages a [`serde_nix::RawExpr`]. Paths are a [`serde_nix::Path`], so
+//! that they're written as path literals. Read-only options are left out.
+//!
+//! The `serde-nix-codegen` binary takes the path of `options.json` and the prefix, and prints the
+//! code.
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::Deserialize;
+use serde_json::Value as Json;
+
+/// An error generating code.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("can't read options.json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("there are no options under {0:?}")]
+    NoOptions(String),
+}
+
+/// Generates the Rust types for the options under `prefix` in `options_json`. The type for the
+/// prefix itself is named after its last attribute, `Nginx` for `services.nginx`.
+pub fn generate(options_json: &str, prefix: &str) -> Result<String, Error> {
+    let declarations: BTreeMap<String, Declaration> = serde_json::from_str(options_json)?;
+    let prefix: Vec<&str> = prefix.split('.').filter(|name| !name.is_empty()).collect();
+    let mut root = Node::default();
+    for (name, mut declaration) in declarations {
+        let loc = declaration
+            .loc
+            .take()
+            .unwrap_or_else(|| name.split('.').map(str::to_string).collect());
+        if declaration.read_only
+            || loc.len() <= prefix.len()
+            || loc.iter().zip(&prefix).any(|(name, prefix)| name != prefix)
+        {
+            continue;
+        }
+        let mut node = &mut root;
+        for name in &loc[prefix.len()..] {
+            node = node.children.entry(name.clone()).or_default();
+        }
+        node.option = Some(declaration);
+    }
+    let path = prefix.join(".");
+    if root.children.is_empty() {
+        return Err(Error::NoOptions(path));
+    }
+
+    let mut codegen = Codegen::default();
+    let name = codegen.type_name(prefix.last().copied().unwrap_or("options"), "");
+    codegen.emit_struct(&name, &path, &root);
+    let mut code = format!(
+        "// Generated by serde-nix-codegen from the NixOS options under `{}`.\n\nuse serde::Serialize;\n",
+        path,
+    );
+    for item in codegen.items {
+        code.push('\n');
+        code.push_str(&item);
+    }
+    Ok(code)
+}
+
+// An option as it's described in options.json.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Declaration {
+    // The attribute path, which the name is only a rendering of.
+    #[serde(default)]
+    loc: Option<Vec<String>>,
+    #[serde(rename = "type", default)]
+    ty: String,
+    // Present but null if the default is null, and missing if there's none.
+    #[serde(default, deserialize_with = "present")]
+    default: Option<Json>,
+    #[serde(default)]
+    description: Option<Json>,
+    #[serde(default)]
+    read_only: bool,
+}
+
+fn present<'de, D>(deserializer: D) -> Result<Option<Json>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Json::deserialize(deserializer).map(Some)
+}
+
+// The options under an attribute path. Those of submodules are under `<name>` for attrsets and
+// `*` for lists.
+#[derive(Default)]
+struct Node {
+    option: Option<Declaration>,
+    children: BTreeMap<String, Node>,
+}
+
+#[derive(Default)]
+struct Codegen {
+    names: HashSet<String>,
+    items: Vec<String>,
+}
+
+impl Codegen {
+    // A type name for the options under `name`, prefixed with the name of the parent type if it's
+    // taken, which it is for the types the generated code uses.
+    fn type_name(&mut self, name: &str, parent: &str) -> String {
+        if self.names.is_empty() {
+            let used = [
+                "Box",
+                "None",
+                "Option",
+                "Serialize",
+                "Some",
+                "String",
+                "Vec",
+            ];
+            self.names.extend(used.iter().map(|name| name.to_string()));
+        }
+        let mut name = pascal_case(name);
+        if self.names.contains(&name) {
+            name = format!("{}{}", parent, name);
+        }
+        let base = name.clone();
+        let mut n = 2;
+        while self.names.contains(&name) {
+            name = format!("{}{}", base, n);
+            n += 1;
+        }
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn emit_struct(&mut self, name: &str, path: &str, node: &Node) {
+        // Reserve the slot first, so types come after those that use them.
+        let slot = self.items.len();
+        self.items.push(String::new());
+        let mut item = format!(
+            "/// The options under `{}`.\n#[derive(Clone, Debug, Default, PartialEq, Serialize)]\npub struct {} {{\n",
+            path, name,
+        );
+        let mut fields = HashSet::new();
+        for (option, child) in &node.children {
+            if option == "<name>" || option == "*" {
+                continue;
+            }
+            let child_path = format!("{}.{}", path, option);
+            let ty = match &child.option {
+                Some(declaration) => {
+                    write_docs(&mut item, declaration);
+                    let ty = parse_type(&declaration.ty);
+                    self.rust_type(&ty, option, name, &child_path, Some(child))
+                }
+                None => {
+                    let ty = self.type_name(option, name);
+                    self.emit_struct(&ty, &child_path, child);
+                    ty
+                }
+            };
+            let field = field_name(option, &mut fields);
+            if field.trim_start_matches("r#") != option {
+                item.push_str(&format!("    #[serde(rename = {:?})]\n", option));
+            }
+            item.push_str("    #[serde(skip_serializing_if = \"Option::is_none\")]\n");
+            item.push_str(&format!("    pub {}: Option<{}>,\n", field, ty));
+        }
+        item.push_str("}\n");
+        self.items[slot] = item;
+    }
+
+    fn emit_enum(&mut self, name: &str, path: &str, values: &[String]) {
+        let mut item = format!(
+            "/// The values of `{}`.\n#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]\npub enum {} {{\n",
+            path, name,
+        );
+        let mut variants = HashSet::new();
+        for value in values {
+            let mut variant = pascal_case(value);
+            let base = variant.clone();
+            let mut n = 2;
+            while !variants.insert(variant.clone()) {
+                variant = format!("{}{}", base, n);
+                n += 1;
+            }
+            if &variant != value {
+                item.push_str(&format!("    #[serde(rename = {:?})]\n", value));
+            }
+            item.push_str(&format!("    {},\n", variant));
+        }
+        item.push_str("}\n");
+        self.items.push(item);
+    }
+
+    // The Rust type for `ty`. `node` has the options of the submodules in it, if there are any.
+    fn rust_type(
+        &mut self,
+        ty: &Type,
+        option: &str,
+        parent: &str,
+        path: &str,
+        node: Option<&Node>,
+    ) -> String {
+        match ty {
+            Type::Bool => "bool".to_string(),
+            Type::Int(int) => int.to_string(),
+            Type::Float => "f64".to_string(),
+            Type::Str => "String".to_string(),
+            Type::Path => "serde_nix::Path<std::path::PathBuf>".to_string(),
+            Type::Package => "serde_nix::RawExpr".to_string(),
+            Type::Value => "serde_nix::Value".to_string(),
+            Type::Enum(values) => {
+                let name = self.type_name(option, parent);
+                self.emit_enum(&name, path, values);
+                name
+            }
+            Type::Nullable(ty) => {
+                format!("Option<{}>", self.rust_type(ty, option, parent, path, node))
+            }
+            Type::List(ty) => {
+                let elements = node.and_then(|node| node.children.get("*"));
+                let path = format!("{}.*", path);
+                let ty = self.rust_type(ty, option, parent, &path, elements);
+                format!("Vec<{}>", ty)
+            }
+            Type::Attrs(ty) => {
+                let values = node.and_then(|node| node.children.get("<name>"));
+                let path = format!("{}.<name>", path);
+                let ty = self.rust_type(ty, option, parent, &path, values);
+                format!("std::collections::BTreeMap<String, {}>", ty)
+            }
+            Type::Submodule => match node {
+                Some(node) if !node.children.is_empty() => {
+                    let name = self.type_name(option, parent);
+                    self.emit_struct(&name, path, node);
+                    name
+                }
+                _ => "serde_nix::Value".to_string(),
+            },
+        }
+    }
+}
+
+// Writes the description and default of an option as the doc comment of its field.
+fn write_docs(item: &mut String, declaration: &Declaration) {
+    let mut lines = Vec::new();
+    if let Some(description) = declaration.description.as_ref().and_then(text) {
+        let mut code = false;
+        for line in description.trim_end().lines() {
+            if line.trim_start().starts_with("```") {
+                // Code blocks without a language would be run as doc tests.
+                if !code && line.trim() == "```" {
+                    lines.push(line.replace("```", "```text"));
+                    code = true;
+                    continue;
+                }
+                code = !code;
+            }
+            lines.push(line.to_string());
+        }
+    }
+    let default = match &declaration.default {
+        Some(Json::Object(default)) if default.contains_key("_type") => default
+            .get("text")
+            .and_then(Json::as_str)
+            .map(str::to_string),
+        Some(default) => serde_nix::to_string(default).ok(),
+        None => None,
+    };
+    if let Some(default) = default {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        if default.contains('\n') || default.contains('`') {
+            lines.push("Default:".to_string());
+            lines.push(String::new());
+            lines.push("```nix".to_string());
+            lines.extend(default.lines().map(str::to_string));
+            lines.push("```".to_string());
+        } else {
+            lines.push(format!("Default: `{}`", default));
+        }
+    }
+    for line in lines {
+        if line.is_empty() {
+            item.push_str("    ///\n");
+        } else {
+            item.push_str(&format!("    /// {}\n", line.trim_end()));
+        }
+    }
+}
+
+// Descriptions are either strings or, in older versions, `{ _type = "mdDoc"; text = ...; }`.
+fn text(json: &Json) -> Option<&str> {
+    match json {
+        Json::String(text) => Some(text),
+        Json::Object(object) => object.get("text").and_then(Json::as_str),
+        _ => None,
+    }
+}
+
+// The type of an option, from the description of it in options.json.
+#[derive(Debug, PartialEq)]
+enum Type {
+    Bool,
+    Int(&'static str),
+    Float,
+    Str,
+    Path,
+    Package,
+    Enum(Vec<String>),
+    Nullable(Box<Type>),
+    List(Box<Type>),
+    Attrs(Box<Type>),
+    Submodule,
+    Value,
+}
+
+fn parse_type(description: &str) -> Type {
+    let mut ty = description.trim();
+    while let Some(inner) = ty.strip_prefix('(').and_then(|ty| ty.strip_suffix(')')) {
+        if !is_balanced(inner) {
+            break;
+        }
+        ty = inner.trim();
+    }
+    if let Some(ty) = ty.strip_prefix("null or ") {
+        return Type::Nullable(Box::new(parse_type(ty)));
+    }
+    if let Some(list) = ty.strip_prefix("non-empty ") {
+        if list.starts_with("list of ") || list.starts_with("(list of ") {
+            return parse_type(list);
+        }
+    }
+    if let Some(ty) = ty.strip_prefix("list of ") {
+        return Type::List(Box::new(parse_type(ty)));
+    }
+    if let Some(ty) = ty
+        .strip_prefix("attribute set of ")
+        .or_else(|| ty.strip_prefix("lazy attribute set of "))
+    {
+        return Type::Attrs(Box::new(parse_type(ty)));
+    }
+    if let Some(values) = ty.strip_prefix("one of ") {
+        // The values are written like JSON strings.
+        return match serde_json::from_str(&format!("[{}]", values)) {
+            Ok(values) => Type::Enum(values),
+            Err(_) => Type::Value,
+        };
+    }
+    if let Some((bits, int)) = ty.split_once(" bit ") {
+        let int = match (bits, int.split(';').next()) {
+            ("8", Some("signed integer")) => "i8",
+            ("16", Some("signed integer")) => "i16",
+            ("32", Some("signed integer")) => "i32",
+            ("64", Some("signed integer")) => "i64",
+            ("8", Some("unsigned integer")) => "u8",
+            ("16", Some("unsigned integer")) => "u16",
+            ("32", Some("unsigned integer")) => "u32",
+            ("64", Some("unsigned integer")) => "u64",
+            _ => return Type::Value,
+        };
+        return Type::Int(int);
+    }
+    match ty {
+        "boolean" => Type::Bool,
+        "signed integer" => Type::Int("i64"),
+        "floating point number" | "integer or floating point number" => Type::Float,
+        "string" | "lines" | "non-empty string" | "single-line string" | "Concatenated string" => {
+            Type::Str
+        }
+        "path" | "absolute path" => Type::Path,
+        "package" => Type::Package,
+        "submodule" => Type::Submodule,
+        _ if ty.starts_with("unsigned integer") || ty.starts_with("positive integer") => {
+            Type::Int("u64")
+        }
+        _ if ty.starts_with("integer between") => Type::Int("i64"),
+        _ if ty.starts_with("string, ")
+            || ty.starts_with("string matching the pattern")
+            || ty.starts_with("strings concatenated with") =>
+        {
+            Type::Str
+        }
+        _ => Type::Value,
+    }
+}
+
+fn is_balanced(s: &str) -> bool {
+    let mut depth = 0usize;
+    for c in s.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return false,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth == 0
+}
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false",
+    "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where", "while",
+    "abstract", "become", "box", "do", "final", "gen", "macro", "override", "priv", "try",
+    "typeof", "unsized", "virtual", "yield",
+];
+
+// The snake_case field name for an option, unique among `fields`.
+fn field_name(option: &str, fields: &mut HashSet<String>) -> String {
+    let chars: Vec<char> = option.chars().collect();
+    let mut name = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            if !name.is_empty() && !name.ends_with('_') {
+                name.push('_');
+            }
+            continue;
+        }
+        // A word starts at an uppercase letter after a lowercase one or a digit, or at the last
+        // uppercase letter of an acronym, as in `defaultHTTPListenPort`.
+        let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+        let next = chars.get(i + 1);
+        if c.is_ascii_uppercase()
+            && prev.is_some_and(|prev| {
+                prev.is_ascii_lowercase()
+                    || prev.is_ascii_digit()
+                    || (prev.is_ascii_uppercase() && next.is_some_and(char::is_ascii_lowercase))
+            })
+            && !name.ends_with('_')
+        {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    let mut name = name.trim_end_matches('_').to_string();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    if ["self", "super", "crate", "_"].contains(&name.as_str()) {
+        name.push('_');
+    }
+    let base = name.clone();
+    let mut n = 2;
+    while !fields.insert(name.clone()) {
+        name = format!("{}_{}", base, n);
+        n += 1;
+    }
+    if KEYWORDS.contains(&name.as_str()) {
+        format!("r#{}", name)
+    } else {
+        name
+    }
+}
+
+// The PascalCase type or variant name for an option or value.
+fn pascal_case(name: &str) -> String {
+    let mut pascal = String::new();
+    let mut upper = true;
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            if upper {
+                pascal.push(c.to_ascii_uppercase());
+            } else {
+                pascal.push(c);
+            }
+            upper = false;
+        } else {
+            upper = true;
+        }
+    }
+    if pascal.is_empty() || pascal.starts_with(|c: char| c.is_ascii_digit()) {
+        pascal.insert(0, 'V');
+    }
+    if pascal == "Self" {
+        pascal.push('_');
+    }
+    pascal
+}
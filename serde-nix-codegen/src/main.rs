@@ -0,0 +1,23 @@
+use std::{env, fs, process};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("usage: serde-nix-codegen <options.json> <prefix>");
+        process::exit(2);
+    }
+    let json = match fs::read_to_string(&args[0]) {
+        Ok(json) => json,
+        Err(err) => {
+            eprintln!("can't read {}: {}", args[0], err);
+            process::exit(1);
+        }
+    };
+    match serde_nix_codegen::generate(&json, &args[1]) {
+        Ok(code) => print!("{}", code),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use serde_nix::options::Nested;
+use serde_nix::{Path, RawExpr};
+use serde_nix_codegen::{generate, Error};
+
+// The code generated from data/options.json, checked by test_generate.
+#[allow(dead_code)]
+#[path = "data/nginx.rs"]
+mod nginx;
+
+const OPTIONS: &str = include_str!("data/options.json");
+
+#[test]
+fn test_generate() {
+    assert_eq!(
+        generate(OPTIONS, "services.nginx").unwrap(),
+        include_str!("data/nginx.rs"),
+    );
+    assert!(matches!(
+        generate(OPTIONS, "services.nginx.enable"),
+        Err(Error::NoOptions(prefix)) if prefix == "services.nginx.enable",
+    ));
+    assert!(matches!(
+        generate(OPTIONS, "services.ngin"),
+        Err(Error::NoOptions(_)),
+    ));
+    assert!(matches!(generate("[]", "services"), Err(Error::Json(_))));
+}
+
+#[test]
+fn test_generated_types() {
+    use nginx::{Listen, Locations, LogLevel, Nginx, VirtualHosts};
+
+    let mut locations = BTreeMap::new();
+    locations.insert(
+        "/".to_string(),
+        Locations {
+            proxy_pass: Some(Some("http://127.0.0.1:8080".to_string())),
+            ..Locations::default()
+        },
+    );
+    let mut virtual_hosts = BTreeMap::new();
+    virtual_hosts.insert(
+        "example.org".to_string(),
+        VirtualHosts {
+            listen: Some(vec![Listen {
+                addr: Some("0.0.0.0".to_string()),
+                port: Some(None),
+            }]),
+            locations: Some(locations),
+            root: Some(Some(Path("/srv/www".into()))),
+            ..VirtualHosts::default()
+        },
+    );
+    let nginx = Nginx {
+        enable: Some(true),
+        default_http_listen_port: Some(8080),
+        log_level: Some(LogLevel::Warn),
+        package: Some(RawExpr::new("pkgs.nginxMainline")),
+        virtual_hosts: Some(virtual_hosts),
+        ..Nginx::default()
+    };
+    let config = Nested::new(["services", "nginx"], nginx);
+    assert_eq!(
+        serde_nix::to_string(&config).unwrap(),
+        concat!(
+            r#"{ services = { nginx = { defaultHTTPListenPort = 8080; enable = true; logLevel = "warn"; "#,
+            r#"package = pkgs.nginxMainline; virtualHosts = { "example.org" = { listen = [ { addr = "0.0.0.0"; port = null; } ]; "#,
+            r#"locations = { "/" = { proxyPass = "http://127.0.0.1:8080"; }; }; root = /srv/www; }; }; }; }; }"#,
+        ),
+    );
+}
@@ -0,0 +1,185 @@
+// Generated by serde-nix-codegen from the NixOS options under `services.nginx`.
+
+use serde::Serialize;
+
+/// The options under `services.nginx`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Nginx {
+    /// Configuration lines appended to the generated Nginx
+    /// configuration file, for example:
+    ///
+    /// ```text
+    /// events {
+    ///   worker_connections 1024;
+    /// }
+    /// ```
+    ///
+    /// Default: `""`
+    #[serde(rename = "appendConfig")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub append_config: Option<String>,
+    /// Set nginx global client_max_body_size.
+    ///
+    /// Default: `"10m"`
+    #[serde(rename = "clientMaxBodySize")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_max_body_size: Option<String>,
+    /// If vhosts do not specify listen.port, use these ports for HTTP by default.
+    ///
+    /// Default: `80`
+    #[serde(rename = "defaultHTTPListenPort")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_http_listen_port: Option<u16>,
+    /// Whether to enable Nginx Web Server.
+    ///
+    /// Default: `false`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enable: Option<bool>,
+    /// Configures logging.
+    /// The first parameter defines a file that will store the log.
+    ///
+    /// Default: `"stderr"`
+    #[serde(rename = "logError")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_error: Option<String>,
+    /// The minimum severity of logged messages.
+    ///
+    /// Default: `"error"`
+    #[serde(rename = "logLevel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+    /// Nginx package to use.
+    ///
+    /// Default: `pkgs.nginxStable`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<serde_nix::RawExpr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolver: Option<Resolver>,
+    /// Defines a group of servers to use as proxy target.
+    ///
+    /// Default: `{ }`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstreams: Option<std::collections::BTreeMap<String, Upstreams>>,
+    /// Declarative vhost config
+    ///
+    /// Default: `{ localhost = { }; }`
+    #[serde(rename = "virtualHosts")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub virtual_hosts: Option<std::collections::BTreeMap<String, VirtualHosts>>,
+}
+
+/// The values of `services.nginx.logLevel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum LogLevel {
+    #[serde(rename = "debug")]
+    Debug,
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "crit")]
+    Crit,
+}
+
+/// The options under `services.nginx.resolver`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Resolver {
+    /// List of resolvers to use.
+    ///
+    /// Default: `[ ]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<String>>,
+    /// By default, both IPv4 and IPv6 addresses are looked up.
+    ///
+    /// Default: `true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<bool>,
+    /// By default, nginx caches answers using the TTL value of a response.
+    ///
+    /// Default: `""`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valid: Option<String>,
+}
+
+/// The options under `services.nginx.upstreams.<name>`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Upstreams {
+    /// These lines go to the end of the upstream verbatim.
+    ///
+    /// Default: `""`
+    #[serde(rename = "extraConfig")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_config: Option<String>,
+    /// Defines the address and other parameters of the upstream servers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub servers: Option<std::collections::BTreeMap<String, Servers>>,
+}
+
+/// The options under `services.nginx.upstreams.<name>.servers.<name>`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Servers {
+    /// Marks the server as a backup server.
+    ///
+    /// Default: `false`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<bool>,
+}
+
+/// The options under `services.nginx.virtualHosts.<name>`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct VirtualHosts {
+    /// Listen addresses and ports for this virtual host.
+    ///
+    /// Default: `[ ]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<Vec<Listen>>,
+    /// Declarative location config
+    ///
+    /// Default: `{ }`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locations: Option<std::collections::BTreeMap<String, Locations>>,
+    /// The path of the web root directory.
+    ///
+    /// Default: `null`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<Option<serde_nix::Path<std::path::PathBuf>>>,
+    /// Additional names of virtual hosts served by this virtual host configuration.
+    ///
+    /// Default: `[ ]`
+    #[serde(rename = "serverAliases")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_aliases: Option<Vec<String>>,
+}
+
+/// The options under `services.nginx.virtualHosts.<name>.listen.*`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Listen {
+    /// Listen address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+    /// Port number to listen on.
+    /// If unset and the listen address is not a socket then nginx defaults to 80.
+    ///
+    /// Default: `null`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<Option<u16>>,
+}
+
+/// The options under `services.nginx.virtualHosts.<name>.locations.<name>`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Locations {
+    /// Adds proxy_pass directive and sets recommended proxy headers if
+    /// recommendedProxySettings is enabled.
+    ///
+    /// Default: `null`
+    #[serde(rename = "proxyPass")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_pass: Option<Option<String>>,
+    /// Adds a return directive, for e.g. redirections.
+    ///
+    /// Default: `null`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#return: Option<Option<serde_nix::Value>>,
+}
@@ -0,0 +1,389 @@
+//! `#[derive(NixOptions)]` for serde-nix, usually used through its `derive` feature. See the
+//! `serde_nix::options` module for what it generates.
+//!
+//! ```
+//! use serde::Serialize;
+//! use serde_nix::options::NixOptions as _;
+//! use serde_nix_derive::NixOptions;
+//!
+//! #[derive(Default, Serialize, NixOptions)]
+//! #[serde(rename_all = "camelCase")]
+//! struct Foo {
+//!     /// Whether to run foo.
+//!     enable: bool,
+//!     log_level: Level,
+//! }
+//!
+//! #[derive(Default, Serialize, NixOptions)]
+//! #[serde(rename_all = "lowercase")]
+//! enum Level {
+//!     #[default]
+//!     Info,
+//!     Debug,
+//! }
+//!
+//! assert_eq!(
+//!     serde_nix::to_string(&Foo::nix_options()).unwrap(),
+//!     concat!(
+//!         r#"{ enable = lib.mkOption { type = lib.types.bool; default = false; description = "Whether to run foo."; }; "#,
+//!         r#"logLevel = lib.mkOption { type = lib.types.enum [ "info" "debug" ]; default = "info"; }; }"#,
+//!     ),
+//! );
+//! ```
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::meta::ParseNestedMeta;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Expr, Fields, Lit, LitStr, Token};
+
+/// Implements `NixOptions` and `NixType` for a struct with named fields, or `NixType` for an
+/// enum of unit variants.
+#[proc_macro_derive(NixOptions, attributes(nix))]
+pub fn derive_nix_options(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let rename_all = match serde_attrs(&input.attrs)?.rename_all {
+        Some(rule) => Some(RenameRule::parse(&rule).ok_or_else(|| {
+            syn::Error::new_spanned(&input.ident, format!("unknown rename rule {:?}", rule))
+        })?),
+        None => None,
+    };
+    match &input.data {
+        Data::Struct(data) => expand_struct(input, &data.fields, rename_all),
+        Data::Enum(data) => {
+            let mut names = Vec::new();
+            for variant in &data.variants {
+                if !matches!(variant.fields, Fields::Unit) {
+                    return Err(syn::Error::new_spanned(
+                        variant,
+                        "NixOptions can only be derived for enums without data",
+                    ));
+                }
+                let attrs = serde_attrs(&variant.attrs)?;
+                if attrs.skip {
+                    continue;
+                }
+                names.push(attrs.rename.unwrap_or_else(|| {
+                    let name = variant.ident.unraw().to_string();
+                    rename_all.map_or(name.clone(), |rule| rule.apply_to_variant(&name))
+                }));
+            }
+            let ident = &input.ident;
+            let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+            Ok(quote! {
+                impl #impl_generics ::serde_nix::options::NixType for #ident #ty_generics #where_clause {
+                    fn option_type() -> ::serde_nix::options::OptionType {
+                        ::serde_nix::options::OptionType::Enum(::std::vec![
+                            #(::std::string::String::from(#names)),*
+                        ])
+                    }
+                }
+            })
+        }
+        Data::Union(_) => Err(syn::Error::new_spanned(
+            &input.ident,
+            "NixOptions can't be derived for unions",
+        )),
+    }
+}
+
+fn expand_struct(
+    input: &DeriveInput,
+    fields: &Fields,
+    rename_all: Option<RenameRule>,
+) -> syn::Result<TokenStream2> {
+    let fields = match fields {
+        Fields::Named(fields) => &fields.named,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "NixOptions can only be derived for structs with named fields",
+            ))
+        }
+    };
+    let mut options = Vec::new();
+    for field in fields {
+        let attrs = serde_attrs(&field.attrs)?;
+        if attrs.skip {
+            continue;
+        }
+        if attrs.flatten {
+            return Err(syn::Error::new_spanned(
+                field,
+                "NixOptions doesn't support flattened fields",
+            ));
+        }
+        let ident = field.ident.as_ref().expect("named field");
+        let name = attrs.rename.unwrap_or_else(|| {
+            let name = ident.unraw().to_string();
+            rename_all.map_or(name.clone(), |rule| rule.apply_to_field(&name))
+        });
+        let nix = nix_attrs(&field.attrs)?;
+        let ty = match nix.ty {
+            Some(expr) => quote! {
+                ::serde_nix::options::OptionType::Raw(::serde_nix::RawExpr::new(#expr))
+            },
+            None => {
+                let ty = &field.ty;
+                quote!(<#ty as ::serde_nix::options::NixType>::option_type())
+            }
+        };
+        let default = match nix.default {
+            Some(expr) => quote! {
+                ::std::option::Option::Some(::serde_nix::Value::Raw(::serde_nix::RawExpr::new(#expr)))
+            },
+            None => quote!(defaults.as_ref().and_then(|defaults| defaults.get(#name)).cloned()),
+        };
+        let description = match doc_comment(&field.attrs) {
+            Some(doc) => quote!(::std::option::Option::Some(::std::string::String::from(#doc))),
+            None => quote!(::std::option::Option::None),
+        };
+        options.push(quote! {
+            options.0.insert(
+                ::std::string::String::from(#name),
+                ::serde_nix::options::MkOption {
+                    ty: #ty,
+                    default: #default,
+                    description: #description,
+                },
+            );
+        });
+    }
+
+    let ident = &input.ident;
+    // The options of the fields need their types to be `NixType`, so type parameters are too.
+    let mut generics = input.generics.clone();
+    let params: Vec<_> = generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+    let where_clause = generics.make_where_clause();
+    for param in params {
+        where_clause
+            .predicates
+            .push(syn::parse_quote!(#param: ::serde_nix::options::NixType));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::serde_nix::options::NixOptions for #ident #ty_generics #where_clause {
+            fn nix_options() -> ::serde_nix::options::Declarations {
+                // Types without a `Default` implementation have no defaults.
+                #[allow(unused_imports)]
+                use ::serde_nix::__private::{WithDefault as _, WithoutDefault as _};
+                let defaults =
+                    (&::serde_nix::__private::DefaultsOf::<Self>(::std::marker::PhantomData))
+                        .defaults();
+                let mut options = ::serde_nix::options::Declarations::default();
+                #(#options)*
+                options
+            }
+        }
+
+        impl #impl_generics ::serde_nix::options::NixType for #ident #ty_generics #where_clause {
+            fn option_type() -> ::serde_nix::options::OptionType {
+                ::serde_nix::options::OptionType::Submodule(
+                    <Self as ::serde_nix::options::NixOptions>::nix_options(),
+                )
+            }
+        }
+    })
+}
+
+// The serde attributes that change which options there are and what they're called.
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<String>,
+    rename_all: Option<String>,
+    skip: bool,
+    flatten: bool,
+}
+
+fn serde_attrs(attrs: &[Attribute]) -> syn::Result<SerdeAttrs> {
+    let mut serde = SerdeAttrs::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                serde.rename = serialize_name(&meta)?;
+            } else if meta.path.is_ident("rename_all") {
+                serde.rename_all = serialize_name(&meta)?;
+            } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_serializing") {
+                serde.skip = true;
+            } else if meta.path.is_ident("flatten") {
+                serde.flatten = true;
+            } else {
+                skip_meta(&meta)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(serde)
+}
+
+// Reads `rename = "..."`, or the serialize half of `rename(serialize = "...", ...)`.
+fn serialize_name(meta: &ParseNestedMeta) -> syn::Result<Option<String>> {
+    if meta.input.peek(Token![=]) {
+        return Ok(Some(meta.value()?.parse::<LitStr>()?.value()));
+    }
+    let mut name = None;
+    meta.parse_nested_meta(|meta| {
+        if meta.path.is_ident("serialize") {
+            name = Some(meta.value()?.parse::<LitStr>()?.value());
+            Ok(())
+        } else {
+            skip_meta(&meta)
+        }
+    })?;
+    Ok(name)
+}
+
+// Skips over the arguments of an attribute that doesn't matter here.
+fn skip_meta(meta: &ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(Token![=]) {
+        meta.value()?.parse::<Expr>()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        meta.parse_nested_meta(|meta| skip_meta(&meta))?;
+    }
+    Ok(())
+}
+
+// The nix expressions from `#[nix(type = "...", default = "...")]`.
+#[derive(Default)]
+struct NixAttrs {
+    ty: Option<LitStr>,
+    default: Option<LitStr>,
+}
+
+fn nix_attrs(attrs: &[Attribute]) -> syn::Result<NixAttrs> {
+    let mut nix = NixAttrs::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("nix")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("type") {
+                nix.ty = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("default") {
+                nix.default = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown nix attribute, expected `type` or `default`"))
+            }
+        })?;
+    }
+    Ok(nix)
+}
+
+// The doc comment, with the space after each `///` removed.
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<_> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta.require_name_value().ok()?.value {
+            Expr::Lit(syn::ExprLit {
+                lit: Lit::Str(s), ..
+            }) => Some(s.value()),
+            _ => None,
+        })
+        .collect();
+    let doc = lines
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let doc = doc.trim();
+    if doc.is_empty() {
+        None
+    } else {
+        Some(doc.to_string())
+    }
+}
+
+// serde's `rename_all` rules.
+#[derive(Clone, Copy)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+    Kebab,
+    ScreamingKebab,
+}
+
+impl RenameRule {
+    fn parse(rule: &str) -> Option<Self> {
+        Some(match rule {
+            "lowercase" => RenameRule::Lower,
+            "UPPERCASE" => RenameRule::Upper,
+            "PascalCase" => RenameRule::Pascal,
+            "camelCase" => RenameRule::Camel,
+            "snake_case" => RenameRule::Snake,
+            "SCREAMING_SNAKE_CASE" => RenameRule::ScreamingSnake,
+            "kebab-case" => RenameRule::Kebab,
+            "SCREAMING-KEBAB-CASE" => RenameRule::ScreamingKebab,
+            _ => return None,
+        })
+    }
+
+    // Field names are expected in snake_case.
+    fn apply_to_field(self, field: &str) -> String {
+        match self {
+            RenameRule::Lower | RenameRule::Snake => field.to_string(),
+            RenameRule::Upper | RenameRule::ScreamingSnake => field.to_ascii_uppercase(),
+            RenameRule::Pascal | RenameRule::Camel => {
+                let mut name = String::new();
+                let mut capitalize = matches!(self, RenameRule::Pascal);
+                for c in field.chars() {
+                    if c == '_' {
+                        capitalize = true;
+                    } else if capitalize {
+                        name.push(c.to_ascii_uppercase());
+                        capitalize = false;
+                    } else {
+                        name.push(c);
+                    }
+                }
+                name
+            }
+            RenameRule::Kebab => field.replace('_', "-"),
+            RenameRule::ScreamingKebab => field.replace('_', "-").to_ascii_uppercase(),
+        }
+    }
+
+    // Variant names are expected in PascalCase.
+    fn apply_to_variant(self, variant: &str) -> String {
+        match self {
+            RenameRule::Pascal => variant.to_string(),
+            RenameRule::Lower => variant.to_ascii_lowercase(),
+            RenameRule::Upper => variant.to_ascii_uppercase(),
+            RenameRule::Camel => variant[..1].to_ascii_lowercase() + &variant[1..],
+            RenameRule::Snake => {
+                let mut name = String::new();
+                for (i, c) in variant.char_indices() {
+                    if i > 0 && c.is_uppercase() {
+                        name.push('_');
+                    }
+                    name.push(c.to_ascii_lowercase());
+                }
+                name
+            }
+            RenameRule::ScreamingSnake => RenameRule::Snake
+                .apply_to_variant(variant)
+                .to_ascii_uppercase(),
+            RenameRule::Kebab => RenameRule::Snake
+                .apply_to_variant(variant)
+                .replace('_', "-"),
+            RenameRule::ScreamingKebab => RenameRule::ScreamingSnake
+                .apply_to_variant(variant)
+                .replace('_', "-"),
+        }
+    }
+}
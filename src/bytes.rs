@@ -0,0 +1,96 @@
+//! The string encodings of byte buffers that [`Bytes`] chooses between.
+
+// usize::is_multiple_of is only stable since Rust 1.87.
+#![allow(clippy::manual_is_multiple_of)]
+
+use crate::error::Error;
+use crate::ser::Bytes;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// The hash algorithms nix accepts in SRI hashes, by the length of their digests.
+const HASHES: &[(&str, usize)] = &[("md5", 16), ("sha1", 20), ("sha256", 32), ("sha512", 64)];
+
+/// Encodes `bytes` as a string, or returns `None` if they're written as a list.
+pub(crate) fn encode(bytes: &[u8], encoding: Bytes) -> Result<Option<String>, Error> {
+    Ok(Some(match encoding {
+        Bytes::List => return Ok(None),
+        Bytes::Utf8 => std::str::from_utf8(bytes)
+            .map_err(|e| Error::InvalidUtf8(e.valid_up_to()))?
+            .to_string(),
+        Bytes::Base64 => base64(bytes),
+        Bytes::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        Bytes::Sri => match HASHES.iter().find(|&&(_, len)| len == bytes.len()) {
+            Some((algorithm, _)) => format!("{}-{}", algorithm, base64(bytes)),
+            None => return Err(Error::NotAHash(bytes.len())),
+        },
+    }))
+}
+
+/// Decodes a string written by [`encode`], or returns `None` if it isn't valid in `encoding`.
+pub(crate) fn decode(s: &str, encoding: Bytes) -> Option<Vec<u8>> {
+    match encoding {
+        Bytes::List | Bytes::Utf8 => Some(s.as_bytes().to_vec()),
+        Bytes::Base64 => unbase64(s),
+        Bytes::Hex => {
+            if s.len() % 2 != 0 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            (0..s.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+                .collect()
+        }
+        Bytes::Sri => {
+            let (algorithm, digest) = s.split_once('-')?;
+            let &(_, len) = HASHES.iter().find(|&&(name, _)| name == algorithm)?;
+            unbase64(digest).filter(|digest| digest.len() == len)
+        }
+    }
+}
+
+// Standard base64 with padding, as nix writes SRI hashes.
+fn base64(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+fn unbase64(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if s.len() % 4 != 0 {
+        return None;
+    }
+    let data = s
+        .strip_suffix(b"==")
+        .or_else(|| s.strip_suffix(b"="))
+        .unwrap_or(s);
+    let mut bytes = Vec::with_capacity(data.len() * 3 / 4);
+    let (mut n, mut bits) = (0u32, 0);
+    for &c in data {
+        n = n << 6 | BASE64.iter().position(|&b| b == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    // Padding bits must be zero, so every byte string has just one encoding.
+    if n != 0 {
+        return None;
+    }
+    Some(bytes)
+}
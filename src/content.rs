@@ -0,0 +1,520 @@
+//! Values held in memory as serde's data model has them, to be serialized once it's known how
+//! they have to be written.
+
+use serde::ser::{self, Serialize};
+
+use crate::error::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+// A serialized value with the names and tokens it was serialized with, so that serializing it
+// again is the same as serializing the original. Integers are widened, which none of the
+// serializers here tell apart.
+pub(crate) enum Content {
+    Bool(bool),
+    I64(i64),
+    I128(i128),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    Char(char),
+    String(String),
+    Bytes(Vec<u8>),
+    None,
+    Some(Box<Content>),
+    Unit,
+    UnitStruct(&'static str),
+    UnitVariant(&'static str, u32, &'static str),
+    NewtypeStruct(&'static str, Box<Content>),
+    NewtypeVariant(&'static str, u32, &'static str, Box<Content>),
+    Seq(Vec<Content>),
+    Tuple(Vec<Content>),
+    TupleStruct(&'static str, Vec<Content>),
+    TupleVariant(&'static str, u32, &'static str, Vec<Content>),
+    Map(Vec<(Content, Content)>),
+    Struct(&'static str, Vec<(&'static str, Content)>),
+    StructVariant(
+        &'static str,
+        u32,
+        &'static str,
+        Vec<(&'static str, Content)>,
+    ),
+}
+
+pub(crate) fn to_content<T>(value: &T) -> Result<Content>
+where
+    T: ?Sized + Serialize,
+{
+    value.serialize(ContentSerializer)
+}
+
+impl Serialize for Content {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        use serde::ser::{
+            SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple,
+            SerializeTupleStruct, SerializeTupleVariant,
+        };
+
+        match *self {
+            Content::Bool(b) => serializer.serialize_bool(b),
+            Content::I64(i) => serializer.serialize_i64(i),
+            Content::I128(i) => serializer.serialize_i128(i),
+            Content::U64(u) => serializer.serialize_u64(u),
+            Content::U128(u) => serializer.serialize_u128(u),
+            Content::F32(f) => serializer.serialize_f32(f),
+            Content::F64(f) => serializer.serialize_f64(f),
+            Content::Char(c) => serializer.serialize_char(c),
+            Content::String(ref s) => serializer.serialize_str(s),
+            Content::Bytes(ref b) => serializer.serialize_bytes(b),
+            Content::None => serializer.serialize_none(),
+            Content::Some(ref value) => serializer.serialize_some(value),
+            Content::Unit => serializer.serialize_unit(),
+            Content::UnitStruct(name) => serializer.serialize_unit_struct(name),
+            Content::UnitVariant(name, index, variant) => {
+                serializer.serialize_unit_variant(name, index, variant)
+            }
+            Content::NewtypeStruct(name, ref value) => {
+                serializer.serialize_newtype_struct(name, value)
+            }
+            Content::NewtypeVariant(name, index, variant, ref value) => {
+                serializer.serialize_newtype_variant(name, index, variant, value)
+            }
+            Content::Seq(ref elements) => {
+                let mut seq = serializer.serialize_seq(Some(elements.len()))?;
+                for element in elements {
+                    seq.serialize_element(element)?;
+                }
+                seq.end()
+            }
+            Content::Tuple(ref elements) => {
+                let mut tuple = serializer.serialize_tuple(elements.len())?;
+                for element in elements {
+                    tuple.serialize_element(element)?;
+                }
+                tuple.end()
+            }
+            Content::TupleStruct(name, ref fields) => {
+                let mut tuple = serializer.serialize_tuple_struct(name, fields.len())?;
+                for field in fields {
+                    tuple.serialize_field(field)?;
+                }
+                tuple.end()
+            }
+            Content::TupleVariant(name, index, variant, ref fields) => {
+                let mut tuple =
+                    serializer.serialize_tuple_variant(name, index, variant, fields.len())?;
+                for field in fields {
+                    tuple.serialize_field(field)?;
+                }
+                tuple.end()
+            }
+            Content::Map(ref entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            Content::Struct(name, ref fields) => {
+                let mut s = serializer.serialize_struct(name, fields.len())?;
+                for &(key, ref value) in fields {
+                    s.serialize_field(key, value)?;
+                }
+                s.end()
+            }
+            Content::StructVariant(name, index, variant, ref fields) => {
+                let mut s =
+                    serializer.serialize_struct_variant(name, index, variant, fields.len())?;
+                for &(key, ref value) in fields {
+                    s.serialize_field(key, value)?;
+                }
+                s.end()
+            }
+        }
+    }
+}
+
+struct ContentSerializer;
+
+impl ser::Serializer for ContentSerializer {
+    type Ok = Content;
+    type Error = Error;
+
+    type SerializeSeq = SerializeElements;
+    type SerializeTuple = SerializeElements;
+    type SerializeTupleStruct = SerializeElements;
+    type SerializeTupleVariant = SerializeElements;
+    type SerializeMap = SerializeEntries;
+    type SerializeStruct = SerializeFields;
+    type SerializeStructVariant = SerializeFields;
+
+    fn serialize_bool(self, value: bool) -> Result<Content> {
+        Ok(Content::Bool(value))
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<Content> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<Content> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<Content> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<Content> {
+        Ok(Content::I64(value))
+    }
+
+    fn serialize_i128(self, value: i128) -> Result<Content> {
+        Ok(Content::I128(value))
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<Content> {
+        self.serialize_u64(value.into())
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<Content> {
+        self.serialize_u64(value.into())
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<Content> {
+        self.serialize_u64(value.into())
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<Content> {
+        Ok(Content::U64(value))
+    }
+
+    fn serialize_u128(self, value: u128) -> Result<Content> {
+        Ok(Content::U128(value))
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<Content> {
+        Ok(Content::F32(value))
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<Content> {
+        Ok(Content::F64(value))
+    }
+
+    fn serialize_char(self, value: char) -> Result<Content> {
+        Ok(Content::Char(value))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<Content> {
+        Ok(Content::String(value.to_string()))
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<Content> {
+        Ok(Content::Bytes(value.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Content> {
+        Ok(Content::None)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Content>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(Content::Some(Box::new(to_content(value)?)))
+    }
+
+    fn serialize_unit(self) -> Result<Content> {
+        Ok(Content::Unit)
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Content> {
+        Ok(Content::UnitStruct(name))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Content> {
+        Ok(Content::UnitVariant(name, variant_index, variant))
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<Content>
+    where
+        T: ?Sized + Serialize,
+    {
+        Ok(Content::NewtypeStruct(name, Box::new(to_content(value)?)))
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Content>
+    where
+        T: ?Sized + Serialize,
+    {
+        let value = Box::new(to_content(value)?);
+        Ok(Content::NewtypeVariant(name, variant_index, variant, value))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeElements> {
+        Ok(SerializeElements {
+            kind: Elements::Seq,
+            elements: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeElements> {
+        Ok(SerializeElements {
+            kind: Elements::Tuple,
+            elements: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<SerializeElements> {
+        Ok(SerializeElements {
+            kind: Elements::TupleStruct(name),
+            elements: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeElements> {
+        Ok(SerializeElements {
+            kind: Elements::TupleVariant(name, variant_index, variant),
+            elements: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeEntries> {
+        Ok(SerializeEntries {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<SerializeFields> {
+        Ok(SerializeFields {
+            kind: Fields::Struct(name),
+            fields: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeFields> {
+        Ok(SerializeFields {
+            kind: Fields::StructVariant(name, variant_index, variant),
+            fields: Vec::with_capacity(len),
+        })
+    }
+}
+
+enum Elements {
+    Seq,
+    Tuple,
+    TupleStruct(&'static str),
+    TupleVariant(&'static str, u32, &'static str),
+}
+
+struct SerializeElements {
+    kind: Elements,
+    elements: Vec<Content>,
+}
+
+impl SerializeElements {
+    fn push<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.elements.push(to_content(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Content {
+        match self.kind {
+            Elements::Seq => Content::Seq(self.elements),
+            Elements::Tuple => Content::Tuple(self.elements),
+            Elements::TupleStruct(name) => Content::TupleStruct(name, self.elements),
+            Elements::TupleVariant(name, index, variant) => {
+                Content::TupleVariant(name, index, variant, self.elements)
+            }
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeElements {
+    type Ok = Content;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Content> {
+        Ok(SerializeElements::end(self))
+    }
+}
+
+impl ser::SerializeTuple for SerializeElements {
+    type Ok = Content;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Content> {
+        Ok(SerializeElements::end(self))
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeElements {
+    type Ok = Content;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Content> {
+        Ok(SerializeElements::end(self))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeElements {
+    type Ok = Content;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Content> {
+        Ok(SerializeElements::end(self))
+    }
+}
+
+struct SerializeEntries {
+    entries: Vec<(Content, Content)>,
+    next_key: Option<Content>,
+}
+
+impl ser::SerializeMap for SerializeEntries {
+    type Ok = Content;
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.next_key = Some(to_content(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let key = self
+            .next_key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.entries.push((key, to_content(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Content> {
+        Ok(Content::Map(self.entries))
+    }
+}
+
+enum Fields {
+    Struct(&'static str),
+    StructVariant(&'static str, u32, &'static str),
+}
+
+struct SerializeFields {
+    kind: Fields,
+    fields: Vec<(&'static str, Content)>,
+}
+
+impl SerializeFields {
+    fn push<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.fields.push((key, to_content(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Content {
+        match self.kind {
+            Fields::Struct(name) => Content::Struct(name, self.fields),
+            Fields::StructVariant(name, index, variant) => {
+                Content::StructVariant(name, index, variant, self.fields)
+            }
+        }
+    }
+}
+
+impl ser::SerializeStruct for SerializeFields {
+    type Ok = Content;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Content> {
+        Ok(SerializeFields::end(self))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeFields {
+    type Ok = Content;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Content> {
+        Ok(SerializeFields::end(self))
+    }
+}
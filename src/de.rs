@@ -0,0 +1,268 @@
+//! Reads nix values back into Rust.
+//!
+//! Only the subset of the nix language that is plain data is understood: attrsets, lists,
+//! strings, indented strings, integers, floats, booleans, `null` and paths, plus parentheses,
+//! negative numbers and paths like `/. + "/weird path"`. That covers everything
+//! [`crate::ser::Serializer`] writes. Paths are read as strings. The source is parsed with
+//! [`crate::syntax`], so any valid nix is accepted syntactically, but anything that would need a
+//! nix evaluator, like variables, `let`, functions or operators, is rejected with
+//! [`Error::RequiresEvaluation`].
+
+use std::io;
+
+use serde::de::DeserializeOwned;
+
+use crate::error::Error;
+use crate::ser::Bytes;
+use crate::syntax::{self, AttrKind, BinOp, Binding, Expr, ExprKind, Span, StrPart, UnaryOp};
+use crate::value::{self, AttrsBuilder, Map, Value};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Parses a nix expression from a string.
+pub fn from_str<T>(s: &str) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    Options::new().from_str(s)
+}
+
+/// Parses a nix expression from UTF-8 bytes.
+pub fn from_slice<T>(v: &[u8]) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    Options::new().from_slice(v)
+}
+
+/// Parses a nix expression read from `reader`.
+pub fn from_reader<R, T>(reader: R) -> Result<T>
+where
+    R: io::Read,
+    T: DeserializeOwned,
+{
+    Options::new().from_reader(reader)
+}
+
+/// Settings for reading values that a [`Serializer`](crate::ser::Serializer) was set to write in
+/// a form that can't be told apart from others in the source, like byte buffers as strings.
+///
+/// ```
+/// use serde_bytes::ByteBuf;
+/// use serde_nix::de::Options;
+/// use serde_nix::ser::Bytes;
+///
+/// let bytes: ByteBuf = Options::new().bytes(Bytes::Hex).from_str(r#""6869""#).unwrap();
+/// assert_eq!(bytes, b"hi");
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    bytes: Bytes,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    /// Sets how byte buffers are read from strings. Lists of integers are read as byte buffers
+    /// either way. Defaults to [`Bytes::List`], which reads strings as their UTF-8 bytes.
+    pub fn bytes(mut self, encoding: Bytes) -> Self {
+        self.bytes = encoding;
+        self
+    }
+
+    /// Parses a nix expression from a string.
+    pub fn from_str<T>(&self, s: &str) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let expr = syntax::parse(s)?;
+        value::from_value_with_bytes(Converter { src: s }.value(expr)?, self.bytes)
+    }
+
+    /// Parses a nix expression from UTF-8 bytes.
+    pub fn from_slice<T>(&self, v: &[u8]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let s = std::str::from_utf8(v).map_err(|e| Error::Syntax {
+            msg: "invalid UTF-8".to_string(),
+            line: 1,
+            column: e.valid_up_to() + 1,
+        })?;
+        self.from_str(s)
+    }
+
+    /// Parses a nix expression read from `reader`.
+    pub fn from_reader<R, T>(&self, mut reader: R) -> Result<T>
+    where
+        R: io::Read,
+        T: DeserializeOwned,
+    {
+        let mut v = Vec::new();
+        reader.read_to_end(&mut v)?;
+        self.from_slice(&v)
+    }
+}
+
+// Turns a parsed expression into a value, as long as that doesn't need an evaluator.
+struct Converter<'a> {
+    src: &'a str,
+}
+
+impl<'a> Converter<'a> {
+    fn eval_error(&self, span: Span, what: impl Into<String>) -> Error {
+        let (line, column) = syntax::line_column(self.src, span.start);
+        Error::RequiresEvaluation {
+            what: what.into(),
+            line,
+            column,
+        }
+    }
+
+    fn syntax_error(&self, span: Span, msg: impl Into<String>) -> Error {
+        let (line, column) = syntax::line_column(self.src, span.start);
+        Error::Syntax {
+            msg: msg.into(),
+            line,
+            column,
+        }
+    }
+
+    fn value(&self, expr: Expr) -> Result<Value> {
+        let span = expr.span;
+        Ok(match expr.kind {
+            ExprKind::Int(i) => Value::Int(i),
+            ExprKind::Float(f) => Value::Float(f),
+            ExprKind::Str(parts) | ExprKind::IndStr(parts) => Value::String(self.string(parts)?),
+            ExprKind::Path(parts) => Value::Path(self.string(parts)?),
+            ExprKind::Ident(ident) => match ident.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                _ => return Err(self.eval_error(span, format!("variable `{}`", ident))),
+            },
+            ExprKind::List(list) => Value::List(
+                list.into_iter()
+                    .map(|e| self.value(e))
+                    .collect::<Result<_>>()?,
+            ),
+            ExprKind::Attrs {
+                rec: false,
+                bindings,
+            } => Value::Attrs(self.attrs(bindings)?),
+            ExprKind::Attrs { rec: true, .. } => {
+                return Err(self.eval_error(span, "recursive attrset"))
+            }
+            ExprKind::Paren(inner) => self.value(*inner)?,
+            ExprKind::UnaryOp {
+                op: UnaryOp::Negate,
+                expr,
+            } => match self.value(*expr)? {
+                Value::Int(i) => match i.checked_neg() {
+                    Some(negated) => Value::Int(negated),
+                    None => return Err(self.syntax_error(span, "integer overflow")),
+                },
+                Value::Float(f) => Value::Float(-f),
+                _ => return Err(self.eval_error(span, "negating something other than a number")),
+            },
+            // The serializer writes i64::MIN this way, since its absolute value is not a valid
+            // literal.
+            ExprKind::BinOp {
+                op: BinOp::Subtract,
+                lhs,
+                rhs,
+            } if self.is_i64_min(&lhs, &rhs) => Value::Int(i64::MIN),
+            // ... and paths with characters a path literal can't hold like this.
+            ExprKind::BinOp {
+                op: BinOp::Add,
+                lhs,
+                rhs,
+            } if root_or_current_dir(&lhs).is_some() && matches!(rhs.kind, ExprKind::Str(_)) => {
+                let dir = root_or_current_dir(&lhs).unwrap();
+                match self.value(*rhs)? {
+                    Value::String(rest) => Value::Path(format!("{}{}", dir, rest)),
+                    _ => unreachable!(),
+                }
+            }
+            ExprKind::Select { expr, .. } => {
+                self.value(*expr)?;
+                return Err(self.eval_error(span, "attribute selection"));
+            }
+            ExprKind::SearchPath(_) => return Err(self.eval_error(span, "lookup path")),
+            ExprKind::Uri(_) => return Err(self.eval_error(span, "URI")),
+            ExprKind::Let { .. } => return Err(self.eval_error(span, "`let` expression")),
+            ExprKind::With { .. } => return Err(self.eval_error(span, "`with` expression")),
+            ExprKind::Assert { .. } => return Err(self.eval_error(span, "`assert` expression")),
+            ExprKind::If { .. } => return Err(self.eval_error(span, "`if` expression")),
+            ExprKind::Lambda { .. } => return Err(self.eval_error(span, "function")),
+            ExprKind::Apply { .. } => return Err(self.eval_error(span, "function call")),
+            ExprKind::HasAttr { .. } | ExprKind::UnaryOp { .. } | ExprKind::BinOp { .. } => {
+                return Err(self.eval_error(span, "operator"))
+            }
+        })
+    }
+
+    fn is_i64_min(&self, lhs: &Expr, rhs: &Expr) -> bool {
+        let is_max = match &lhs.kind {
+            ExprKind::UnaryOp {
+                op: UnaryOp::Negate,
+                expr,
+            } => expr.kind == ExprKind::Int(i64::MAX),
+            _ => false,
+        };
+        is_max && rhs.kind == ExprKind::Int(1)
+    }
+
+    fn string(&self, parts: Vec<StrPart>) -> Result<String> {
+        let mut s = String::new();
+        for part in parts {
+            match part {
+                StrPart::Literal(lit) => s.push_str(&lit),
+                StrPart::Interpolation(expr) => {
+                    return Err(self.eval_error(expr.span, "string interpolation"))
+                }
+            }
+        }
+        Ok(s)
+    }
+
+    fn attrs(&self, bindings: Vec<Binding>) -> Result<Map> {
+        let mut attrs = AttrsBuilder::default();
+        for binding in bindings {
+            let (path, value, span) = match binding {
+                Binding::Attr { path, value, span } => (path, value, span),
+                Binding::Inherit { span, .. } => return Err(self.eval_error(span, "`inherit`")),
+            };
+            let path = path
+                .attrs
+                .into_iter()
+                .map(|attr| match attr.kind {
+                    AttrKind::Ident(name) => Ok(name),
+                    AttrKind::Str(parts) => self.string(parts),
+                    AttrKind::Dynamic(_) => {
+                        Err(self.eval_error(attr.span, "dynamic attribute name"))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let value = self.value(value)?;
+            attrs.try_insert(path, value).map_err(|defined| {
+                self.syntax_error(span, format!("attribute '{}' already defined", defined))
+            })?;
+        }
+        Ok(attrs.finish())
+    }
+}
+
+// What to prefix the string appended to `/.` or `./.` with to get the path nix evaluates it to.
+fn root_or_current_dir(expr: &Expr) -> Option<&'static str> {
+    match &expr.kind {
+        ExprKind::Path(parts) => match parts.as_slice() {
+            [StrPart::Literal(p)] if p == "/." => Some(""),
+            [StrPart::Literal(p)] if p == "./." => Some("."),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,140 @@
+//! Finds attrsets and lists that occur more than once in a value, so they can be written once in
+//! a `let` and referred to by name everywhere else.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::raw::RawExpr;
+use crate::value::{Map, Value};
+
+/// Splits `value` into `let` bindings for its repeated attrsets and lists of at least `min_size`
+/// values, and the value with those replaced by the names they're bound to.
+pub(crate) fn deduplicate(value: Value, min_size: usize) -> (Vec<(String, Value)>, Value) {
+    let mut dedup = Dedup::default();
+    let node = dedup.intern(&value);
+    dedup.count(&node);
+    dedup.min_size = min_size.max(2);
+    let body = dedup.rewrite(value, &node, true);
+    (dedup.bindings, body)
+}
+
+// Structurally identical values get the same key, and so the same id.
+#[derive(PartialEq, Eq, Hash)]
+enum Key {
+    Leaf(String),
+    List(Vec<usize>),
+    Attrs(Vec<(String, usize)>),
+}
+
+// The id of a value, with the ids of its elements or attribute values in order.
+struct Node {
+    id: usize,
+    children: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Dedup {
+    ids: HashMap<Key, usize>,
+    // By id: the number of values in the subtree, and whether it's a list or attrset.
+    sizes: Vec<usize>,
+    compound: Vec<bool>,
+    // By id: how often it occurs, not counting inside the occurrences after the first, which will
+    // be replaced by a name if they're repeated at all.
+    counts: Vec<usize>,
+    min_size: usize,
+    // Words used in raw expressions, which the bindings must not shadow.
+    taken: HashSet<String>,
+    names: HashMap<usize, String>,
+    next_name: usize,
+    bindings: Vec<(String, Value)>,
+}
+
+impl Dedup {
+    fn intern(&mut self, value: &Value) -> Node {
+        let (key, children) = match value {
+            Value::List(list) => {
+                let children: Vec<_> = list.iter().map(|v| self.intern(v)).collect();
+                (Key::List(children.iter().map(|n| n.id).collect()), children)
+            }
+            Value::Attrs(attrs) => {
+                let children: Vec<_> = attrs.values().map(|v| self.intern(v)).collect();
+                let key = attrs.keys().cloned().zip(children.iter().map(|n| n.id));
+                (Key::Attrs(key.collect()), children)
+            }
+            Value::Raw(raw) => {
+                let words = raw
+                    .as_str()
+                    .split(|c: char| !(c.is_ascii_alphanumeric() || "_'-".contains(c)));
+                self.taken.extend(words.map(str::to_string));
+                (Key::Leaf(format!("{:?}", value)), Vec::new())
+            }
+            _ => (Key::Leaf(format!("{:?}", value)), Vec::new()),
+        };
+        let compound = !matches!(key, Key::Leaf(_));
+        let next_id = self.sizes.len();
+        let id = *self.ids.entry(key).or_insert(next_id);
+        if id == next_id {
+            self.sizes
+                .push(1 + children.iter().map(|n| self.sizes[n.id]).sum::<usize>());
+            self.compound.push(compound);
+            self.counts.push(0);
+        }
+        Node { id, children }
+    }
+
+    fn count(&mut self, node: &Node) {
+        self.counts[node.id] += 1;
+        if self.counts[node.id] == 1 {
+            for child in &node.children {
+                self.count(child);
+            }
+        }
+    }
+
+    fn is_hoisted(&self, id: usize) -> bool {
+        self.compound[id] && self.counts[id] > 1 && self.sizes[id] >= self.min_size
+    }
+
+    fn fresh_name(&mut self) -> String {
+        loop {
+            let name = format!("v{}", self.next_name);
+            self.next_name += 1;
+            if !self.taken.contains(&name) {
+                return name;
+            }
+        }
+    }
+
+    fn rewrite(&mut self, value: Value, node: &Node, definition: bool) -> Value {
+        if !definition && self.is_hoisted(node.id) {
+            let name = match self.names.get(&node.id) {
+                Some(name) => name.clone(),
+                None => {
+                    let name = self.fresh_name();
+                    self.names.insert(node.id, name.clone());
+                    // Reserve the slot first, so bindings come out in the order they're named.
+                    let slot = self.bindings.len();
+                    self.bindings.push((name.clone(), Value::Null));
+                    self.bindings[slot].1 = self.rewrite(value, node, true);
+                    name
+                }
+            };
+            return Value::Raw(RawExpr::new(name));
+        }
+        match value {
+            Value::List(list) => Value::List(
+                list.into_iter()
+                    .zip(&node.children)
+                    .map(|(v, n)| self.rewrite(v, n, false))
+                    .collect(),
+            ),
+            Value::Attrs(attrs) => Value::Attrs(
+                attrs
+                    .into_iter()
+                    .zip(&node.children)
+                    .map(|((k, v), n)| (k, self.rewrite(v, n, false)))
+                    .collect::<Map>(),
+            ),
+            value => value,
+        }
+    }
+}
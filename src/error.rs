@@ -0,0 +1,95 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::syntax;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A string, attribute name, path or comment had a null character, which nix can't hold. Has
+    /// the attribute path to where it was, like `services.foo."bar baz"[2]`, or is empty if it
+    /// was at the top level.
+    #[error("nix strings may not contain null bytes{}", at_path(.0))]
+    UnencodableNullString(String),
+    #[error("nix map keys must be strings")]
+    MapKeyMustBeAString,
+    #[error("{0} does not fit in a nix integer")]
+    IntegerOutOfRange(String),
+    #[error("nix has no literal for the float {0}")]
+    NonFiniteFloat(f64),
+    #[error("{0:?} can't be written as a nix path")]
+    InvalidPath(String),
+    #[error("bytes are not valid UTF-8 from index {0} on")]
+    InvalidUtf8(usize),
+    #[error("{0} bytes is not the length of any hash nix supports")]
+    NotAHash(usize),
+    #[error("{0:?} can't be the name of a module argument")]
+    InvalidModuleArg(String),
+    #[error("nix modules must be attrsets")]
+    ModuleMustBeAnAttrset,
+    #[error("{msg} at line {line} column {column}")]
+    Syntax {
+        msg: String,
+        line: usize,
+        column: usize,
+    },
+    #[error("{what} at line {line} column {column} would need evaluation, which is not supported")]
+    RequiresEvaluation {
+        what: String,
+        line: usize,
+        column: usize,
+    },
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl Error {
+    // Adds the attribute `name` to the front of the path of an error about something under it.
+    pub(crate) fn in_attr(self, name: &str) -> Error {
+        let name = if syntax::is_ident(name) {
+            name.to_string()
+        } else {
+            format!("{:?}", name)
+        };
+        self.in_path(name)
+    }
+
+    // Adds the list element `index` to the front of the path of an error about something in it.
+    pub(crate) fn in_element(self, index: usize) -> Error {
+        self.in_path(format!("[{}]", index))
+    }
+
+    fn in_path(self, segment: String) -> Error {
+        match self {
+            Error::UnencodableNullString(path) if path.is_empty() || path.starts_with('[') => {
+                Error::UnencodableNullString(segment + &path)
+            }
+            Error::UnencodableNullString(path) => {
+                Error::UnencodableNullString(segment + "." + &path)
+            }
+            err => err,
+        }
+    }
+}
+
+fn at_path(path: &str) -> String {
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!(" (at {})", path)
+    }
+}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Custom(format!("{}", msg))
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Custom(format!("{}", msg))
+    }
+}
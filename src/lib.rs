@@ -1,3 +1,37 @@
+#[macro_use]
+mod macros;
+
+mod bytes;
+mod content;
+mod dedup;
+mod error;
+
+pub mod de;
+mod mk;
+pub mod options;
+pub mod path;
+mod raw;
 pub mod ser;
+pub mod syntax;
+pub mod value;
+
+pub use de::{from_reader, from_slice, from_str};
+pub use error::Error;
+pub use mk::{MkAfter, MkBefore, MkDefault, MkForce, MkIf, MkMerge, MkOverride};
+pub use options::NixOptions;
+pub use path::Path;
+pub use raw::RawExpr;
+pub use ser::{
+    to_module_string, to_module_string_pretty, to_string, to_string_deduplicated, to_string_pretty,
+    to_writer, to_writer_pretty, Commented, IndentedString, Module, OmitNull,
+};
+pub use value::{from_value, to_value, Value};
 
-pub use ser::to_string;
+// Used by the nix! macro and the NixOptions derive.
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::options::{DefaultsOf, WithDefault, WithoutDefault};
+    pub use crate::value::AttrsBuilder;
+    pub use std::string::{String, ToString};
+    pub use std::vec;
+}
@@ -0,0 +1,122 @@
+/// Builds a [`Value`](crate::Value) from nix-like syntax.
+///
+/// ```
+/// # use serde_nix::nix;
+/// let ports = vec![80, 443];
+/// let value = nix!({
+///     services.nginx.enable = true;
+///     services.nginx.user = "www";
+///     networking.firewall.allowedTCPPorts = ports;
+///     "ssl-cert" = null;
+///     extra = [ 1 "two" (-3) [ ] ];
+/// });
+/// assert_eq!(
+///     serde_nix::to_string(&value).unwrap(),
+///     r#"{ services = { nginx = { enable = true; user = "www"; }; }; networking = { firewall = { allowedTCPPorts = [ 80 443 ]; }; }; ssl-cert = null; extra = [ 1 "two" (-3) [ ] ]; }"#,
+/// );
+/// ```
+///
+/// Attribute names are identifiers, string literals, or a parenthesized Rust expression, joined
+/// by `.` into attribute paths that are merged the way nix merges them. Attribute values may be
+/// any Rust expression implementing [`Serialize`](serde::Serialize). As in nix, list elements are
+/// separated by whitespace, so anything more than a single token, such as `-3` or `x.y`, has to
+/// be wrapped in parentheses.
+///
+/// Like `serde_json::json!`, this panics if an interpolated value can't be converted with
+/// [`to_value`](crate::to_value), or if an attribute is defined twice.
+#[macro_export]
+macro_rules! nix {
+    ($($nix:tt)+) => {
+        $crate::nix_internal!($($nix)+)
+    };
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! nix_internal {
+    //////////////////////////////////////////////////////////////////////////
+    // Lists: every token tree is one element.
+    //////////////////////////////////////////////////////////////////////////
+    (@list [$($elems:expr,)*]) => {
+        $crate::__private::vec![$($elems,)*]
+    };
+
+    (@list [$($elems:expr,)*] $next:tt $($rest:tt)*) => {
+        $crate::nix_internal!(@list [$($elems,)* $crate::nix_internal!($next),] $($rest)*)
+    };
+
+    //////////////////////////////////////////////////////////////////////////
+    // Attrsets: collect the attribute path up to `=`, then the value up to `;`.
+    //////////////////////////////////////////////////////////////////////////
+    (@attrs $map:ident []) => {};
+
+    (@attrs $map:ident [$($path:expr,)+] = $($rest:tt)*) => {
+        $crate::nix_internal!(@value $map [$($path,)+] () $($rest)*)
+    };
+
+    (@attrs $map:ident [$($path:expr,)+] . $($rest:tt)*) => {
+        $crate::nix_internal!(@attrs $map [$($path,)+] $($rest)*)
+    };
+
+    (@attrs $map:ident [$($path:expr,)*] ($key:expr) $($rest:tt)*) => {
+        $crate::nix_internal!(
+            @attrs $map [$($path,)* $crate::__private::ToString::to_string(&$key),] $($rest)*
+        )
+    };
+
+    (@attrs $map:ident [$($path:expr,)*] $key:ident $($rest:tt)*) => {
+        $crate::nix_internal!(
+            @attrs $map [$($path,)* $crate::__private::String::from(stringify!($key)),] $($rest)*
+        )
+    };
+
+    (@attrs $map:ident [$($path:expr,)*] $key:literal $($rest:tt)*) => {
+        $crate::nix_internal!(
+            @attrs $map [$($path,)* $crate::__private::String::from($key),] $($rest)*
+        )
+    };
+
+    (@value $map:ident [$($path:expr,)+] ($($value:tt)+) ; $($rest:tt)*) => {
+        $map.insert(
+            $crate::__private::vec![$($path,)+],
+            $crate::nix_internal!($($value)+),
+        );
+        $crate::nix_internal!(@attrs $map [] $($rest)*);
+    };
+
+    (@value $map:ident [$($path:expr,)+] ($($value:tt)*) $next:tt $($rest:tt)*) => {
+        $crate::nix_internal!(@value $map [$($path,)+] ($($value)* $next) $($rest)*)
+    };
+
+    //////////////////////////////////////////////////////////////////////////
+    // Values.
+    //////////////////////////////////////////////////////////////////////////
+    (null) => {
+        $crate::Value::Null
+    };
+
+    (true) => {
+        $crate::Value::Bool(true)
+    };
+
+    (false) => {
+        $crate::Value::Bool(false)
+    };
+
+    ([ $($tt:tt)* ]) => {
+        $crate::Value::List($crate::nix_internal!(@list [] $($tt)*))
+    };
+
+    ({ $($tt:tt)* }) => {
+        $crate::Value::Attrs({
+            #[allow(unused_mut)]
+            let mut map = $crate::__private::AttrsBuilder::default();
+            $crate::nix_internal!(@attrs map [] $($tt)*);
+            map.finish()
+        })
+    };
+
+    ($other:expr) => {
+        $crate::to_value(&$other).unwrap()
+    };
+}
@@ -0,0 +1,153 @@
+//! The functions of the NixOS module system that set the priority of a definition, make it
+//! conditional or combine several.
+//!
+//! Each wrapper is written as a call to the `lib` function of the same name, so the module has to
+//! take `lib` as an argument, as [`Module::new`](crate::Module::new) does:
+//!
+//! ```
+//! use serde::Serialize;
+//! use serde_nix::{MkForce, MkIf, MkOverride, RawExpr};
+//!
+//! #[derive(Serialize)]
+//! struct Nginx {
+//!     enable: MkForce<bool>,
+//!     user: MkOverride<&'static str>,
+//!     ports: MkIf<Vec<i32>>,
+//! }
+//!
+//! let nginx = Nginx {
+//!     enable: MkForce(true),
+//!     user: MkOverride(900, "www"),
+//!     ports: MkIf(RawExpr::new("config.networking.enableIPv6"), vec![80, -1]),
+//! };
+//! assert_eq!(
+//!     serde_nix::to_string(&nginx).unwrap(),
+//!     r#"{ enable = lib.mkForce true; user = lib.mkOverride 900 "www"; ports = lib.mkIf config.networking.enableIPv6 [ 80 (-1) ]; }"#,
+//! );
+//! ```
+//!
+//! The arguments are parenthesized where they need to be, and so is the call itself where it's
+//! a list element or the argument of another call. Other serializers see a struct with the name
+//! of the function and its arguments, and [`to_value`](crate::to_value) turns them into a
+//! [`RawExpr`](crate::RawExpr).
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::raw::RawExpr;
+
+// Struct name marking a function call. The function is in a field of the same name, followed by
+// one field per argument.
+pub(crate) const APPLY_TOKEN: &str = "$serde_nix::private::Apply";
+
+// A call of `function` with a single argument.
+pub(crate) struct Call<'a, T: ?Sized>(pub &'static str, pub &'a T);
+
+impl<'a, T> Serialize for Call<'a, T>
+where
+    T: ?Sized + Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct(APPLY_TOKEN, 2)?;
+        s.serialize_field(APPLY_TOKEN, self.0)?;
+        s.serialize_field("value", self.1)?;
+        s.end()
+    }
+}
+
+macro_rules! mk_wrapper {
+    ($(#[$attr:meta])* $name:ident<$t:ident>($inner:ty) => $function:literal) => {
+        $(#[$attr])*
+        pub struct $name<$t>(pub $inner);
+
+        impl<$t> Serialize for $name<$t>
+        where
+            $t: Serialize,
+        {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                Call($function, &self.0).serialize(serializer)
+            }
+        }
+    };
+}
+
+mk_wrapper! {
+    /// A definition that only applies if nothing else defines the option, written as
+    /// `lib.mkDefault value`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    MkDefault<T>(T) => "lib.mkDefault"
+}
+
+mk_wrapper! {
+    /// A definition that overrides any other, written as `lib.mkForce value`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    MkForce<T>(T) => "lib.mkForce"
+}
+
+mk_wrapper! {
+    /// Several definitions of the same option, written as `lib.mkMerge [ ... ]`. Useful to
+    /// combine definitions with different conditions or priorities.
+    #[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    MkMerge<T>(Vec<T>) => "lib.mkMerge"
+}
+
+mk_wrapper! {
+    /// A list definition that goes before those of other modules, written as `lib.mkBefore value`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    MkBefore<T>(T) => "lib.mkBefore"
+}
+
+mk_wrapper! {
+    /// A list definition that goes after those of other modules, written as `lib.mkAfter value`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    MkAfter<T>(T) => "lib.mkAfter"
+}
+
+/// A definition with an explicit priority, written as `lib.mkOverride priority value`. Lower
+/// numbers win: `mkForce` is 50, plain definitions 100 and `mkDefault` 1000.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MkOverride<T>(pub i64, pub T);
+
+impl<T> Serialize for MkOverride<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct(APPLY_TOKEN, 3)?;
+        s.serialize_field(APPLY_TOKEN, "lib.mkOverride")?;
+        s.serialize_field("priority", &self.0)?;
+        s.serialize_field("value", &self.1)?;
+        s.end()
+    }
+}
+
+/// A definition that only applies if the condition holds, written as `lib.mkIf condition value`.
+/// The condition is usually an expression on `config` in a [`RawExpr`], but can be anything that
+/// serializes to a bool.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MkIf<T, C = RawExpr>(pub C, pub T);
+
+impl<T, C> Serialize for MkIf<T, C>
+where
+    T: Serialize,
+    C: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct(APPLY_TOKEN, 3)?;
+        s.serialize_field(APPLY_TOKEN, "lib.mkIf")?;
+        s.serialize_field("condition", &self.0)?;
+        s.serialize_field("value", &self.1)?;
+        s.end()
+    }
+}
@@ -0,0 +1,372 @@
+//! Declaring the NixOS options that a Rust configuration type is serialized into.
+//!
+//! A module that takes configuration also has to declare it, as an attrset of `lib.mkOption`
+//! calls with the type, default and description of each option. [`NixOptions`] produces those
+//! declarations from the Rust type, so they can't drift apart from it. It's usually derived, with
+//! the `derive` feature enabled:
+//!
+//! ```
+//! # #[cfg(feature = "derive")]
+//! # fn main() {
+//! use serde::Serialize;
+//! use serde_nix::options::{Nested, NixOptions};
+//! use serde_nix::Module;
+//!
+//! #[derive(Default, Serialize, NixOptions)]
+//! #[serde(rename_all = "camelCase")]
+//! struct Foo {
+//!     /// Whether to run foo.
+//!     enable: bool,
+//!     /// Ports foo listens on.
+//!     listen_ports: Vec<u16>,
+//! }
+//!
+//! let module = Module::new().options(Nested::new(["services", "foo"], Foo::nix_options()));
+//! # }
+//! # #[cfg(not(feature = "derive"))]
+//! # fn main() {}
+//! ```
+//!
+//! which declares `services.foo.enable` as a `lib.types.bool` defaulting to `false`, and
+//! `services.foo.listenPorts` as a `lib.types.listOf lib.types.int` defaulting to `[ ]`.
+//!
+//! The derive names options the way serde names the fields, following `rename`, `rename_all`
+//! and `skip`. Defaults come from the type's [`Default`] implementation, serialized as a whole so
+//! that serde's field attributes apply to them too, and are left out if there is none or it
+//! can't be serialized. Generic types don't get them from `Default`, which may not hold for every
+//! type argument. Descriptions come from the doc comments of the fields. The type of an option
+//! comes from [`NixType`], which the derive also implements, requiring it of type parameters: a
+//! struct is a `lib.types.submodule` with its own options, an enum of unit variants a
+//! `lib.types.enum` of their names. `#[nix(type = "lib.types.port")]` on a field overrides the
+//! type with any nix expression, and `#[nix(default = "8080")]` the default.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
+
+use indexmap::IndexMap;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::mk::Call;
+use crate::raw::RawExpr;
+use crate::ser::{Commented, IndentedString, OmitNull};
+use crate::value::{Map, Value};
+
+#[cfg(feature = "derive")]
+pub use serde_nix_derive::NixOptions;
+
+/// A type whose fields are configured through NixOS options.
+pub trait NixOptions {
+    /// The declarations of the options, one per field.
+    fn nix_options() -> Declarations;
+}
+
+/// A type with a NixOS option type, as in the `type` of `lib.mkOption`.
+pub trait NixType {
+    fn option_type() -> OptionType;
+}
+
+/// The type of a NixOS option. All of them are written as `lib.types.*`, so the module has to
+/// take `lib` as an argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionType {
+    Str,
+    Int,
+    Float,
+    Bool,
+    Path,
+    /// Any value, for options without a more specific type.
+    Anything,
+    ListOf(Box<OptionType>),
+    AttrsOf(Box<OptionType>),
+    NullOr(Box<OptionType>),
+    /// One of the given strings.
+    Enum(Vec<String>),
+    /// An attrset with options of its own.
+    Submodule(Declarations),
+    /// Any other type, written as is.
+    Raw(RawExpr),
+}
+
+impl Serialize for OptionType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let name = match self {
+            OptionType::Str => "lib.types.str",
+            OptionType::Int => "lib.types.int",
+            OptionType::Float => "lib.types.float",
+            OptionType::Bool => "lib.types.bool",
+            OptionType::Path => "lib.types.path",
+            OptionType::Anything => "lib.types.anything",
+            OptionType::ListOf(ty) => return Call("lib.types.listOf", ty).serialize(serializer),
+            OptionType::AttrsOf(ty) => return Call("lib.types.attrsOf", ty).serialize(serializer),
+            OptionType::NullOr(ty) => return Call("lib.types.nullOr", ty).serialize(serializer),
+            OptionType::Enum(names) => return Call("lib.types.enum", names).serialize(serializer),
+            OptionType::Submodule(options) => {
+                return Call("lib.types.submodule", &SubmoduleArgs(options)).serialize(serializer)
+            }
+            OptionType::Raw(raw) => return raw.serialize(serializer),
+        };
+        RawExpr::new(name).serialize(serializer)
+    }
+}
+
+struct SubmoduleArgs<'a>(&'a Declarations);
+
+impl<'a> Serialize for SubmoduleArgs<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry("options", self.0)?;
+        map.end()
+    }
+}
+
+/// The declaration of a single option, written as `lib.mkOption { ... }`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MkOption {
+    pub ty: OptionType,
+    pub default: Option<Value>,
+    pub description: Option<String>,
+}
+
+impl MkOption {
+    /// An option of the given type, without a default or description.
+    pub fn new(ty: OptionType) -> Self {
+        MkOption {
+            ty,
+            default: None,
+            description: None,
+        }
+    }
+}
+
+impl Serialize for MkOption {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Call("lib.mkOption", &MkOptionArgs(self)).serialize(serializer)
+    }
+}
+
+struct MkOptionArgs<'a>(&'a MkOption);
+
+impl<'a> Serialize for MkOptionArgs<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("type", &self.0.ty)?;
+        if let Some(default) = &self.0.default {
+            map.serialize_entry("default", default)?;
+        }
+        if let Some(description) = &self.0.description {
+            map.serialize_entry("description", description)?;
+        }
+        map.end()
+    }
+}
+
+/// Option declarations by name, in the order they will be written.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Declarations(pub IndexMap<String, MkOption>);
+
+impl Serialize for Declarations {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, option) in &self.0 {
+            map.serialize_entry(name, option)?;
+        }
+        map.end()
+    }
+}
+
+/// A value nested in attrsets along an attribute path, such as the options of a service under
+/// `services.foo`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Nested<T> {
+    path: Vec<String>,
+    value: T,
+}
+
+impl<T> Nested<T> {
+    pub fn new<I>(path: I, value: T) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Nested {
+            path: path.into_iter().map(Into::into).collect(),
+            value,
+        }
+    }
+}
+
+impl<T> Serialize for Nested<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        NestedAt(&self.path, &self.value).serialize(serializer)
+    }
+}
+
+struct NestedAt<'a, T>(&'a [String], &'a T);
+
+impl<'a, T> Serialize for NestedAt<'a, T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.0.split_first() {
+            Some((name, rest)) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(name, &NestedAt(rest, self.1))?;
+                map.end()
+            }
+            None => self.1.serialize(serializer),
+        }
+    }
+}
+
+macro_rules! nix_type {
+    ($ty:expr => $($t:ty)*) => {
+        $(
+            impl NixType for $t {
+                fn option_type() -> OptionType {
+                    $ty
+                }
+            }
+        )*
+    };
+}
+
+nix_type!(OptionType::Bool => bool);
+nix_type!(OptionType::Int => i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);
+nix_type!(OptionType::Float => f32 f64);
+nix_type!(OptionType::Str => char str String);
+nix_type!(OptionType::Path => std::path::Path std::path::PathBuf);
+nix_type!(OptionType::Anything => Value RawExpr);
+
+impl<T: NixType> NixType for crate::Path<T> {
+    fn option_type() -> OptionType {
+        OptionType::Path
+    }
+}
+
+impl<T> NixType for IndentedString<T> {
+    fn option_type() -> OptionType {
+        OptionType::Str
+    }
+}
+
+impl<T: ?Sized + NixType> NixType for &T {
+    fn option_type() -> OptionType {
+        T::option_type()
+    }
+}
+
+impl<T: ?Sized + NixType> NixType for Box<T> {
+    fn option_type() -> OptionType {
+        T::option_type()
+    }
+}
+
+impl<T: NixType> NixType for OmitNull<T> {
+    fn option_type() -> OptionType {
+        T::option_type()
+    }
+}
+
+impl<T: NixType> NixType for Commented<T> {
+    fn option_type() -> OptionType {
+        T::option_type()
+    }
+}
+
+impl<T: NixType> NixType for Option<T> {
+    fn option_type() -> OptionType {
+        OptionType::NullOr(Box::new(T::option_type()))
+    }
+}
+
+macro_rules! list_type {
+    ($($t:ident)*) => {
+        $(
+            impl<T: NixType> NixType for $t<T> {
+                fn option_type() -> OptionType {
+                    OptionType::ListOf(Box::new(T::option_type()))
+                }
+            }
+        )*
+    };
+}
+
+list_type!(Vec HashSet BTreeSet);
+
+impl<T: NixType> NixType for [T] {
+    fn option_type() -> OptionType {
+        OptionType::ListOf(Box::new(T::option_type()))
+    }
+}
+
+macro_rules! attrs_type {
+    ($($t:ident)*) => {
+        $(
+            impl<K, V: NixType> NixType for $t<K, V> {
+                fn option_type() -> OptionType {
+                    OptionType::AttrsOf(Box::new(V::option_type()))
+                }
+            }
+        )*
+    };
+}
+
+attrs_type!(HashMap BTreeMap IndexMap);
+
+// How the derive takes defaults only from types that have them. Method resolution on a
+// `&DefaultsOf<T>` finds `WithDefault` first if `T` implements `Default`, and otherwise goes on
+// to `WithoutDefault` on a reference to it.
+#[doc(hidden)]
+pub struct DefaultsOf<T>(pub PhantomData<T>);
+
+#[doc(hidden)]
+pub trait WithDefault {
+    fn defaults(&self) -> Option<Map>;
+}
+
+impl<T: Default + Serialize> WithDefault for DefaultsOf<T> {
+    // Written the way serde writes the whole type, so that its field attributes apply, and left
+    // out if it can't be written at all.
+    fn defaults(&self) -> Option<Map> {
+        match crate::to_value(&T::default()) {
+            Ok(Value::Attrs(attrs)) => Some(attrs),
+            _ => None,
+        }
+    }
+}
+
+#[doc(hidden)]
+pub trait WithoutDefault {
+    fn defaults(&self) -> Option<Map>;
+}
+
+impl<T> WithoutDefault for &DefaultsOf<T> {
+    fn defaults(&self) -> Option<Map> {
+        None
+    }
+}
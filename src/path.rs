@@ -0,0 +1,95 @@
+//! Writing Rust paths as nix paths.
+//!
+//! [`std::path::Path`] and `PathBuf` serialize as strings, which is what most formats want, but
+//! in nix a path is its own type: `./hardware-configuration.nix` gets copied into the store and
+//! resolved relative to the file it appears in, a string doesn't. Wrap a value in [`Path`], or
+//! put `#[serde(with = "serde_nix::path")]` on a field, to write it as a path literal instead:
+//!
+//! ```
+//! use std::path::PathBuf;
+//!
+//! use serde::Serialize;
+//!
+//! #[derive(Serialize)]
+//! struct Config {
+//!     #[serde(with = "serde_nix::path")]
+//!     imports: PathBuf,
+//!     secrets: serde_nix::Path<&'static str>,
+//!     weird: serde_nix::Path<&'static str>,
+//! }
+//!
+//! let config = Config {
+//!     imports: PathBuf::from("./hardware-configuration.nix"),
+//!     secrets: serde_nix::Path("/etc/nixos/secrets"),
+//!     weird: serde_nix::Path("/mnt/My Files"),
+//! };
+//! assert_eq!(
+//!     serde_nix::to_string(&config).unwrap(),
+//!     r#"{ imports = ./hardware-configuration.nix; secrets = /etc/nixos/secrets; weird = /. + "/mnt/My Files"; }"#,
+//! );
+//! ```
+//!
+//! Relative paths stay relative, `foo` is written as `./foo`. Paths with characters a path
+//! literal can't hold are appended as a string to `/.` or `./.`, which nix evaluates to the same
+//! path. Home directory paths like `~/.config` can only be written as literals, so they fail
+//! with [`Error::InvalidPath`](crate::Error::InvalidPath) if they need quoting.
+//!
+//! Other serializers see a string. Deserializing reads the path like any string.
+
+use serde::{de, ser};
+
+use crate::ser::PATH_TOKEN;
+
+/// A value written as a nix path rather than a string. See the [module docs](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Path<T>(pub T);
+
+impl<T> ser::Serialize for Path<T>
+where
+    T: AsRef<std::path::Path>,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        serialize(&self.0, serializer)
+    }
+}
+
+impl<'de, T> de::Deserialize<'de> for Path<T>
+where
+    T: de::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(Path)
+    }
+}
+
+/// Serializes `path` as a nix path, for use with `#[serde(with = "serde_nix::path")]`.
+pub fn serialize<P, S>(path: &P, serializer: S) -> Result<S::Ok, S::Error>
+where
+    P: ?Sized + AsRef<std::path::Path>,
+    S: ser::Serializer,
+{
+    let path = path.as_ref();
+    match path.to_str() {
+        Some(s) => serializer.serialize_newtype_struct(PATH_TOKEN, s),
+        None => Err(ser::Error::custom(format!(
+            "{:?} is not valid UTF-8, which nix paths must be",
+            path
+        ))),
+    }
+}
+
+/// Deserializes a path written by [`serialize`], for use with
+/// `#[serde(with = "serde_nix::path")]`.
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: de::Deserialize<'de>,
+    D: de::Deserializer<'de>,
+{
+    T::deserialize(deserializer)
+}
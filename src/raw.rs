@@ -0,0 +1,107 @@
+//! Embedding nix expressions that aren't plain data.
+
+use serde::ser::{self, SerializeStruct};
+
+use crate::error::Error;
+use crate::syntax::{self, ExprKind};
+
+// Struct name and field marking a raw expression, the same trick serde_json uses for its
+// `RawValue`. Other serializers see a struct with a single string field.
+pub(crate) const RAW_EXPR_TOKEN: &str = "$serde_nix::private::RawExpr";
+
+/// A nix expression written out verbatim, for things plain data can't express: references like
+/// `pkgs.hello`, function calls like `lib.mkForce true` or `import ./foo.nix`.
+///
+/// ```
+/// use serde::Serialize;
+/// use serde_nix::RawExpr;
+///
+/// #[derive(Serialize)]
+/// struct Config {
+///     packages: Vec<RawExpr>,
+/// }
+///
+/// let config = Config {
+///     packages: vec![RawExpr::new("pkgs.hello"), RawExpr::new("pkgs.callPackage ./foo.nix { }")],
+/// };
+/// assert_eq!(
+///     serde_nix::to_string(&config).unwrap(),
+///     "{ packages = [ pkgs.hello (pkgs.callPackage ./foo.nix { }) ]; }",
+/// );
+/// ```
+///
+/// The text is inserted into the output as is, wrapped in parentheses where the surrounding
+/// syntax needs it. [`RawExpr::new`] trusts it to be a single expression; text that isn't, say
+/// `1; b = 2`, produces output that means something else or doesn't parse at all. Use
+/// [`RawExpr::validated`] for text that doesn't come from the program itself.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RawExpr {
+    text: String,
+}
+
+impl RawExpr {
+    /// Wraps `text` without checking it.
+    pub fn new(text: impl Into<String>) -> Self {
+        RawExpr { text: text.into() }
+    }
+
+    /// Wraps `text` after checking that it parses as a single nix expression.
+    ///
+    /// Fails with [`Error::Syntax`] if it doesn't, or if it ends in a comment, which would
+    /// swallow whatever the serializer writes after it.
+    pub fn validated(text: impl Into<String>) -> Result<Self, Error> {
+        let text = text.into();
+        let expr = syntax::parse(&text)?;
+        if !text[expr.span.end..].trim().is_empty() {
+            let (line, column) = syntax::line_column(&text, expr.span.end);
+            return Err(Error::Syntax {
+                msg: "comment after the expression".to_string(),
+                line,
+                column,
+            });
+        }
+        Ok(RawExpr { text })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn into_string(self) -> String {
+        self.text
+    }
+}
+
+impl ser::Serialize for RawExpr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let mut s = serializer.serialize_struct(RAW_EXPR_TOKEN, 1)?;
+        s.serialize_field(RAW_EXPR_TOKEN, &self.text)?;
+        s.end()
+    }
+}
+
+// Whether `text` can stand where nix only admits a select expression, like a list element,
+// without parentheses. Text that doesn't parse is parenthesized to be safe.
+pub(crate) fn is_select_expr(text: &str) -> bool {
+    match syntax::parse(text) {
+        Ok(expr) => matches!(
+            expr.kind,
+            ExprKind::Int(_)
+                | ExprKind::Float(_)
+                | ExprKind::Str(_)
+                | ExprKind::IndStr(_)
+                | ExprKind::Path(_)
+                | ExprKind::SearchPath(_)
+                | ExprKind::Uri(_)
+                | ExprKind::Ident(_)
+                | ExprKind::List(_)
+                | ExprKind::Attrs { .. }
+                | ExprKind::Select { .. }
+                | ExprKind::Paren(_)
+        ),
+        Err(_) => false,
+    }
+}
@@ -1,149 +1,1595 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::fmt;
 use std::io;
 use std::string::String;
 
 use serde::ser::{self, Impossible, Serialize};
-use thiserror::Error;
+
+use crate::bytes;
+use crate::content::{self, Content};
+use crate::dedup;
+pub use crate::error::Error;
+use crate::mk::APPLY_TOKEN;
+use crate::raw::{self, RAW_EXPR_TOKEN};
+use crate::syntax::{self, is_path_char};
+use crate::value::{self, Value};
 
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
-pub struct Serializer<W> {
-    writer: W,
+// Newtype struct name marking a string that should be written as a nix path literal rather than a
+// string. Serializers other than ours just see the string.
+pub(crate) const PATH_TOKEN: &str = "$serde_nix::private::Path";
+
+// Newtype struct name marking a string that should be written as an indented string.
+pub(crate) const INDENTED_STRING_TOKEN: &str = "$serde_nix::private::IndentedString";
+
+// Newtype struct name marking a value whose attribute is left out if it's null.
+pub(crate) const OMIT_NULL_TOKEN: &str = "$serde_nix::private::OmitNull";
+
+// Struct name marking a value with a comment. The comment is in a field of the same name, followed
+// by the value in a field called `value`.
+pub(crate) const COMMENTED_TOKEN: &str = "$serde_nix::private::Commented";
+
+#[derive(Debug)]
+pub struct Serializer<W, F = CompactFormatter> {
+    writer: Output<W>,
+    formatter: F,
+    // Set while the next value is being written somewhere the nix grammar only admits a "select
+    // expression", such as a list element. Anything looser, like `-1`, must be parenthesized
+    // there or it would be parsed as part of a larger expression.
+    select_position: bool,
+    // Set while an attrset is being written as dotted paths on the attributes of its parent,
+    // rather than in braces of its own: the path to it, and how its first attribute starts.
+    dotted: Option<(Vec<String>, NextAttr)>,
+    // Set when the comment of the `Commented` value about to be written has already been written
+    // before its attribute name.
+    comment_written: bool,
+    options: Options,
+}
+
+// How the next attribute of an attrset starts. The attributes of a sorted attrset are buffered
+// from the attribute name on, and get their `begin_attr_key` once they're copied out and it's
+// known which one comes first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NextAttr {
+    First,
+    Rest,
+    Begun,
+}
+
+// The writer, unless something is being written into a buffer to be moved into place later, like
+// the attributes of an attrset whose order isn't known until all of them have been seen. The file
+// header goes to the writer ahead of whatever is written first.
+#[derive(Debug)]
+struct Output<W> {
+    writer: W,
+    buffers: Vec<Vec<u8>>,
+    header: Vec<u8>,
+}
+
+impl<W> io::Write for Output<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.header.is_empty() {
+            let header = std::mem::take(&mut self.header);
+            self.writer.write_all(&header)?;
+        }
+        match self.buffers.last_mut() {
+            Some(buffer) => buffer.write(buf),
+            None => self.writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.buffers.last_mut() {
+            Some(_) => Ok(()),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+// Settings that change what gets written, as opposed to the layout the formatter controls.
+#[derive(Clone, Debug, Default)]
+struct Options {
+    non_finite_floats: NonFiniteFloats,
+    integer_overflow: IntegerOverflow,
+    multiline_strings: MultilineStrings,
+    attr_order: AttrOrder,
+    attr_paths: AttrPaths,
+    null_attrs: NullAttrs,
+    bytes: Bytes,
+    unrepresentable_chars: UnrepresentableChars,
+    map_keys: MapKeys,
+}
+
+/// What to do with floats that nix has no literal for: NaN and the infinities.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFiniteFloats {
+    /// Fail with [`Error::NonFiniteFloat`].
+    #[default]
+    Error,
+    /// Write `null` in their place, which is what `builtins.toJSON` consumers typically expect.
+    Null,
+}
+
+/// What to do with integers outside the signed 64-bit range of nix integers, which can come from
+/// `u64`, `i128` and `u128` values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegerOverflow {
+    /// Fail with [`Error::IntegerOutOfRange`].
+    #[default]
+    Error,
+    /// Write the decimal digits as a nix string, e.g. `"18446744073709551615"`.
+    String,
+    /// Write the nearest nix float. This loses precision.
+    Float,
+}
+
+/// How strings containing newlines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultilineStrings {
+    /// As a double quoted string with `\n` escapes, e.g. `"set -e\necho hi\n"`.
+    #[default]
+    Quoted,
+    /// As an indented `''` string with one line of source per line of text, which is much
+    /// easier to read for embedded scripts and config files. See [`IndentedString`] to choose
+    /// this for individual values instead.
+    Indented,
+}
+
+/// The order attributes are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AttrOrder {
+    /// The order they are serialized in: field order for structs, iteration order for maps.
+    /// `HashMap`s iterate in a different order every run.
+    #[default]
+    Serialized,
+    /// Sorted by the bytes of their names, the order nix itself uses when printing an attrset,
+    /// e.g. with `nix eval`. Gives the same output for the same attributes however they were
+    /// ordered, at the cost of buffering each attrset in memory until it's complete.
+    Sorted,
+}
+
+/// How attrsets nested in other attrsets are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AttrPaths {
+    /// In braces of their own, e.g. `services = { nginx = { enable = true; }; };`.
+    #[default]
+    Nested,
+    /// As dotted attribute paths on their parent if they have between one and `max_attrs`
+    /// attributes, the way NixOS configurations are usually written. With a `max_attrs` of 1,
+    /// the example above becomes `services.nginx.enable = true;`, with 2, `{ a = { b = 1; c = 2;
+    /// }; }` becomes `{ a.b = 1; a.c = 2; }`. Nix merges the paths back into the same attrsets,
+    /// so this doesn't change the value. Empty attrsets are always written as `{ }`.
+    Dotted { max_attrs: usize },
+}
+
+/// What to do with attributes whose value is `null`, like `None` fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NullAttrs {
+    /// Write them as `name = null;`.
+    #[default]
+    Write,
+    /// Leave them out, as if they had `#[serde(skip_serializing_if = "Option::is_none")]`. In a
+    /// NixOS module, `null` overrides the option's default, which is rarely what a `None` means.
+    /// See [`OmitNull`] to leave out individual attributes instead.
+    Omit,
+}
+
+/// How byte buffers are written, like those of `serde_bytes` types. Serde only writes a `Vec<u8>`
+/// or `&[u8]` as bytes when told to, with `serde_bytes` or `serialize_with`, and as a list of
+/// integers otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Bytes {
+    /// As a list of integers, e.g. `[ 104 105 ]`.
+    #[default]
+    List,
+    /// As a string, e.g. `"hi"`. Fails with [`Error::InvalidUtf8`] if they aren't UTF-8.
+    Utf8,
+    /// As a base64 string with padding, e.g. `"aGk="`.
+    Base64,
+    /// As a lowercase hex string, e.g. `"6869"`.
+    Hex,
+    /// As an SRI hash, the form of the `hash` attributes of fetchers, e.g. `"sha256-..."`. The
+    /// algorithm goes by the length: 16 bytes for md5, 20 for sha1, 32 for sha256 and 64 for
+    /// sha512. Fails with [`Error::NotAHash`] for any other length.
+    Sri,
+}
+
+/// What to do with characters that nix strings can't hold. Nix keeps strings as C strings, so
+/// that's only the null character; every other character is written so that nix reads it back
+/// unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnrepresentableChars {
+    /// Fail with [`Error::UnencodableNullString`], which has the attribute path to the string.
+    #[default]
+    Error,
+    /// Leave them out.
+    Drop,
+    /// Write U+FFFD REPLACEMENT CHARACTER in their place.
+    Replace,
+}
+
+/// How maps with keys other than strings are written, since only strings can name attributes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MapKeys {
+    /// Fail with [`Error::MapKeyMustBeAString`].
+    #[default]
+    Error,
+    /// Write bool and number keys as strings, like serde_json does, e.g. `{ "8080" = ...; }`.
+    /// Other keys still fail.
+    Stringify,
+    /// Write maps with any key that isn't a string as a list of attrsets with the key in `name`
+    /// and the value in `value`, e.g. `[ { name = [ "a" 1 ]; value = ...; } ]`, in the order the
+    /// map gives them. Any value can be a key this way. Maps with only string keys are still
+    /// attrsets.
+    Pairs,
+}
+
+/// A value whose attribute is left out if it's `null`, regardless of [`Serializer::null_attrs`].
+///
+/// ```
+/// use serde::Serialize;
+/// use serde_nix::OmitNull;
+///
+/// #[derive(Serialize)]
+/// struct Service {
+///     enable: bool,
+///     port: OmitNull<Option<u16>>,
+///     user: Option<String>,
+/// }
+///
+/// let service = Service { enable: true, port: OmitNull(None), user: None };
+/// assert_eq!(serde_nix::to_string(&service).unwrap(), "{ enable = true; user = null; }");
+/// ```
+///
+/// Other serializers see the value itself. A missing attribute deserializes as `None` like it
+/// would for a plain `Option`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OmitNull<T>(pub T);
+
+impl<T> Serialize for OmitNull<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        serializer.serialize_newtype_struct(OMIT_NULL_TOKEN, &self.0)
+    }
+}
+
+impl<'de, T> serde::Deserialize<'de> for OmitNull<T>
+where
+    T: serde::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(OmitNull)
+    }
+}
+
+/// A string that is always written as an indented `''` string, regardless of
+/// [`Serializer::multiline_strings`].
+///
+/// Other serializers see a plain string.
+///
+/// ```
+/// use serde_nix::IndentedString;
+///
+/// let script = IndentedString("set -e\necho hi\n");
+/// assert_eq!(serde_nix::to_string(&script).unwrap(), "''\nset -e\necho hi\n''");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IndentedString<T>(pub T);
+
+impl<T> Serialize for IndentedString<T>
+where
+    T: AsRef<str>,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        serializer.serialize_newtype_struct(INDENTED_STRING_TOKEN, self.0.as_ref())
+    }
+}
+
+impl<'de, T> serde::Deserialize<'de> for IndentedString<T>
+where
+    T: serde::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(IndentedString)
+    }
+}
+
+/// A value with a comment written before it, for provenance, "do not edit" markers or option
+/// descriptions.
+///
+/// ```
+/// use serde::Serialize;
+/// use serde_nix::{ser::Serializer, Commented};
+///
+/// #[derive(Serialize)]
+/// struct Config {
+///     port: Commented<u16>,
+/// }
+///
+/// let config = Config { port: Commented::new("from ports.toml", 8080) };
+/// assert_eq!(
+///     serde_nix::to_string(&config).unwrap(),
+///     "{ /* from ports.toml */ port = 8080; }",
+/// );
+///
+/// let mut ser = Serializer::pretty(Vec::new());
+/// config.serialize(&mut ser).unwrap();
+/// assert_eq!(
+///     String::from_utf8(ser.into_inner()).unwrap(),
+///     "{\n  # from ports.toml\n  port = 8080;\n}",
+/// );
+/// ```
+///
+/// The comment of an attribute goes before its name, that of a list element or the whole value
+/// right before the value. The pretty formatter writes each line as a `#` comment, the compact
+/// one puts everything in a single `/* */` comment, with line breaks turned into spaces and `*/`
+/// into `* /`. An empty comment isn't written at all, nor is the comment of an attribute that
+/// is left out for being null.
+///
+/// Other serializers see a struct with the comment and the value, and
+/// [`to_value`](crate::to_value) drops the comment.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Commented<T> {
+    pub comment: String,
+    pub value: T,
+}
+
+impl<T> Commented<T> {
+    pub fn new(comment: impl Into<String>, value: T) -> Self {
+        Commented {
+            comment: comment.into(),
+            value,
+        }
+    }
+}
+
+impl<T> Serialize for Commented<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct(COMMENTED_TOKEN, 2)?;
+        s.serialize_field(COMMENTED_TOKEN, &self.comment)?;
+        s.serialize_field("value", &self.value)?;
+        s.end()
+    }
+}
+
+/// How [`Serializer::serialize_module`] wraps a value into a NixOS or home-manager module.
+///
+/// ```
+/// use serde_nix::{nix, Module, RawExpr, Value};
+///
+/// let module = Module::new()
+///     .args(["lib", "pkgs"])
+///     .import(Value::Path("./hardware-configuration.nix".to_string()));
+/// assert_eq!(
+///     serde_nix::to_module_string(&nix!({ services.nginx.enable = true; }), &module).unwrap(),
+///     "{ lib, pkgs, ... }: { imports = [ ./hardware-configuration.nix ]; services = { nginx = { enable = true; }; }; }",
+/// );
+///
+/// let module = Module::new().options(nix!({
+///     foo.enable = (RawExpr::new("lib.mkEnableOption \"foo\""));
+/// }));
+/// assert_eq!(
+///     serde_nix::to_module_string(&nix!({ environment.etc.foo.text = "bar"; }), &module).unwrap(),
+///     r#"{ config, lib, pkgs, ... }: { options = { foo = { enable = lib.mkEnableOption "foo"; }; }; config = { environment = { etc = { foo = { text = "bar"; }; }; }; }; }"#,
+/// );
+/// ```
+///
+/// The option declarations can be anything serializable, such as the
+/// [`Declarations`](crate::options::Declarations) of a [`NixOptions`](crate::options::NixOptions)
+/// type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module<O = Value> {
+    args: Vec<String>,
+    imports: Vec<Value>,
+    options: Option<O>,
+}
+
+impl Module {
+    /// A module taking `{ config, lib, pkgs, ... }`, without imports or options.
+    pub fn new() -> Self {
+        Module {
+            args: vec!["config".to_string(), "lib".to_string(), "pkgs".to_string()],
+            imports: Vec::new(),
+            options: None,
+        }
+    }
+}
+
+impl<O> Module<O> {
+    /// Sets the arguments the module function names, before the `...` every module takes.
+    /// Serializing fails with [`Error::InvalidModuleArg`] if one isn't an identifier.
+    pub fn args<I>(mut self, args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Adds a module to the `imports` list, usually a [`Value::Path`] or a
+    /// [`RawExpr`](crate::RawExpr) like `inputs.foo.nixosModules.default`.
+    pub fn import(mut self, module: impl Into<Value>) -> Self {
+        self.imports.push(module.into());
+        self
+    }
+
+    /// Sets the option declarations of the module. The serialized value then goes in its
+    /// `config` attribute, as nix requires of modules that declare options.
+    pub fn options<P>(self, options: P) -> Module<P> {
+        Module {
+            args: self.args,
+            imports: self.imports,
+            options: Some(options),
+        }
+    }
+}
+
+impl Default for Module {
+    fn default() -> Self {
+        Module::new()
+    }
+}
+
+impl<W> Serializer<W>
+where
+    W: io::Write,
+{
+    /// Creates a serializer that writes everything on a single line.
+    pub fn new(writer: W) -> Self {
+        Serializer::with_formatter(writer, CompactFormatter)
+    }
+}
+
+impl<W> Serializer<W, PrettyFormatter>
+where
+    W: io::Write,
+{
+    /// Creates a serializer that writes one attribute or list element per line, indented by two
+    /// spaces per level.
+    pub fn pretty(writer: W) -> Self {
+        Serializer::with_formatter(writer, PrettyFormatter::new())
+    }
+
+    /// Creates a pretty serializer using the given number of spaces per level of indentation.
+    pub fn with_indent(writer: W, width: usize) -> Self {
+        Serializer::with_formatter(writer, PrettyFormatter::with_indent(width))
+    }
+}
+
+impl<W, F> Serializer<W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    /// Creates a serializer that writes its output through the given formatter.
+    pub fn with_formatter(writer: W, formatter: F) -> Self {
+        Serializer {
+            writer: Output {
+                writer,
+                buffers: Vec::new(),
+                header: Vec::new(),
+            },
+            formatter,
+            select_position: false,
+            dotted: None,
+            comment_written: false,
+            options: Options::default(),
+        }
+    }
+
+    /// Sets how NaN and infinite floats are handled. Defaults to [`NonFiniteFloats::Error`].
+    pub fn non_finite_floats(mut self, policy: NonFiniteFloats) -> Self {
+        self.options.non_finite_floats = policy;
+        self
+    }
+
+    /// Sets how integers that don't fit in a nix integer are handled. Defaults to
+    /// [`IntegerOverflow::Error`].
+    pub fn integer_overflow(mut self, policy: IntegerOverflow) -> Self {
+        self.options.integer_overflow = policy;
+        self
+    }
+
+    /// Sets how strings containing newlines are written. Defaults to
+    /// [`MultilineStrings::Quoted`].
+    pub fn multiline_strings(mut self, style: MultilineStrings) -> Self {
+        self.options.multiline_strings = style;
+        self
+    }
+
+    /// Sets the order attributes are written in. Defaults to [`AttrOrder::Serialized`].
+    pub fn attr_order(mut self, order: AttrOrder) -> Self {
+        self.options.attr_order = order;
+        self
+    }
+
+    /// Sets how attrsets nested in other attrsets are written. Defaults to
+    /// [`AttrPaths::Nested`].
+    pub fn attr_paths(mut self, paths: AttrPaths) -> Self {
+        self.options.attr_paths = paths;
+        self
+    }
+
+    /// Sets what happens to attributes whose value is `null`. Defaults to [`NullAttrs::Write`].
+    pub fn null_attrs(mut self, policy: NullAttrs) -> Self {
+        self.options.null_attrs = policy;
+        self
+    }
+
+    /// Sets how byte buffers are written. Defaults to [`Bytes::List`]. Read them back with the same
+    /// setting of [`de::Options::bytes`](crate::de::Options::bytes).
+    pub fn bytes(mut self, encoding: Bytes) -> Self {
+        self.options.bytes = encoding;
+        self
+    }
+
+    /// Sets what happens to characters that nix strings can't hold, wherever they are: in
+    /// strings, attribute names, paths or comments. Defaults to [`UnrepresentableChars::Error`].
+    /// [`to_value`](crate::to_value) and [`nix!`](crate::nix) have no settings and always fail on
+    /// them, as with the default.
+    pub fn unrepresentable_chars(mut self, policy: UnrepresentableChars) -> Self {
+        self.options.unrepresentable_chars = policy;
+        self
+    }
+
+    /// Sets how maps with keys other than strings are written. Defaults to [`MapKeys::Error`].
+    pub fn map_keys(mut self, policy: MapKeys) -> Self {
+        self.options.map_keys = policy;
+        self
+    }
+
+    /// Serializes `value` with every attrset and list of at least `min_size` values that occurs
+    /// more than once written just once, bound to a name in a `let` around the whole expression:
+    ///
+    /// ```
+    /// use serde_nix::{nix, ser::Serializer};
+    ///
+    /// let meta = nix!({ license = "mit"; platforms = ["x86_64-linux"]; });
+    /// let packages = nix!({
+    ///     foo = { version = 1; meta = meta.clone(); };
+    ///     bar = { version = 2; meta = meta; };
+    /// });
+    ///
+    /// let mut out = Vec::new();
+    /// Serializer::new(&mut out).serialize_deduplicated(&packages, 3).unwrap();
+    /// assert_eq!(
+    ///     String::from_utf8(out).unwrap(),
+    ///     r#"let v0 = { license = "mit"; platforms = [ "x86_64-linux" ]; }; in { foo = { version = 1; meta = v0; }; bar = { version = 2; meta = v0; }; }"#,
+    /// );
+    /// ```
+    ///
+    /// The size of a value counts the value itself and everything in it, so `min_size` trades
+    /// output size against readability. Nix shares a bound value rather than copying it, so the
+    /// result also takes less memory to evaluate. Repeats found inside a bound value are bound in
+    /// turn, and names used by a [`RawExpr`](crate::RawExpr) in the value are skipped.
+    ///
+    /// The value is first converted the way [`to_value`](crate::to_value) does it, but with this
+    /// serializer's settings for what values become, like [`bytes`](Self::bytes) and
+    /// [`integer_overflow`](Self::integer_overflow), so it has to be representable as a
+    /// [`Value`](crate::Value). Structurally identical means the same attributes in the same
+    /// order.
+    pub fn serialize_deduplicated<T>(&mut self, value: &T, min_size: usize) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let value = value.serialize(self.value_serializer())?;
+        let (bindings, body) = dedup::deduplicate(value, min_size);
+        if !bindings.is_empty() {
+            self.formatter.begin_let(&mut self.writer)?;
+            let mut next = NextAttr::First;
+            for (name, value) in bindings {
+                self.serialize_attr(vec![name], &mut next, &value)?;
+            }
+            self.formatter.begin_let_body(&mut self.writer)?;
+        }
+        body.serialize(self)
+    }
+
+    /// Sets a comment to write at the top of the output, like `Generated by foo, do not edit.`
+    /// It's rendered the same way as a [`Commented`] value.
+    pub fn header(mut self, text: &str) -> Self {
+        let mut header = Vec::new();
+        if !text.is_empty() {
+            self.formatter
+                .write_comment(&mut header, text)
+                .expect("writing to a Vec can't fail");
+        }
+        self.writer.header = header;
+        self
+    }
+
+    /// Serializes `value` as the body of a module function, with the imports and option
+    /// declarations from `module`. See [`Module`].
+    ///
+    /// Without options, the imports go first in the attrset `value` serializes to, so it must
+    /// not have an `imports` attribute of its own. It fails with [`Error::ModuleMustBeAnAttrset`]
+    /// if it doesn't serialize to an attrset at all.
+    pub fn serialize_module<T, O>(&mut self, value: &T, module: &Module<O>) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+        O: Serialize,
+    {
+        if let Some(arg) = module.args.iter().find(|arg| !syntax::is_ident(arg)) {
+            return Err(Error::InvalidModuleArg(arg.clone()));
+        }
+        if module.options.is_none() {
+            let shape = value
+                .serialize(self.shape_probe(true))
+                .unwrap_or(Shape::Other);
+            let shape = match shape {
+                Shape::Commented(_, shape) => *shape,
+                shape => shape,
+            };
+            if !matches!(shape, Shape::Attrs(_)) {
+                return Err(Error::ModuleMustBeAnAttrset);
+            }
+        }
+        self.formatter
+            .write_module_args(&mut self.writer, &module.args)?;
+        let options = match &module.options {
+            Some(options) => options,
+            None if module.imports.is_empty() => return value.serialize(self),
+            None => {
+                // The attributes of the value go in the braces opened for the imports, like
+                // those of an attrset written as dotted paths.
+                self.formatter.begin_attrs(&mut self.writer)?;
+                let mut next = NextAttr::First;
+                self.serialize_attr(vec!["imports".to_string()], &mut next, &module.imports)?;
+                self.dotted = Some((Vec::new(), next));
+                return value.serialize(self);
+            }
+        };
+        self.formatter.begin_attrs(&mut self.writer)?;
+        let mut next = NextAttr::First;
+        if !module.imports.is_empty() {
+            self.serialize_attr(vec!["imports".to_string()], &mut next, &module.imports)?;
+        }
+        self.serialize_attr(vec!["options".to_string()], &mut next, options)?;
+        self.serialize_attr(vec!["config".to_string()], &mut next, value)?;
+        self.formatter.end_attrs(&mut self.writer)?;
+        Ok(())
+    }
+
+    /// Unwraps the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer.writer
+    }
+
+    // Clears the select position flag, returning whether the current value is in one.
+    fn take_select_position(&mut self) -> bool {
+        std::mem::replace(&mut self.select_position, false)
+    }
+
+    fn parenthesize_if<G>(&mut self, parens: bool, write: G) -> Result<()>
+    where
+        G: FnOnce(&mut Self) -> Result<()>,
+    {
+        if parens {
+            self.formatter.begin_parens(&mut self.writer)?;
+        }
+        write(self)?;
+        if parens {
+            self.formatter.end_parens(&mut self.writer)?;
+        }
+        Ok(())
+    }
+
+    fn serialize_non_finite_float(&mut self, value: f64) -> Result<()> {
+        match self.options.non_finite_floats {
+            NonFiniteFloats::Error => Err(Error::NonFiniteFloat(value)),
+            NonFiniteFloats::Null => ser::Serializer::serialize_unit(self),
+        }
+    }
+
+    fn serialize_out_of_range<T>(&mut self, value: T, as_float: f64) -> Result<()>
+    where
+        T: fmt::Display,
+    {
+        match self.options.integer_overflow {
+            IntegerOverflow::Error => Err(Error::IntegerOutOfRange(value.to_string())),
+            IntegerOverflow::String => ser::Serializer::serialize_str(self, &value.to_string()),
+            IntegerOverflow::Float => ser::Serializer::serialize_f64(self, as_float),
+        }
+    }
+
+    fn representable<'s>(&self, s: &'s str) -> Result<Cow<'s, str>> {
+        representable(s, self.options.unrepresentable_chars)
+    }
+
+    // The settings that decide what a value is, for converting it into a `Value` first.
+    fn value_serializer(&self) -> value::Serializer {
+        value::Serializer {
+            non_finite_floats: self.options.non_finite_floats,
+            integer_overflow: self.options.integer_overflow,
+            bytes: self.options.bytes,
+            unrepresentable_chars: self.options.unrepresentable_chars,
+            map_keys: self.options.map_keys,
+        }
+    }
+
+    fn serialize_indented_string(&mut self, value: &str) -> Result<()> {
+        self.take_select_position();
+        let lines = indented_string_lines(&self.representable(value)?);
+        self.formatter
+            .write_indented_string(&mut self.writer, &lines)?;
+        Ok(())
+    }
+
+    fn begin_attr(&mut self, next: &mut NextAttr) -> Result<()> {
+        match *next {
+            NextAttr::First => self.formatter.begin_attr_key(&mut self.writer, true)?,
+            NextAttr::Rest => self.formatter.begin_attr_key(&mut self.writer, false)?,
+            NextAttr::Begun => {}
+        }
+        *next = NextAttr::Rest;
+        Ok(())
+    }
+
+    // Writes `path = value;`, or if the value is an attrset small enough to be written as dotted
+    // paths, `path.name = ...;` for each of its attributes. Returns false if the attribute was
+    // left out because it's null.
+    fn serialize_attr<T>(
+        &mut self,
+        path: Vec<String>,
+        next: &mut NextAttr,
+        value: &T,
+    ) -> Result<bool>
+    where
+        T: ?Sized + Serialize,
+    {
+        let omit_nulls = self.options.null_attrs == NullAttrs::Omit;
+        let count_attrs = matches!(self.options.attr_paths, AttrPaths::Dotted { .. });
+        let shape = value
+            .serialize(self.shape_probe(count_attrs))
+            .unwrap_or(Shape::Other);
+        if shape.is_omitted(omit_nulls) {
+            return Ok(false);
+        }
+        if let AttrPaths::Dotted { max_attrs } = self.options.attr_paths {
+            if matches!(shape, Shape::Attrs(n) if n > 0 && n <= max_attrs) {
+                self.dotted = Some((path, *next));
+                let written = value.serialize(&mut *self);
+                self.dotted = None;
+                *next = NextAttr::Rest;
+                return written.map(|()| true);
+            }
+        }
+        // Attributes written as dotted paths add the whole path to errors themselves.
+        self.write_attr(&path, next, &shape, value)
+            .map_err(|err| path.iter().rev().fold(err, |err, name| err.in_attr(name)))?;
+        Ok(true)
+    }
+
+    fn shape_probe(&self, count_attrs: bool) -> ShapeProbe {
+        ShapeProbe {
+            omit_nulls: self.options.null_attrs == NullAttrs::Omit,
+            stringify_keys: self.options.map_keys == MapKeys::Stringify,
+            count_attrs,
+        }
+    }
+
+    fn write_attr<T>(
+        &mut self,
+        path: &[String],
+        next: &mut NextAttr,
+        shape: &Shape,
+        value: &T,
+    ) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.begin_attr(next)?;
+        if let Shape::Commented(comment, _) = shape {
+            self.write_comment(comment)?;
+            self.comment_written = true;
+        }
+        let key = path
+            .iter()
+            .map(|name| Ok(escape_map_key(&self.representable(name)?)))
+            .collect::<Result<Vec<_>>>()?
+            .join(".");
+        self.formatter.write_attr_key(&mut self.writer, &key)?;
+        self.formatter.end_attr_key(&mut self.writer)?;
+        self.formatter.begin_attr_value(&mut self.writer)?;
+        value.serialize(&mut *self)?;
+        self.formatter.end_attr_value(&mut self.writer)?;
+        Ok(())
+    }
+
+    fn write_comment(&mut self, text: &str) -> Result<()> {
+        if !text.is_empty() {
+            let text = self.representable(text)?;
+            self.formatter.write_comment(&mut self.writer, &text)?;
+        }
+        Ok(())
+    }
+
+    fn write_variant_key(&mut self, variant: &'static str) -> Result<()> {
+        self.take_select_position();
+        self.formatter.begin_attrs(&mut self.writer)?;
+        self.formatter.begin_attr_key(&mut self.writer, true)?;
+        let key = escape_map_key(&self.representable(variant)?);
+        self.formatter.write_attr_key(&mut self.writer, &key)?;
+        self.formatter.end_attr_key(&mut self.writer)?;
+        self.formatter.begin_attr_value(&mut self.writer)?;
+        Ok(())
+    }
+
+    fn end_variant(&mut self) -> Result<()> {
+        self.formatter.end_attr_value(&mut self.writer)?;
+        self.formatter.end_attrs(&mut self.writer)?;
+        Ok(())
+    }
+}
+
+/// Controls the layout of the nix expression written by a [`Serializer`].
+///
+/// Every token the serializer writes goes through one of these hooks, so a formatter can change
+/// whitespace and line breaks without affecting what the expression evaluates to. The default
+/// implementations produce the compact single-line style of [`CompactFormatter`].
+pub trait Formatter {
+    /// Writes `null`.
+    fn write_null<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"null")
+    }
+
+    /// Writes `true` or `false`.
+    fn write_bool<W>(&mut self, writer: &mut W, value: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        write!(writer, "{}", value)
+    }
+
+    /// Writes a signed integer literal.
+    fn write_i64<W>(&mut self, writer: &mut W, value: i64) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        if value == i64::MIN {
+            // `-` is an operator in nix, and 9223372036854775808 is too big for a nix integer
+            write!(writer, "{} - 1", value + 1)
+        } else {
+            write!(writer, "{}", value)
+        }
+    }
+
+    /// Writes an unsigned integer literal. `value` is never larger than `i64::MAX`.
+    fn write_u64<W>(&mut self, writer: &mut W, value: u64) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        write!(writer, "{}", value)
+    }
+
+    /// Writes a finite single precision floating point literal.
+    fn write_f32<W>(&mut self, writer: &mut W, value: f32) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(float_literal(value).as_bytes())
+    }
+
+    /// Writes a finite floating point literal.
+    fn write_f64<W>(&mut self, writer: &mut W, value: f64) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(float_literal(value).as_bytes())
+    }
+
+    /// Writes a string literal. `literal` is already quoted and escaped.
+    fn write_string<W>(&mut self, writer: &mut W, literal: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(literal.as_bytes())
+    }
+
+    /// Writes an indented `''` string. `lines` are the lines of the string, already escaped so
+    /// that indentation added in front of every non-empty line is stripped again by nix. The
+    /// last line is empty if the string ends with a newline.
+    fn write_indented_string<W>(&mut self, writer: &mut W, lines: &[String]) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"''")?;
+        for line in lines {
+            write!(writer, "\n{}", line)?;
+        }
+        writer.write_all(b"''")
+    }
+
+    /// Writes a path. `literal` is a validated path literal, or for paths a literal can't hold,
+    /// an expression like `/. + "/weird path"`.
+    fn write_path<W>(&mut self, writer: &mut W, literal: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(literal.as_bytes())
+    }
+
+    /// Writes the text of a [`RawExpr`](crate::RawExpr) as is.
+    fn write_raw_expr<W>(&mut self, writer: &mut W, text: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(text.as_bytes())
+    }
+
+    /// Called before each argument of a function call, such as the `true` in `lib.mkForce true`.
+    fn begin_apply_arg<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b" ")
+    }
+
+    /// Writes an attribute name. `key` is already quoted and escaped if it needs to be. With
+    /// [`AttrPaths::Dotted`] it can be a path of several names joined by `.`.
+    fn write_attr_key<W>(&mut self, writer: &mut W, key: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(key.as_bytes())
+    }
+
+    /// Called before a value that has to be wrapped in parentheses, e.g. `-1` as a list element.
+    fn begin_parens<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"(")
+    }
+
+    /// Called after a value that has to be wrapped in parentheses.
+    fn end_parens<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b")")
+    }
+
+    /// Called before the first attribute of an attrset.
+    fn begin_attrs<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"{ ")
+    }
+
+    /// Called after the last attribute of an attrset.
+    fn end_attrs<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"}")
+    }
+
+    /// Called before each attribute name.
+    fn begin_attr_key<W>(&mut self, _writer: &mut W, _first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        Ok(())
+    }
+
+    /// Called after each attribute name.
+    fn end_attr_key<W>(&mut self, _writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        Ok(())
+    }
+
+    /// Called between an attribute name and its value.
+    fn begin_attr_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b" = ")
+    }
+
+    /// Called after each attribute value.
+    fn end_attr_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"; ")
+    }
+
+    /// Called before the first element of a list.
+    fn begin_list<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"[ ")
+    }
+
+    /// Called after the last element of a list.
+    fn end_list<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"]")
+    }
+
+    /// Called before each list element.
+    fn begin_list_value<W>(&mut self, _writer: &mut W, _first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        Ok(())
+    }
+
+    /// Called after each list element.
+    fn end_list_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b" ")
+    }
+
+    /// Writes a comment from a [`Commented`] value or [`Serializer::header`], followed by
+    /// whatever separates it from what comes next. `text` is never empty, but can contain
+    /// anything, including line breaks and `*/`.
+    fn write_comment<W>(&mut self, writer: &mut W, text: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        let text = comment_lines(text).join(" ").replace("*/", "* /");
+        write!(writer, "/* {} */ ", text)
+    }
+
+    /// Writes the head of the module function written by [`Serializer::serialize_module`], e.g.
+    /// `{ config, lib, pkgs, ... }: `. `args` are valid identifiers.
+    fn write_module_args<W>(&mut self, writer: &mut W, args: &[String]) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"{ ")?;
+        for arg in args {
+            write!(writer, "{}, ", arg)?;
+        }
+        writer.write_all(b"... }: ")
+    }
+
+    /// Called before the bindings of the `let` written by
+    /// [`Serializer::serialize_deduplicated`]. They're written like attributes.
+    fn begin_let<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"let ")
+    }
+
+    /// Called between the bindings of a `let` and the value they're used in.
+    fn begin_let_body<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"in ")
+    }
+}
+
+/// Writes the whole expression on a single line, e.g. `{ name = "foo"; ports = [ 80 443 ]; }`.
+#[derive(Clone, Debug)]
+pub struct CompactFormatter;
+
+impl Formatter for CompactFormatter {}
+
+/// Writes one attribute or list element per line, in the style of nixfmt.
+#[derive(Clone, Debug)]
+pub struct PrettyFormatter {
+    indent: usize,
+    current_indent: usize,
+    has_value: bool,
+}
+
+impl PrettyFormatter {
+    /// Indents by two spaces per level.
+    pub fn new() -> Self {
+        PrettyFormatter::with_indent(2)
+    }
+
+    /// Indents by `width` spaces per level.
+    pub fn with_indent(width: usize) -> Self {
+        PrettyFormatter {
+            indent: width,
+            current_indent: 0,
+            has_value: false,
+        }
+    }
+
+    fn write_newline_indent<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        write!(writer, "\n{:1$}", "", self.current_indent * self.indent)
+    }
+
+    fn begin_compound<W>(&mut self, writer: &mut W, open: &[u8]) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.current_indent += 1;
+        self.has_value = false;
+        writer.write_all(open)
+    }
+
+    // An empty compound is written as `{ }` or `[ ]`, anything else gets its closing delimiter on
+    // its own line.
+    fn end_compound<W>(&mut self, writer: &mut W, close: &[u8]) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.current_indent -= 1;
+        if self.has_value {
+            self.write_newline_indent(writer)?;
+        } else {
+            writer.write_all(b" ")?;
+        }
+        writer.write_all(close)
+    }
+}
+
+impl Default for PrettyFormatter {
+    fn default() -> Self {
+        PrettyFormatter::new()
+    }
+}
+
+impl Formatter for PrettyFormatter {
+    // The text goes one level deeper than the line the string starts on, and the closing quotes
+    // line up with that line, as nixfmt does it.
+    fn write_indented_string<W>(&mut self, writer: &mut W, lines: &[String]) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        let text_indent = (self.current_indent + 1) * self.indent;
+        writer.write_all(b"''")?;
+        let (last, init) = lines.split_last().expect("a string has at least one line");
+        for line in init {
+            if line.is_empty() {
+                writer.write_all(b"\n")?;
+            } else {
+                write!(writer, "\n{:2$}{}", "", line, text_indent)?;
+            }
+        }
+        if last.is_empty() {
+            self.write_newline_indent(writer)?;
+        } else {
+            write!(writer, "\n{:2$}{}", "", last, text_indent)?;
+        }
+        writer.write_all(b"''")
+    }
+
+    fn begin_attrs<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.begin_compound(writer, b"{")
+    }
+
+    fn end_attrs<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.end_compound(writer, b"}")
+    }
+
+    fn begin_attr_key<W>(&mut self, writer: &mut W, _first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.write_newline_indent(writer)
+    }
+
+    fn end_attr_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.has_value = true;
+        writer.write_all(b";")
+    }
+
+    fn begin_list<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.begin_compound(writer, b"[")
+    }
+
+    fn end_list<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.end_compound(writer, b"]")
+    }
+
+    fn begin_list_value<W>(&mut self, writer: &mut W, _first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.write_newline_indent(writer)
+    }
+
+    fn end_list_value<W>(&mut self, _writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.has_value = true;
+        Ok(())
+    }
+
+    fn write_comment<W>(&mut self, writer: &mut W, text: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        for line in comment_lines(text) {
+            if line.is_empty() {
+                writer.write_all(b"#")?;
+            } else {
+                write!(writer, "# {}", line)?;
+            }
+            self.write_newline_indent(writer)?;
+        }
+        Ok(())
+    }
+
+    fn write_module_args<W>(&mut self, writer: &mut W, args: &[String]) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        writer.write_all(b"{ ")?;
+        for arg in args {
+            write!(writer, "{}, ", arg)?;
+        }
+        writer.write_all(b"... }:")?;
+        self.write_newline_indent(writer)
+    }
+
+    // The bindings go one level deeper than `let`, and `in` and the value line up with it.
+    fn begin_let<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.current_indent += 1;
+        writer.write_all(b"let")
+    }
+
+    fn begin_let_body<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.current_indent -= 1;
+        self.write_newline_indent(writer)?;
+        writer.write_all(b"in")?;
+        self.write_newline_indent(writer)
+    }
+}
+
+// Split a comment into lines. Nix ends a `#` comment at a carriage return as well as a newline,
+// so both have to start a new one. A final line break doesn't leave an empty line behind.
+fn comment_lines(text: &str) -> Vec<&str> {
+    let mut lines: Vec<_> = text
+        .split('\n')
+        .flat_map(|line| line.strip_suffix('\r').unwrap_or(line).split('\r'))
+        .collect();
+    if lines.len() > 1 && lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines
 }
 
-impl<W> Serializer<W>
+// `s` with the characters nix can't hold dealt with as `policy` says.
+pub(crate) fn representable(s: &str, policy: UnrepresentableChars) -> Result<Cow<'_, str>> {
+    if !s.contains('\0') {
+        return Ok(Cow::Borrowed(s));
+    }
+    match policy {
+        UnrepresentableChars::Error => Err(Error::UnencodableNullString(String::new())),
+        UnrepresentableChars::Drop => Ok(Cow::Owned(s.replace('\0', ""))),
+        UnrepresentableChars::Replace => Ok(Cow::Owned(s.replace('\0', "\u{fffd}"))),
+    }
+}
+
+// Format a finite float so nix reads it back as the same float. Nix float literals must contain a
+// `.`, otherwise they're integers, and Rust's `Display` never uses an exponent, so very large or
+// small values switch to scientific notation.
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L99
+pub(crate) fn float_literal<T>(value: T) -> String
 where
-    W: io::Write,
+    T: fmt::Display + fmt::LowerExp,
 {
-    pub fn new(writer: W) -> Self {
-        Serializer { writer }
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-5..16).contains(&exponent) {
+        let decimal = value.to_string();
+        if decimal.contains('.') {
+            decimal
+        } else {
+            decimal + ".0"
+        }
+    } else if mantissa.contains('.') {
+        scientific
+    } else {
+        format!("{}.0e{}", mantissa, exponent)
+    }
+}
+
+// Write the given path as a nix path literal, tidying up the forms nix won't parse but that have
+// an obvious literal, like `/` and `foo`. A path with characters a literal can't hold is appended
+// as a string to the root or current directory instead, e.g. `/. + "/weird path"`, which nix
+// evaluates to the same path. The flag says whether that happened, since the result then isn't a
+// select expression.
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L107-L112
+fn path_expression(path: &str) -> Result<(String, bool)> {
+    if let Some(literal) = path_literal(path) {
+        return Ok((literal, false));
+    }
+    let (base, rest) = if path.starts_with('/') {
+        ("/.", path.to_string())
+    } else if path.is_empty() || path == "~" || path.starts_with("~/") {
+        // `~` is only understood at the start of a path literal
+        return Err(Error::InvalidPath(path.to_string()));
+    } else {
+        (
+            "./.",
+            format!("/{}", path.strip_prefix("./").unwrap_or(path)),
+        )
+    };
+    Ok((format!("{} + {}", base, escape(&rest)), true))
+}
+
+// Whether `path` can be written at all, which it can't if it's empty or `~` forms nix only reads
+// in literals.
+pub(crate) fn is_expressible_path(path: &str) -> bool {
+    path_expression(path).is_ok()
+}
+
+fn path_literal(path: &str) -> Option<String> {
+    let trimmed = path.trim_end_matches('/');
+    let literal = if trimmed.is_empty() && !path.is_empty() {
+        "/.".to_string()
+    } else if !trimmed.contains('/') && trimmed != "~" && !trimmed.is_empty() {
+        format!("./{}", trimmed)
+    } else {
+        trimmed.to_string()
+    };
+
+    let mut segments = literal.split('/');
+    let valid_prefix = match segments.next() {
+        Some("~") => true,
+        Some(first) => first.chars().all(is_path_char),
+        None => false,
+    };
+    let valid = valid_prefix
+        && literal.contains('/')
+        && segments.all(|seg| !seg.is_empty() && seg.chars().all(is_path_char));
+    if valid {
+        Some(literal)
+    } else {
+        None
+    }
+}
+
+// Escape `s` for an indented string, split into lines. Nix strips the smallest indentation of
+// any line and a final line of only spaces, so the text is arranged to keep whatever indentation
+// the formatter adds the smallest, and to never end in a line of only spaces. Escapes don't count
+// as indentation.
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L172-L202
+fn indented_string_lines(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.chars().collect();
+    // Whether the escaped text for `chars[i..]` starts with a `'`, which a lone `'` before it
+    // would run into. The closing `''` counts too.
+    let quote_follows = |i: usize| match chars.get(i) {
+        None | Some('\r') => true,
+        Some('$') => chars.get(i + 1) == Some(&'{'),
+        _ => false,
+    };
+    let mut escaped = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\'' if chars.get(i + 1) == Some(&'\'') => {
+                escaped += "'''";
+                i += 1;
+            }
+            '\'' if quote_follows(i + 1) => escaped += "''\\'",
+            '$' if chars.get(i + 1) == Some(&'{') => escaped += "''$",
+            '\r' => escaped += "''\\r",
+            c => escaped.push(c),
+        }
+        i += 1;
+    }
+
+    let mut lines: Vec<String> = escaped.split('\n').map(str::to_string).collect();
+    let last = lines.last_mut().unwrap();
+    if !last.is_empty() && last.chars().all(|c| c == ' ') {
+        last.pop();
+        last.push_str("''\\ ");
+    }
+    // If every line starts with a space, nix would strip those too.
+    let non_empty = |line: &&mut String| !line.is_empty();
+    if lines
+        .iter_mut()
+        .filter(non_empty)
+        .all(|line| line.starts_with(' '))
+    {
+        if let Some(first) = lines.iter_mut().find(non_empty) {
+            first.replace_range(..1, "''\\ ");
+        }
     }
+    lines
 }
 
-fn escape(s: &str) -> Result<String> {
+fn escape(s: &str) -> String {
     let mut result = String::new();
     result += "\"";
     let mut chars = s.chars().peekable();
     while let Some(c) = chars.next() {
-        result += &escape_char(c, chars.peek())?;
+        escape_char(&mut result, c, chars.peek());
     }
 
     result += "\"";
-    Ok(result)
+    result
 }
 
 // Escape the given string into a nix map key. Omit quoting for keys that don't need it
-fn escape_map_key(s: &str) -> Result<String> {
-    // keywords can't be map keys
-    // https://github.com/NixOS/nix/blob/master/src/libexpr/lexer.l#L109-L118
-    match s {
-        "if" | "then" | "else" | "assert" | "with" | "let" | "in" | "rec" | "inherit" | "or" => {
-            return escape(s);
-        }
-        _ => {}
-    }
-
-    // https://github.com/NixOS/nix/blob/1a14ce83811038b05b653df461a944ef0847d14d/doc/manual/src/language/values.md?plain=1#L168
-    let mut chars = s.chars();
-    match chars.next() {
-        // empty string must escape
-        None => return escape(s),
-        Some('a'..='z' | 'A'..='Z' | '_') => {
-            // valid first chars
-        }
-        _ => return escape(s),
-    };
-
-    // rules for all characters after the initial one
-    let requires_quoting =
-        |c: char| -> bool { !matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '\'' | '-') };
-    if chars.any(requires_quoting) {
-        return escape(s);
+fn escape_map_key(s: &str) -> String {
+    // Keywords and anything the lexer wouldn't read as a single identifier must be quoted.
+    if syntax::is_ident(s) {
+        s.to_string()
+    } else {
+        escape(s)
     }
-    Ok(s.to_string())
 }
 
-fn escape_char(c: char, peek: Option<&char>) -> Result<String> {
-    Ok(match (c, peek) {
-        ('\0', _) => return Err(Error::UnencodableNullString),
-        ('\n', _) => "\\n".to_string(),
-        ('\t', _) => "\\t".to_string(),
-        ('\r', _) => "\\r".to_string(),
-        ('\\', _) => "\\\\".to_string(),
-        ('"', _) => "\\\"".to_string(),
-        ('$', Some('{')) => "\\$".to_string(),
-        (c, _) => c.to_string(),
-    })
+// Nix strings only have escapes for `\n`, `\t` and `\r`, and a backslash before any other
+// character stands for the character itself. Everything else, control characters included, is
+// read as is and so written raw, except that nix turns a raw `\r` into `\n`, and a null ends the
+// string, which `Serializer::representable` has already dealt with.
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l
+fn escape_char(result: &mut String, c: char, peek: Option<&char>) {
+    match (c, peek) {
+        ('\n', _) => result.push_str("\\n"),
+        ('\t', _) => result.push_str("\\t"),
+        ('\r', _) => result.push_str("\\r"),
+        ('\\', _) => result.push_str("\\\\"),
+        ('"', _) => result.push_str("\\\""),
+        ('$', Some('{')) => result.push_str("\\$"),
+        (c, _) => result.push(c),
+    }
 }
 
-impl<'a, W> serde::Serializer for &'a mut Serializer<W>
+impl<'a, W, F> serde::Serializer for &'a mut Serializer<W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
-    type SerializeSeq = NixExpr<'a, W>;
-    type SerializeTuple = NixExpr<'a, W>;
-    type SerializeTupleStruct = NixExpr<'a, W>;
-    type SerializeTupleVariant = NixExpr<'a, W>;
-    type SerializeMap = NixExpr<'a, W>;
-    type SerializeStruct = NixExpr<'a, W>;
-    type SerializeStructVariant = NixExpr<'a, W>;
+    type SerializeSeq = NixExpr<'a, W, F>;
+    type SerializeTuple = NixExpr<'a, W, F>;
+    type SerializeTupleStruct = NixExpr<'a, W, F>;
+    type SerializeTupleVariant = NixExpr<'a, W, F>;
+    type SerializeMap = NixExpr<'a, W, F>;
+    type SerializeStruct = NixExpr<'a, W, F>;
+    type SerializeStructVariant = NixExpr<'a, W, F>;
 
     fn serialize_bool(self, value: bool) -> Result<()> {
-        write!(self.writer, "{}", value)?;
+        self.take_select_position();
+        self.formatter.write_bool(&mut self.writer, value)?;
         Ok(())
     }
 
     fn serialize_i8(self, value: i8) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        self.serialize_i64(value.into())
     }
 
     fn serialize_i16(self, value: i16) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        self.serialize_i64(value.into())
     }
 
     fn serialize_i32(self, value: i32) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        self.serialize_i64(value.into())
     }
 
     fn serialize_i64(self, value: i64) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        let parens = self.take_select_position() && value < 0;
+        self.parenthesize_if(parens, |ser| {
+            ser.formatter.write_i64(&mut ser.writer, value)?;
+            Ok(())
+        })
     }
 
     fn serialize_u8(self, value: u8) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        self.serialize_u64(value.into())
     }
 
     fn serialize_u16(self, value: u16) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        self.serialize_u64(value.into())
     }
 
     fn serialize_u32(self, value: u32) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        self.serialize_u64(value.into())
     }
 
     fn serialize_u64(self, value: u64) -> Result<()> {
-        write!(self.writer, "{}", value)?;
+        if i64::try_from(value).is_err() {
+            return self.serialize_out_of_range(value, value as f64);
+        }
+        self.take_select_position();
+        self.formatter.write_u64(&mut self.writer, value)?;
         Ok(())
     }
 
+    fn serialize_i128(self, value: i128) -> Result<()> {
+        match i64::try_from(value) {
+            Ok(value) => self.serialize_i64(value),
+            Err(_) => self.serialize_out_of_range(value, value as f64),
+        }
+    }
+
+    fn serialize_u128(self, value: u128) -> Result<()> {
+        match u64::try_from(value) {
+            Ok(value) => self.serialize_u64(value),
+            Err(_) => self.serialize_out_of_range(value, value as f64),
+        }
+    }
+
     fn serialize_f32(self, value: f32) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        if !value.is_finite() {
+            return self.serialize_non_finite_float(value.into());
+        }
+        let parens = self.take_select_position() && value.is_sign_negative();
+        self.parenthesize_if(parens, |ser| {
+            ser.formatter.write_f32(&mut ser.writer, value)?;
+            Ok(())
+        })
     }
 
     fn serialize_f64(self, value: f64) -> Result<()> {
-        write!(self.writer, "{}", value)?;
-        Ok(())
+        if !value.is_finite() {
+            return self.serialize_non_finite_float(value);
+        }
+        let parens = self.take_select_position() && value.is_sign_negative();
+        self.parenthesize_if(parens, |ser| {
+            ser.formatter.write_f64(&mut ser.writer, value)?;
+            Ok(())
+        })
     }
 
     fn serialize_char(self, value: char) -> Result<()> {
@@ -151,12 +1597,21 @@ where
     }
 
     fn serialize_str(self, value: &str) -> Result<()> {
-        write!(self.writer, "{}", escape(value)?)?;
+        if self.options.multiline_strings == MultilineStrings::Indented && value.contains('\n') {
+            return self.serialize_indented_string(value);
+        }
+        self.take_select_position();
+        let value = self.representable(value)?;
+        self.formatter
+            .write_string(&mut self.writer, &escape(&value))?;
         Ok(())
     }
 
     fn serialize_bytes(self, value: &[u8]) -> Result<()> {
         use serde::ser::SerializeSeq;
+        if let Some(s) = bytes::encode(value, self.options.bytes)? {
+            return self.serialize_str(&s);
+        }
         let mut seq = self.serialize_seq(Some(value.len()))?;
         for byte in value {
             seq.serialize_element(byte)?;
@@ -165,13 +1620,13 @@ where
     }
 
     fn serialize_unit(self) -> Result<()> {
-        write!(self.writer, "null")?;
+        self.take_select_position();
+        self.formatter.write_null(&mut self.writer)?;
         Ok(())
     }
 
     fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
-        write!(self.writer, "null")?;
-        Ok(())
+        self.serialize_unit()
     }
 
     fn serialize_unit_variant(
@@ -183,10 +1638,22 @@ where
         self.serialize_str(variant)
     }
 
-    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<()>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
+        if name == PATH_TOKEN {
+            let path = value.serialize(StrCollector)?;
+            let (expr, is_concat) = path_expression(&self.representable(&path)?)?;
+            let parens = self.take_select_position() && is_concat;
+            return self.parenthesize_if(parens, |ser| {
+                ser.formatter.write_path(&mut ser.writer, &expr)?;
+                Ok(())
+            });
+        }
+        if name == INDENTED_STRING_TOKEN {
+            return self.serialize_indented_string(&value.serialize(StrCollector)?);
+        }
         value.serialize(self)
     }
 
@@ -200,12 +1667,11 @@ where
     where
         T: ?Sized + Serialize,
     {
-        write!(self.writer, "{{ ")?;
-        self.serialize_str(variant)?;
-        write!(self.writer, " = ")?;
-        value.serialize(&mut *self)?;
-        write!(self.writer, "; }}")?;
-        Ok(())
+        self.write_variant_key(variant)?;
+        value
+            .serialize(&mut *self)
+            .map_err(|err| err.in_attr(variant))?;
+        self.end_variant()
     }
 
     fn serialize_none(self) -> Result<()> {
@@ -222,8 +1688,9 @@ where
 
     #[inline]
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        write!(self.writer, "[ ")?;
-        Ok(NixExpr::Map { ser: self })
+        self.take_select_position();
+        self.formatter.begin_list(&mut self.writer)?;
+        Ok(NixExpr(Compound::Map { ser: self, len: 0 }))
     }
 
     #[inline]
@@ -248,19 +1715,40 @@ where
         variant: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
-        self.serialize_str(variant)?;
+        self.write_variant_key(variant)?;
         self.serialize_seq(Some(len))
     }
 
     #[inline]
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        write!(self.writer, "{{ ")?;
-        Ok(NixExpr::Map { ser: self })
+        self.take_select_position();
+        // Attrsets written as dotted paths were already found to have string keys.
+        if self.options.map_keys == MapKeys::Pairs && self.dotted.is_none() {
+            return Ok(NixExpr(Compound::Pairs {
+                ser: self,
+                entries: Vec::new(),
+                next_key: None,
+            }));
+        }
+        self.begin_attrs_expr().map(NixExpr)
     }
 
     #[inline]
-    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
-        self.serialize_map(Some(len))
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        if name == RAW_EXPR_TOKEN {
+            return Ok(NixExpr(Compound::RawValue { ser: self }));
+        }
+        if name == COMMENTED_TOKEN {
+            return Ok(NixExpr(Compound::Commented { ser: self }));
+        }
+        if name == APPLY_TOKEN {
+            return Ok(NixExpr(Compound::Apply {
+                ser: self,
+                parens: false,
+            }));
+        }
+        self.take_select_position();
+        self.begin_attrs_expr().map(NixExpr)
     }
 
     #[inline]
@@ -269,22 +1757,152 @@ where
         _name: &'static str,
         _variant_index: u32,
         variant: &'static str,
-        len: usize,
+        _len: usize,
     ) -> Result<Self::SerializeStructVariant> {
-        self.serialize_str(variant)?;
-        self.serialize_map(Some(len))
+        self.write_variant_key(variant)?;
+        self.begin_attrs_expr().map(NixExpr)
+    }
+}
+
+impl<W, F> Serializer<W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    fn begin_attrs_expr(&mut self) -> Result<Compound<'_, W, F>> {
+        let (path, next) = match self.dotted.take() {
+            Some(dotted) => dotted,
+            None => {
+                self.formatter.begin_attrs(&mut self.writer)?;
+                (Vec::new(), NextAttr::First)
+            }
+        };
+        if self.options.attr_order == AttrOrder::Sorted {
+            return Ok(Compound::SortedMap {
+                ser: self,
+                path,
+                next,
+                entries: Vec::new(),
+                next_key: None,
+            });
+        }
+        Ok(Compound::Attrs {
+            ser: self,
+            path,
+            next,
+            next_key: None,
+        })
+    }
+
+    // Writes the `index`th element of a map written as a list of pairs. The value is written even
+    // if it's null, so that every pair has one.
+    fn write_pair(&mut self, index: usize, key: &Content, value: &Content) -> Result<()> {
+        self.formatter
+            .begin_list_value(&mut self.writer, index == 0)?;
+        self.formatter.begin_attrs(&mut self.writer)?;
+        let name = ["name".to_string()];
+        self.write_attr(&name, &mut NextAttr::First, &Shape::Other, key)
+            .map_err(|err| err.in_attr("name").in_element(index))?;
+        let name = ["value".to_string()];
+        let shape = Shape::of(value, false);
+        self.write_attr(&name, &mut NextAttr::Rest, &shape, value)
+            .map_err(|err| err.in_attr("value").in_element(index))?;
+        self.formatter.end_attrs(&mut self.writer)?;
+        self.formatter.end_list_value(&mut self.writer)?;
+        Ok(())
     }
 }
 
-pub enum NixExpr<'a, W> {
-    Map { ser: &'a mut Serializer<W> },
-    Number { ser: &'a mut Serializer<W> },
-    RawValue { ser: &'a mut Serializer<W> },
+// What the `Serialize*` types of `Serializer` return: a list, attrset or other compound value
+// being written. Its state is kept private so it can change without breaking anyone.
+pub struct NixExpr<'a, W, F = CompactFormatter>(Compound<'a, W, F>);
+
+enum Compound<'a, W, F> {
+    // A list. `len` is the number of elements written so far.
+    Map {
+        ser: &'a mut Serializer<W, F>,
+        len: usize,
+    },
+    // An attrset. `path` is the path to it if its attributes are written as dotted paths on its
+    // parent, or empty if it's in braces. Keys are held back until their value is seen, since
+    // the value decides how the attribute is written, or whether it's written at all.
+    Attrs {
+        ser: &'a mut Serializer<W, F>,
+        path: Vec<String>,
+        next: NextAttr,
+        next_key: Option<String>,
+    },
+    // An attrset written in sorted order, otherwise like `Attrs`. The
+    // attributes are written to buffers as they come and copied out in order once the attrset is
+    // complete.
+    SortedMap {
+        ser: &'a mut Serializer<W, F>,
+        path: Vec<String>,
+        next: NextAttr,
+        entries: Vec<(String, Vec<u8>)>,
+        next_key: Option<String>,
+    },
+    RawValue {
+        ser: &'a mut Serializer<W, F>,
+    },
+    Commented {
+        ser: &'a mut Serializer<W, F>,
+    },
+    // A function call, parenthesized if it's in a select position.
+    Apply {
+        ser: &'a mut Serializer<W, F>,
+        parens: bool,
+    },
+    // A map under `MapKeys::Pairs`, which is an attrset if all of its keys are strings and a list
+    // of `{ name = ...; value = ...; }` attrsets otherwise. The entries are held until the map is
+    // complete and then written the one way or the other.
+    Pairs {
+        ser: &'a mut Serializer<W, F>,
+        entries: Vec<(Content, Content)>,
+        next_key: Option<Content>,
+    },
+}
+
+impl<'a, W, F> Compound<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    // Writes out any buffered attributes and closes the attrset.
+    fn end_attrs(self) -> Result<&'a mut Serializer<W, F>> {
+        match self {
+            Compound::Attrs { ser, path, .. } => {
+                if path.is_empty() {
+                    ser.formatter.end_attrs(&mut ser.writer)?;
+                }
+                Ok(ser)
+            }
+            Compound::SortedMap {
+                ser,
+                path,
+                mut next,
+                mut entries,
+                ..
+            } => {
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                for (_, attr) in &entries {
+                    ser.begin_attr(&mut next)?;
+                    io::Write::write_all(&mut ser.writer, attr)?;
+                }
+                if path.is_empty() {
+                    ser.formatter.end_attrs(&mut ser.writer)?;
+                }
+                Ok(ser)
+            }
+            _ => unreachable!(),
+        }
+    }
 }
 
-impl<'a, W> ser::SerializeSeq for NixExpr<'a, W>
+impl<'a, W, F> ser::SerializeSeq for Compound<'a, W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
@@ -294,9 +1912,17 @@ where
         T: ?Sized + Serialize,
     {
         match *self {
-            NixExpr::Map { ref mut ser } => {
-                value.serialize(&mut **ser)?;
-                write!(ser.writer, " ")?;
+            Compound::Map {
+                ref mut ser,
+                ref mut len,
+            } => {
+                ser.formatter.begin_list_value(&mut ser.writer, *len == 0)?;
+                ser.select_position = true;
+                value
+                    .serialize(&mut **ser)
+                    .map_err(|err| err.in_element(*len))?;
+                ser.formatter.end_list_value(&mut ser.writer)?;
+                *len += 1;
                 Ok(())
             }
             _ => unreachable!(),
@@ -305,8 +1931,8 @@ where
 
     fn end(self) -> Result<()> {
         match self {
-            NixExpr::Map { ser } => {
-                write!(ser.writer, "]")?;
+            Compound::Map { ser, .. } => {
+                ser.formatter.end_list(&mut ser.writer)?;
                 Ok(())
             }
             _ => unreachable!(),
@@ -314,9 +1940,10 @@ where
     }
 }
 
-impl<'a, W> ser::SerializeTuple for NixExpr<'a, W>
+impl<'a, W, F> ser::SerializeTuple for Compound<'a, W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
@@ -333,9 +1960,10 @@ where
     }
 }
 
-impl<'a, W> ser::SerializeTupleStruct for NixExpr<'a, W>
+impl<'a, W, F> ser::SerializeTupleStruct for Compound<'a, W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
@@ -352,9 +1980,10 @@ where
     }
 }
 
-impl<'a, W> ser::SerializeTupleVariant for NixExpr<'a, W>
+impl<'a, W, F> ser::SerializeTupleVariant for Compound<'a, W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
@@ -367,13 +1996,20 @@ where
     }
 
     fn end(self) -> Result<()> {
-        ser::SerializeSeq::end(self)
+        match self {
+            Compound::Map { ser, .. } => {
+                ser.formatter.end_list(&mut ser.writer)?;
+                ser.end_variant()
+            }
+            _ => unreachable!(),
+        }
     }
 }
 
-impl<'a, W> ser::SerializeMap for NixExpr<'a, W>
+impl<'a, W, F> ser::SerializeMap for Compound<'a, W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
@@ -383,40 +2019,319 @@ where
         T: ?Sized + Serialize,
     {
         match *self {
-            NixExpr::Map { ref mut ser } => key.serialize(MapKeySerializer { ser: *ser }),
+            Compound::Attrs {
+                ref ser,
+                ref mut next_key,
+                ..
+            }
+            | Compound::SortedMap {
+                ref ser,
+                ref mut next_key,
+                ..
+            } => {
+                let stringify = ser.options.map_keys == MapKeys::Stringify;
+                *next_key = Some(value::key_to_string(key, stringify)?);
+                Ok(())
+            }
+            Compound::Pairs {
+                ref mut next_key, ..
+            } => {
+                *next_key = Some(content::to_content(key)?);
+                Ok(())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        match *self {
+            Compound::Attrs {
+                ref mut ser,
+                ref path,
+                ref mut next,
+                ref mut next_key,
+            } => {
+                let key = next_key
+                    .take()
+                    .expect("serialize_value called before serialize_key");
+                let mut path = path.clone();
+                path.push(key);
+                ser.serialize_attr(path, next, value)?;
+                Ok(())
+            }
+            Compound::SortedMap {
+                ref mut ser,
+                ref path,
+                ref mut entries,
+                ref mut next_key,
+                ..
+            } => {
+                let key = next_key
+                    .take()
+                    .expect("serialize_value called before serialize_key");
+                let mut path = path.clone();
+                path.push(key.clone());
+                ser.writer.buffers.push(Vec::new());
+                let written = ser.serialize_attr(path, &mut NextAttr::Begun, value);
+                let buffer = ser.writer.buffers.pop().unwrap();
+                if written? {
+                    entries.push((key, buffer));
+                }
+                Ok(())
+            }
+            Compound::Pairs {
+                ref mut entries,
+                ref mut next_key,
+                ..
+            } => {
+                let key = next_key
+                    .take()
+                    .expect("serialize_value called before serialize_key");
+                entries.push((key, content::to_content(value)?));
+                Ok(())
+            }
             _ => unreachable!(),
         }
     }
 
+    fn end(self) -> Result<()> {
+        match self {
+            Compound::Pairs { ser, entries, .. } => {
+                if entries
+                    .iter()
+                    .all(|(key, _)| value::key_to_string(key, false).is_ok())
+                {
+                    let mut attrs = ser.begin_attrs_expr()?;
+                    for (key, value) in &entries {
+                        ser::SerializeMap::serialize_entry(&mut attrs, key, value)?;
+                    }
+                    return ser::SerializeMap::end(attrs);
+                }
+                ser.formatter.begin_list(&mut ser.writer)?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    ser.write_pair(i, key, value)?;
+                }
+                ser.formatter.end_list(&mut ser.writer)?;
+            }
+            _ => {
+                self.end_attrs()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W, F> ser::SerializeStruct for Compound<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        match *self {
+            Compound::Attrs { .. } | Compound::SortedMap { .. } => {
+                ser::SerializeMap::serialize_entry(self, key, value)
+            }
+            Compound::RawValue { ref mut ser } => {
+                let text = value.serialize(StrCollector)?;
+                let parens = ser.take_select_position() && !raw::is_select_expr(&text);
+                ser.parenthesize_if(parens, |ser| {
+                    ser.formatter.write_raw_expr(&mut ser.writer, &text)?;
+                    Ok(())
+                })
+            }
+            Compound::Commented { ref mut ser } if key == COMMENTED_TOKEN => {
+                let comment = value.serialize(StrCollector)?;
+                if std::mem::replace(&mut ser.comment_written, false) {
+                    return Ok(());
+                }
+                ser.write_comment(&comment)
+            }
+            Compound::Commented { ref mut ser } => value.serialize(&mut **ser),
+            Compound::Apply {
+                ref mut ser,
+                ref mut parens,
+            } if key == APPLY_TOKEN => {
+                let function = value.serialize(StrCollector)?;
+                *parens = ser.take_select_position();
+                if *parens {
+                    ser.formatter.begin_parens(&mut ser.writer)?;
+                }
+                ser.formatter.write_raw_expr(&mut ser.writer, &function)?;
+                Ok(())
+            }
+            Compound::Apply { ref mut ser, .. } => {
+                ser.formatter.begin_apply_arg(&mut ser.writer)?;
+                ser.select_position = true;
+                value.serialize(&mut **ser)
+            }
+            Compound::Map { .. } | Compound::Pairs { .. } => unreachable!(),
+        }
+    }
+
+    fn end(self) -> Result<()> {
+        match self {
+            Compound::Attrs { .. } | Compound::SortedMap { .. } => ser::SerializeMap::end(self),
+            Compound::Apply { ser, parens: true } => {
+                ser.formatter.end_parens(&mut ser.writer)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a, W, F> ser::SerializeStructVariant for Compound<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        match *self {
+            Compound::Attrs { .. } | Compound::SortedMap { .. } => {
+                ser::SerializeStruct::serialize_field(self, key, value)
+            }
+            Compound::Map { .. }
+            | Compound::RawValue { .. }
+            | Compound::Commented { .. }
+            | Compound::Apply { .. }
+            | Compound::Pairs { .. } => unreachable!(),
+        }
+    }
+
+    fn end(self) -> Result<()> {
+        match self {
+            Compound::Attrs { .. } | Compound::SortedMap { .. } => self.end_attrs()?.end_variant(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a, W, F> ser::SerializeSeq for NixExpr<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeSeq::serialize_element(&mut self.0, value)
+    }
+
+    fn end(self) -> Result<()> {
+        ser::SerializeSeq::end(self.0)
+    }
+}
+
+impl<'a, W, F> ser::SerializeTuple for NixExpr<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeTuple::serialize_element(&mut self.0, value)
+    }
+
+    fn end(self) -> Result<()> {
+        ser::SerializeTuple::end(self.0)
+    }
+}
+
+impl<'a, W, F> ser::SerializeTupleStruct for NixExpr<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeTupleStruct::serialize_field(&mut self.0, value)
+    }
+
+    fn end(self) -> Result<()> {
+        ser::SerializeTupleStruct::end(self.0)
+    }
+}
+
+impl<'a, W, F> ser::SerializeTupleVariant for NixExpr<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeTupleVariant::serialize_field(&mut self.0, value)
+    }
+
+    fn end(self) -> Result<()> {
+        ser::SerializeTupleVariant::end(self.0)
+    }
+}
+
+impl<'a, W, F> ser::SerializeMap for NixExpr<'a, W, F>
+where
+    W: io::Write,
+    F: Formatter,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeMap::serialize_key(&mut self.0, value)
+    }
+
     fn serialize_value<T>(&mut self, value: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
     {
-        match *self {
-            NixExpr::Map { ref mut ser } => {
-                write!(ser.writer, " = ")?;
-                value.serialize(&mut **ser)?;
-                write!(ser.writer, "; ")?;
-                Ok(())
-            }
-            _ => unreachable!(),
-        }
+        ser::SerializeMap::serialize_value(&mut self.0, value)
     }
 
     fn end(self) -> Result<()> {
-        match self {
-            NixExpr::Map { ser } => {
-                write!(ser.writer, "}}")?;
-                Ok(())
-            }
-            _ => unreachable!(),
-        }
+        ser::SerializeMap::end(self.0)
     }
 }
 
-impl<'a, W> ser::SerializeStruct for NixExpr<'a, W>
+impl<'a, W, F> ser::SerializeStruct for NixExpr<'a, W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
@@ -425,24 +2340,18 @@ where
     where
         T: ?Sized + Serialize,
     {
-        match *self {
-            NixExpr::Map { .. } => ser::SerializeMap::serialize_entry(self, key, value),
-            NixExpr::Number { ref mut ser } => value.serialize(&mut **ser),
-            NixExpr::RawValue { .. } => unreachable!(),
-        }
+        ser::SerializeStruct::serialize_field(&mut self.0, key, value)
     }
 
     fn end(self) -> Result<()> {
-        match self {
-            NixExpr::Map { .. } => ser::SerializeMap::end(self),
-            _ => Ok(()),
-        }
+        ser::SerializeStruct::end(self.0)
     }
 }
 
-impl<'a, W> ser::SerializeStructVariant for NixExpr<'a, W>
+impl<'a, W, F> ser::SerializeStructVariant for NixExpr<'a, W, F>
 where
     W: io::Write,
+    F: Formatter,
 {
     type Ok = ();
     type Error = Error;
@@ -451,152 +2360,397 @@ where
     where
         T: ?Sized + Serialize,
     {
-        match *self {
-            NixExpr::Map { .. } | NixExpr::Number { .. } => {
-                ser::SerializeStruct::serialize_field(self, key, value)
-            }
-            NixExpr::RawValue { .. } => unreachable!(),
-        }
+        ser::SerializeStructVariant::serialize_field(&mut self.0, key, value)
     }
 
     fn end(self) -> Result<()> {
-        Ok(())
+        ser::SerializeStructVariant::end(self.0)
     }
 }
 
-struct MapKeySerializer<'a, W: 'a> {
-    ser: &'a mut Serializer<W>,
-}
+// Pulls the string out of the values wrapped by our private newtype tokens.
+pub(crate) struct StrCollector;
 
-impl<'a, W> ser::Serializer for MapKeySerializer<'a, W>
-where
-    W: io::Write,
-{
-    type Ok = ();
+impl ser::Serializer for StrCollector {
+    type Ok = String;
     type Error = Error;
 
-    #[inline]
-    fn serialize_str(self, value: &str) -> Result<()> {
-        write!(self.ser.writer, "{}", escape_map_key(value)?)?;
-        Ok(())
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    fn serialize_str(self, value: &str) -> Result<String> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_bool(self, _value: bool) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_i8(self, _value: i8) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_i16(self, _value: i16) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_i32(self, _value: i32) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_i64(self, _value: i64) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_u8(self, _value: u8) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_u16(self, _value: u16) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_u32(self, _value: u32) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_u64(self, _value: u64) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_f32(self, _value: f32) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_f64(self, _value: f64) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_char(self, value: char) -> Result<String> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_bytes(self, _value: &[u8]) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
     }
 
-    #[inline]
     fn serialize_unit_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
-        variant: &'static str,
-    ) -> Result<()> {
-        self.ser.serialize_str(variant)
+        _variant: &'static str,
+    ) -> Result<String> {
+        Err(ser::Error::custom("expected a string"))
     }
 
-    #[inline]
-    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<()>
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<String>
     where
         T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
 
-    type SerializeSeq = Impossible<(), Error>;
-    type SerializeTuple = Impossible<(), Error>;
-    type SerializeTupleStruct = Impossible<(), Error>;
-    type SerializeTupleVariant = Impossible<(), Error>;
-    type SerializeMap = Impossible<(), Error>;
-    type SerializeStruct = Impossible<(), Error>;
-    type SerializeStructVariant = Impossible<(), Error>;
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(ser::Error::custom("expected a string"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(ser::Error::custom("expected a string"))
+    }
+}
+
+// What a value looks like from the outside, as far as deciding how to write the attribute
+// holding it goes. Finding out only serializes as much of the value as it takes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Shape {
+    Null,
+    // `null` in an `OmitNull`.
+    OmittedNull,
+    // An attrset with this many attributes, not counting any that are left out for being null.
+    Attrs(usize),
+    // A value in a `Commented` that isn't left out, whose comment goes before the attribute name,
+    // and the shape of that value.
+    Commented(String, Box<Shape>),
+    Other,
+}
+
+impl Shape {
+    fn of<T>(value: &T, omit_nulls: bool) -> Shape
+    where
+        T: ?Sized + Serialize,
+    {
+        value
+            .serialize(ShapeProbe {
+                omit_nulls,
+                stringify_keys: false,
+                count_attrs: false,
+            })
+            .unwrap_or(Shape::Other)
+    }
+
+    fn is_omitted(&self, omit_nulls: bool) -> bool {
+        match *self {
+            Shape::OmittedNull => true,
+            Shape::Null => omit_nulls,
+            _ => false,
+        }
+    }
+}
+
+// Whether `value` is null in an `OmitNull`, for serializers without the option to omit all nulls.
+pub(crate) fn is_omitted_null<T>(value: &T) -> bool
+where
+    T: ?Sized + Serialize,
+{
+    Shape::of(value, false) == Shape::OmittedNull
+}
+
+#[derive(Clone, Copy)]
+struct ShapeProbe {
+    omit_nulls: bool,
+    // Whether keys other than strings are written as strings, rather than making the map
+    // something other than an attrset.
+    stringify_keys: bool,
+    // Whether attrsets come out as `Shape::Attrs` with their attributes counted, which takes a
+    // shallow probe of each of them, rather than as `Shape::Other` without looking inside.
+    count_attrs: bool,
+}
+
+struct CountAttrs {
+    probe: ShapeProbe,
+    count: usize,
+}
+
+enum ProbeStruct {
+    Attrs(CountAttrs),
+    Commented {
+        probe: ShapeProbe,
+        comment: String,
+        shape: Shape,
+    },
+}
 
-    fn serialize_bool(self, _value: bool) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+impl CountAttrs {
+    fn count<T>(&mut self, value: &T)
+    where
+        T: ?Sized + Serialize,
+    {
+        let probe = ShapeProbe {
+            count_attrs: false,
+            ..self.probe
+        };
+        let shape = value.serialize(probe).unwrap_or(Shape::Other);
+        if !shape.is_omitted(probe.omit_nulls) {
+            self.count += 1;
+        }
     }
+}
+
+// Lists, variants, raw expressions, function calls and attrsets that aren't being counted fail
+// rather than have a serializer for their contents made up, which comes out as `Shape::Other` all
+// the same.
+fn other<T>() -> Result<T> {
+    Err(ser::Error::custom("not an attrset"))
+}
+
+impl ser::Serializer for ShapeProbe {
+    type Ok = Shape;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<Shape, Error>;
+    type SerializeTuple = Impossible<Shape, Error>;
+    type SerializeTupleStruct = Impossible<Shape, Error>;
+    type SerializeTupleVariant = Impossible<Shape, Error>;
+    type SerializeMap = CountAttrs;
+    type SerializeStruct = ProbeStruct;
+    type SerializeStructVariant = Impossible<Shape, Error>;
 
-    fn serialize_i8(self, _value: i8) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_bool(self, _value: bool) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_i16(self, _value: i16) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_i8(self, _value: i8) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_i32(self, _value: i32) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_i16(self, _value: i16) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_i64(self, _value: i64) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_i32(self, _value: i32) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_u8(self, _value: u8) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_i64(self, _value: i64) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_u16(self, _value: u16) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_u8(self, _value: u8) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_u32(self, _value: u32) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_u16(self, _value: u16) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_u64(self, _value: u64) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_u32(self, _value: u32) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_f32(self, _value: f32) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_u64(self, _value: u64) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_f64(self, _value: f64) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_f32(self, _value: f32) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_char(self, value: char) -> Result<()> {
-        self.serialize_str(&value.to_string())
+    fn serialize_f64(self, _value: f64) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_bytes(self, _value: &[u8]) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_char(self, _value: char) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_unit(self) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_str(self, _value: &str) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_bytes(self, _value: &[u8]) -> Result<Shape> {
+        Ok(Shape::Other)
     }
 
-    fn serialize_newtype_variant<T>(
+    fn serialize_none(self) -> Result<Shape> {
+        Ok(Shape::Null)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Shape>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Shape> {
+        Ok(Shape::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Shape> {
+        Ok(Shape::Null)
+    }
+
+    fn serialize_unit_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
         _variant: &'static str,
-        _value: &T,
-    ) -> Result<()>
+    ) -> Result<Shape> {
+        Ok(Shape::Other)
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<Shape>
     where
         T: ?Sized + Serialize,
     {
-        Err(Error::MapKeyMustBeAString)
-    }
-
-    fn serialize_none(self) -> Result<()> {
-        Err(Error::MapKeyMustBeAString)
+        match value.serialize(self)? {
+            Shape::Null if name == OMIT_NULL_TOKEN => Ok(Shape::OmittedNull),
+            shape => Ok(shape),
+        }
     }
 
-    fn serialize_some<T>(self, _value: &T) -> Result<()>
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Shape>
     where
         T: ?Sized + Serialize,
     {
-        Err(Error::MapKeyMustBeAString)
+        Ok(Shape::Other)
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        Err(Error::MapKeyMustBeAString)
+        other()
     }
 
     fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
-        Err(Error::MapKeyMustBeAString)
+        other()
     }
 
     fn serialize_tuple_struct(
@@ -604,7 +2758,7 @@ where
         _name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleStruct> {
-        Err(Error::MapKeyMustBeAString)
+        other()
     }
 
     fn serialize_tuple_variant(
@@ -614,15 +2768,31 @@ where
         _variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
-        Err(Error::MapKeyMustBeAString)
+        other()
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        Err(Error::MapKeyMustBeAString)
+        if !self.count_attrs {
+            return other();
+        }
+        Ok(CountAttrs {
+            probe: self,
+            count: 0,
+        })
     }
 
-    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
-        Err(Error::MapKeyMustBeAString)
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        if name == RAW_EXPR_TOKEN || name == APPLY_TOKEN {
+            return other();
+        }
+        if name == COMMENTED_TOKEN {
+            return Ok(ProbeStruct::Commented {
+                probe: self,
+                comment: String::new(),
+                shape: Shape::Other,
+            });
+        }
+        self.serialize_map(Some(len)).map(ProbeStruct::Attrs)
     }
 
     fn serialize_struct_variant(
@@ -632,32 +2802,70 @@ where
         _variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStructVariant> {
-        Err(Error::MapKeyMustBeAString)
+        other()
+    }
+}
+
+impl ser::SerializeMap for CountAttrs {
+    type Ok = Shape;
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value::key_to_string(key, self.probe.stringify_keys)?;
+        Ok(())
     }
 
-    fn collect_str<T>(self, value: &T) -> Result<()>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
     where
-        T: ?Sized + fmt::Display,
+        T: ?Sized + Serialize,
     {
-        self.ser.collect_str(value)
+        self.count(value);
+        Ok(())
     }
-}
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
-    #[error("nix strings may not contain null bytes")]
-    UnencodableNullString,
-    #[error("nix map keys must be strings")]
-    MapKeyMustBeAString,
-    #[error("{0}")]
-    Custom(String),
+    fn end(self) -> Result<Shape> {
+        Ok(Shape::Attrs(self.count))
+    }
 }
 
-impl serde::ser::Error for Error {
-    fn custom<T: fmt::Display>(msg: T) -> Error {
-        Error::Custom(format!("{}", msg))
+impl ser::SerializeStruct for ProbeStruct {
+    type Ok = Shape;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        match self {
+            ProbeStruct::Attrs(attrs) => attrs.count(value),
+            ProbeStruct::Commented { comment, .. } if key == COMMENTED_TOKEN => {
+                *comment = value.serialize(StrCollector)?;
+            }
+            ProbeStruct::Commented { probe, shape, .. } => {
+                *shape = value.serialize(*probe).unwrap_or(Shape::Other);
+            }
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Shape> {
+        match self {
+            ProbeStruct::Attrs(attrs) => Ok(Shape::Attrs(attrs.count)),
+            ProbeStruct::Commented {
+                probe,
+                comment,
+                shape,
+            } => {
+                if shape.is_omitted(probe.omit_nulls) {
+                    Ok(shape)
+                } else {
+                    Ok(Shape::Commented(comment, Box::new(shape)))
+                }
+            }
+        }
     }
 }
 
@@ -670,6 +2878,15 @@ where
     value.serialize(&mut ser)
 }
 
+pub fn to_writer_pretty<W, T>(writer: W, value: &T) -> Result<()>
+where
+    W: io::Write,
+    T: ?Sized + Serialize,
+{
+    let mut ser = Serializer::pretty(writer);
+    value.serialize(&mut ser)
+}
+
 pub fn to_string<T>(value: &T) -> Result<String>
 where
     T: ?Sized + Serialize,
@@ -678,3 +2895,46 @@ where
     to_writer(&mut v, value)?;
     Ok(String::from_utf8(v).unwrap())
 }
+
+pub fn to_string_pretty<T>(value: &T) -> Result<String>
+where
+    T: ?Sized + Serialize,
+{
+    let mut v = Vec::new();
+    to_writer_pretty(&mut v, value)?;
+    Ok(String::from_utf8(v).unwrap())
+}
+
+/// Like [`to_string`], but with repeated attrsets and lists of at least `min_size` values bound
+/// once in a `let`. See [`Serializer::serialize_deduplicated`].
+pub fn to_string_deduplicated<T>(value: &T, min_size: usize) -> Result<String>
+where
+    T: ?Sized + Serialize,
+{
+    let mut v = Vec::new();
+    Serializer::new(&mut v).serialize_deduplicated(value, min_size)?;
+    Ok(String::from_utf8(v).unwrap())
+}
+
+/// Serializes `value` as a module function on a single line. See [`Serializer::serialize_module`].
+pub fn to_module_string<T, O>(value: &T, module: &Module<O>) -> Result<String>
+where
+    T: ?Sized + Serialize,
+    O: Serialize,
+{
+    let mut v = Vec::new();
+    Serializer::new(&mut v).serialize_module(value, module)?;
+    Ok(String::from_utf8(v).unwrap())
+}
+
+/// Serializes `value` as a pretty printed module function. See
+/// [`Serializer::serialize_module`].
+pub fn to_module_string_pretty<T, O>(value: &T, module: &Module<O>) -> Result<String>
+where
+    T: ?Sized + Serialize,
+    O: Serialize,
+{
+    let mut v = Vec::new();
+    Serializer::pretty(&mut v).serialize_module(value, module)?;
+    Ok(String::from_utf8(v).unwrap())
+}
@@ -0,0 +1,183 @@
+use super::Span;
+
+/// A nix expression together with the source it was parsed from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprKind {
+    Int(i64),
+    Float(f64),
+    /// A double quoted string, with escapes already processed.
+    Str(Vec<StrPart>),
+    /// An indented `''` string, with escapes processed and indentation already stripped.
+    IndStr(Vec<StrPart>),
+    /// A path such as `./foo.nix`, `/etc/${name}` or `~/.config`.
+    Path(Vec<StrPart>),
+    /// A lookup path such as `<nixpkgs>`, without the angle brackets.
+    SearchPath(String),
+    Uri(String),
+    /// A variable reference. `true`, `false` and `null` are variables too.
+    Ident(String),
+    List(Vec<Expr>),
+    Attrs {
+        rec: bool,
+        bindings: Vec<Binding>,
+    },
+    Let {
+        bindings: Vec<Binding>,
+        body: Box<Expr>,
+    },
+    With {
+        env: Box<Expr>,
+        body: Box<Expr>,
+    },
+    Assert {
+        cond: Box<Expr>,
+        body: Box<Expr>,
+    },
+    If {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        else_: Box<Expr>,
+    },
+    Lambda {
+        param: Param,
+        body: Box<Expr>,
+    },
+    Apply {
+        func: Box<Expr>,
+        arg: Box<Expr>,
+    },
+    /// `expr.a.b`, or `expr.a.b or default`.
+    Select {
+        expr: Box<Expr>,
+        path: AttrPath,
+        default: Option<Box<Expr>>,
+    },
+    /// `expr ? a.b`
+    HasAttr {
+        expr: Box<Expr>,
+        path: AttrPath,
+    },
+    UnaryOp {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    BinOp {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// Parentheses are kept so that spans and the original layout can be recovered.
+    Paren(Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StrPart {
+    Literal(String),
+    Interpolation(Expr),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    /// `-`
+    Negate,
+    /// `!`
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    /// `->`
+    Implication,
+    /// `||`
+    Or,
+    /// `&&`
+    And,
+    /// `==`
+    Equal,
+    /// `!=`
+    NotEqual,
+    /// `<`
+    Less,
+    /// `<=`
+    LessOrEqual,
+    /// `>`
+    Greater,
+    /// `>=`
+    GreaterOrEqual,
+    /// `//`
+    Update,
+    /// `+`
+    Add,
+    /// `-`
+    Subtract,
+    /// `*`
+    Multiply,
+    /// `/`
+    Divide,
+    /// `++`
+    Concat,
+}
+
+/// The bindings of an attrset or `let`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Binding {
+    /// `a.b.c = value;`
+    Attr {
+        path: AttrPath,
+        value: Expr,
+        span: Span,
+    },
+    /// `inherit a b;` or `inherit (from) a b;`
+    Inherit {
+        from: Option<Expr>,
+        attrs: Vec<Attr>,
+        span: Span,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttrPath {
+    pub attrs: Vec<Attr>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attr {
+    pub kind: AttrKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttrKind {
+    Ident(String),
+    /// A quoted attribute name, which may contain interpolations.
+    Str(Vec<StrPart>),
+    /// `${expr}`
+    Dynamic(Expr),
+}
+
+/// The parameter of a function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Param {
+    /// `x: ...`
+    Ident(String),
+    /// `{ a, b ? 1, ... } @ args: ...`
+    Pattern {
+        formals: Vec<Formal>,
+        ellipsis: bool,
+        bind: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Formal {
+    pub name: String,
+    pub default: Option<Expr>,
+    pub span: Span,
+}
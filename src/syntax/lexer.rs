@@ -0,0 +1,653 @@
+//! Splits nix source into tokens.
+//!
+//! Like nix's own flex lexer, this is stateful: strings, indented strings and paths are lexed in
+//! their own modes, and a stack of open braces tracks where an interpolation ends.
+//! https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l
+
+use super::{Span, SyntaxError};
+
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L109-L118
+pub(crate) const KEYWORDS: &[&str] = &[
+    "if", "then", "else", "assert", "with", "let", "in", "rec", "inherit", "or",
+];
+
+pub(crate) fn is_ident_start(c: char) -> bool {
+    matches!(c, 'a'..='z' | 'A'..='Z' | '_')
+}
+
+pub(crate) fn is_ident_char(c: char) -> bool {
+    matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '\'' | '-')
+}
+
+/// Whether `s` can be written as an attribute name or variable without quotes.
+pub(crate) fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(is_ident_start) && chars.all(is_ident_char) && !KEYWORDS.contains(&s)
+}
+
+pub(crate) fn is_path_char(c: char) -> bool {
+    matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' | '+')
+}
+
+fn is_uri_scheme_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')
+}
+
+fn is_uri_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "%/?:@&=+$,-_.!~*'".contains(c)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Uri(String),
+    SearchPath(String),
+
+    StrStart,
+    StrEnd,
+    IndStrStart,
+    IndStrEnd,
+    PathStart,
+    PathEnd,
+    /// Text inside a string or path, with escapes processed.
+    StrContent(String),
+    /// Text inside an indented string. Escaped text doesn't count as indentation.
+    IndStrContent {
+        text: String,
+        escaped: bool,
+    },
+    /// `${`, either in a string or path, or starting a dynamic attribute.
+    InterpStart,
+    /// The `}` ending an interpolation inside a string or path.
+    InterpEnd,
+
+    If,
+    Then,
+    Else,
+    Assert,
+    With,
+    Let,
+    In,
+    Rec,
+    Inherit,
+    OrKw,
+
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Semicolon,
+    Colon,
+    Comma,
+    Dot,
+    Ellipsis,
+    Assign,
+    At,
+    Question,
+    Not,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Concat,
+    Update,
+    Eq,
+    NotEq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Impl,
+
+    Eof,
+}
+
+impl TokenKind {
+    pub(crate) fn describe(&self) -> String {
+        let s = match self {
+            TokenKind::Ident(i) => return format!("`{}`", i),
+            TokenKind::Int(i) => return format!("`{}`", i),
+            TokenKind::Float(_) => "a float",
+            TokenKind::Uri(_) => "a URI",
+            TokenKind::SearchPath(_) => "a lookup path",
+            TokenKind::StrStart => "a string",
+            TokenKind::StrEnd => "`\"`",
+            TokenKind::IndStrStart => "an indented string",
+            TokenKind::IndStrEnd => "`''`",
+            TokenKind::PathStart => "a path",
+            TokenKind::PathEnd => "the end of a path",
+            TokenKind::StrContent(_) | TokenKind::IndStrContent { .. } => "string contents",
+            TokenKind::InterpStart => "`${`",
+            TokenKind::InterpEnd => "`}`",
+            TokenKind::If => "`if`",
+            TokenKind::Then => "`then`",
+            TokenKind::Else => "`else`",
+            TokenKind::Assert => "`assert`",
+            TokenKind::With => "`with`",
+            TokenKind::Let => "`let`",
+            TokenKind::In => "`in`",
+            TokenKind::Rec => "`rec`",
+            TokenKind::Inherit => "`inherit`",
+            TokenKind::OrKw => "`or`",
+            TokenKind::LBrace => "`{`",
+            TokenKind::RBrace => "`}`",
+            TokenKind::LBracket => "`[`",
+            TokenKind::RBracket => "`]`",
+            TokenKind::LParen => "`(`",
+            TokenKind::RParen => "`)`",
+            TokenKind::Semicolon => "`;`",
+            TokenKind::Colon => "`:`",
+            TokenKind::Comma => "`,`",
+            TokenKind::Dot => "`.`",
+            TokenKind::Ellipsis => "`...`",
+            TokenKind::Assign => "`=`",
+            TokenKind::At => "`@`",
+            TokenKind::Question => "`?`",
+            TokenKind::Not => "`!`",
+            TokenKind::Plus => "`+`",
+            TokenKind::Minus => "`-`",
+            TokenKind::Star => "`*`",
+            TokenKind::Slash => "`/`",
+            TokenKind::Concat => "`++`",
+            TokenKind::Update => "`//`",
+            TokenKind::Eq => "`==`",
+            TokenKind::NotEq => "`!=`",
+            TokenKind::Lt => "`<`",
+            TokenKind::Le => "`<=`",
+            TokenKind::Gt => "`>`",
+            TokenKind::Ge => "`>=`",
+            TokenKind::And => "`&&`",
+            TokenKind::Or => "`||`",
+            TokenKind::Impl => "`->`",
+            TokenKind::Eof => "end of input",
+        };
+        s.to_string()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Normal,
+    Str,
+    IndStr,
+    Path,
+}
+
+// What a `}` in normal mode closes.
+enum Brace {
+    Plain,
+    Interpolation(Mode),
+}
+
+pub(crate) fn tokenize(src: &str) -> Result<Vec<Token>, SyntaxError> {
+    let mut lexer = Lexer {
+        src,
+        pos: 0,
+        mode: Mode::Normal,
+        braces: Vec::new(),
+        tokens: Vec::new(),
+    };
+    lexer.run()?;
+    Ok(lexer.tokens)
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    mode: Mode,
+    braces: Vec<Brace>,
+    tokens: Vec<Token>,
+}
+
+impl<'a> Lexer<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.rest().chars().nth(1)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn push(&mut self, kind: TokenKind, start: usize) {
+        self.push_span(kind, start, self.pos);
+    }
+
+    fn push_span(&mut self, kind: TokenKind, start: usize, end: usize) {
+        self.tokens.push(Token {
+            kind,
+            span: Span::new(start, end),
+        });
+    }
+
+    fn error_at(&self, pos: usize, msg: impl Into<String>) -> SyntaxError {
+        SyntaxError {
+            msg: msg.into(),
+            span: Span::new(pos, pos),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), SyntaxError> {
+        loop {
+            match self.mode {
+                Mode::Normal => {
+                    self.skip_trivia()?;
+                    if self.pos == self.src.len() {
+                        // Unclosed braces are reported by the parser, which knows what was open.
+                        self.push(TokenKind::Eof, self.pos);
+                        return Ok(());
+                    }
+                    self.lex_normal()?;
+                }
+                Mode::Str => self.lex_string()?,
+                Mode::IndStr => self.lex_indented_string()?,
+                Mode::Path => self.lex_path()?,
+            }
+        }
+    }
+
+    fn skip_trivia(&mut self) -> Result<(), SyntaxError> {
+        loop {
+            match self.peek() {
+                // Nix only takes ASCII whitespace, so a non-breaking space is an error here too.
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.bump();
+                }
+                // Like nix, a line comment ends at a carriage return too.
+                Some('#') => {
+                    let end = self.rest().find(['\n', '\r']).unwrap_or(self.rest().len());
+                    self.pos += end;
+                }
+                Some('/') if self.peek_second() == Some('*') => {
+                    let start = self.pos;
+                    match self.rest()[2..].find("*/") {
+                        Some(end) => self.pos += end + 4,
+                        None => return Err(self.error_at(start, "unterminated comment")),
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Whether a path literal starts here. A path is some path characters (or `~`) followed by a
+    // slash and more path characters or an interpolation.
+    fn path_start(&self) -> bool {
+        let rest = self.rest();
+        let after_prefix = match rest.strip_prefix('~') {
+            Some(after) => after,
+            None => rest.trim_start_matches(is_path_char),
+        };
+        let mut chars = after_prefix.chars();
+        chars.next() == Some('/')
+            && (chars.next().is_some_and(is_path_char) || after_prefix[1..].starts_with("${"))
+    }
+
+    // `scheme:rest` per the URI rule in nix's lexer.
+    fn uri_len(&self) -> Option<usize> {
+        let rest = self.rest();
+        if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let scheme = rest.find(|c| !is_uri_scheme_char(c)).unwrap_or(rest.len());
+        let after = rest[scheme..].strip_prefix(':')?;
+        let body = after.find(|c| !is_uri_char(c)).unwrap_or(after.len());
+        if body == 0 {
+            None
+        } else {
+            Some(scheme + 1 + body)
+        }
+    }
+
+    fn lex_normal(&mut self) -> Result<(), SyntaxError> {
+        let start = self.pos;
+        let c = self.peek().unwrap();
+
+        if self.path_start() {
+            self.push(TokenKind::PathStart, start);
+            self.mode = Mode::Path;
+            return Ok(());
+        }
+        if let Some(len) = self.uri_len() {
+            self.pos += len;
+            let uri = self.src[start..self.pos].to_string();
+            self.push(TokenKind::Uri(uri), start);
+            return Ok(());
+        }
+        if is_ident_start(c) {
+            while self.peek().is_some_and(is_ident_char) {
+                self.bump();
+            }
+            let kind = match &self.src[start..self.pos] {
+                "if" => TokenKind::If,
+                "then" => TokenKind::Then,
+                "else" => TokenKind::Else,
+                "assert" => TokenKind::Assert,
+                "with" => TokenKind::With,
+                "let" => TokenKind::Let,
+                "in" => TokenKind::In,
+                "rec" => TokenKind::Rec,
+                "inherit" => TokenKind::Inherit,
+                "or" => TokenKind::OrKw,
+                ident => TokenKind::Ident(ident.to_string()),
+            };
+            self.push(kind, start);
+            return Ok(());
+        }
+        if c.is_ascii_digit()
+            || (c == '.' && self.peek_second().is_some_and(|c| c.is_ascii_digit()))
+        {
+            return self.lex_number();
+        }
+        if c == '<' {
+            let rest = &self.rest()[1..];
+            let len = rest
+                .find(|c: char| !(is_path_char(c) || c == '/'))
+                .unwrap_or(rest.len());
+            if len > 0 && rest[len..].starts_with('>') && !rest.starts_with('/') {
+                let path = rest[..len].to_string();
+                self.pos += len + 2;
+                self.push(TokenKind::SearchPath(path), start);
+                return Ok(());
+            }
+        }
+        if self.eat("\"") {
+            self.push(TokenKind::StrStart, start);
+            self.mode = Mode::Str;
+            return Ok(());
+        }
+        if self.eat("''") {
+            // Spaces and a newline right after the opening quotes are dropped.
+            let first_line = self.rest().find(|c| c != ' ').unwrap_or(0);
+            if self.rest()[first_line..].starts_with('\n') {
+                self.pos += first_line + 1;
+            }
+            self.push(TokenKind::IndStrStart, start);
+            self.mode = Mode::IndStr;
+            return Ok(());
+        }
+        if self.eat("${") {
+            self.braces.push(Brace::Plain);
+            self.push(TokenKind::InterpStart, start);
+            return Ok(());
+        }
+        if self.eat("{") {
+            self.braces.push(Brace::Plain);
+            self.push(TokenKind::LBrace, start);
+            return Ok(());
+        }
+        if self.eat("}") {
+            match self.braces.pop() {
+                Some(Brace::Interpolation(mode)) => {
+                    self.push(TokenKind::InterpEnd, start);
+                    self.mode = mode;
+                }
+                _ => self.push(TokenKind::RBrace, start),
+            }
+            return Ok(());
+        }
+
+        const PUNCTUATION: &[(&str, TokenKind)] = &[
+            ("...", TokenKind::Ellipsis),
+            ("==", TokenKind::Eq),
+            ("!=", TokenKind::NotEq),
+            ("<=", TokenKind::Le),
+            (">=", TokenKind::Ge),
+            ("&&", TokenKind::And),
+            ("||", TokenKind::Or),
+            ("->", TokenKind::Impl),
+            ("//", TokenKind::Update),
+            ("++", TokenKind::Concat),
+            ("[", TokenKind::LBracket),
+            ("]", TokenKind::RBracket),
+            ("(", TokenKind::LParen),
+            (")", TokenKind::RParen),
+            (";", TokenKind::Semicolon),
+            (":", TokenKind::Colon),
+            (",", TokenKind::Comma),
+            (".", TokenKind::Dot),
+            ("=", TokenKind::Assign),
+            ("@", TokenKind::At),
+            ("?", TokenKind::Question),
+            ("!", TokenKind::Not),
+            ("+", TokenKind::Plus),
+            ("-", TokenKind::Minus),
+            ("*", TokenKind::Star),
+            ("/", TokenKind::Slash),
+            ("<", TokenKind::Lt),
+            (">", TokenKind::Gt),
+        ];
+        for (text, kind) in PUNCTUATION {
+            if self.eat(text) {
+                self.push(kind.clone(), start);
+                return Ok(());
+            }
+        }
+        Err(self.error_at(start, format!("unexpected `{}`", c)))
+    }
+
+    // https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L98-L99
+    fn lex_number(&mut self) -> Result<(), SyntaxError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+        let int_part = &self.src[start..self.pos];
+        // Floats are `[1-9][0-9]*\.[0-9]*` or `0?\.[0-9]+`.
+        let is_float = self.peek() == Some('.')
+            && if int_part.is_empty() || int_part == "0" {
+                self.peek_second().is_some_and(|c| c.is_ascii_digit())
+            } else {
+                !int_part.starts_with('0')
+            };
+        if is_float {
+            self.bump();
+            while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                self.bump();
+            }
+            if matches!(self.peek(), Some('e' | 'E')) {
+                let exponent_start = self.pos;
+                self.bump();
+                if matches!(self.peek(), Some('+' | '-')) {
+                    self.bump();
+                }
+                if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.pos = exponent_start;
+                }
+                while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.bump();
+                }
+            }
+        }
+        let text = &self.src[start..self.pos];
+        let kind = if is_float {
+            TokenKind::Float(
+                text.parse()
+                    .map_err(|_| self.error_at(start, format!("invalid float `{}`", text)))?,
+            )
+        } else {
+            TokenKind::Int(
+                text.parse()
+                    .map_err(|_| self.error_at(start, format!("invalid integer `{}`", text)))?,
+            )
+        };
+        self.push(kind, start);
+        Ok(())
+    }
+
+    fn start_interpolation(&mut self, from: Mode) {
+        let start = self.pos;
+        self.pos += 2;
+        self.braces.push(Brace::Interpolation(from));
+        self.push(TokenKind::InterpStart, start);
+        self.mode = Mode::Normal;
+    }
+
+    // https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L150-L170
+    fn lex_string(&mut self) -> Result<(), SyntaxError> {
+        let start = self.pos;
+        let mut s = String::new();
+        loop {
+            if self.rest().starts_with("${") {
+                if !s.is_empty() {
+                    self.push(TokenKind::StrContent(s), start);
+                }
+                self.start_interpolation(Mode::Str);
+                return Ok(());
+            }
+            let c_start = self.pos;
+            match self.bump() {
+                None => return Err(self.error_at(start, "unterminated string")),
+                Some('"') => {
+                    if !s.is_empty() {
+                        self.push_span(TokenKind::StrContent(s), start, c_start);
+                    }
+                    self.push(TokenKind::StrEnd, c_start);
+                    self.mode = Mode::Normal;
+                    return Ok(());
+                }
+                Some('\\') => match self.bump() {
+                    None => return Err(self.error_at(start, "unterminated string")),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some(c) => s.push(c),
+                },
+                // `$${` is a literal `$${`
+                Some('$') if self.eat("$") => s.push_str("$$"),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    // https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L172-L202
+    fn lex_indented_string(&mut self) -> Result<(), SyntaxError> {
+        let start = self.pos;
+        let mut text = String::new();
+        loop {
+            let piece_start = self.pos;
+            let escaped = if self.rest().starts_with("''") {
+                self.pos += 2;
+                if self.eat("'") {
+                    Some("''".to_string())
+                } else if self.eat("$") {
+                    Some("$".to_string())
+                } else if self.eat("\\") {
+                    match self.bump() {
+                        None => return Err(self.error_at(start, "unterminated string")),
+                        Some('n') => Some("\n".to_string()),
+                        Some('r') => Some("\r".to_string()),
+                        Some('t') => Some("\t".to_string()),
+                        Some(c) => Some(c.to_string()),
+                    }
+                } else {
+                    self.flush_indented(&mut text, start, piece_start);
+                    self.push(TokenKind::IndStrEnd, piece_start);
+                    self.mode = Mode::Normal;
+                    return Ok(());
+                }
+            } else {
+                None
+            };
+            if let Some(escaped) = escaped {
+                self.flush_indented(&mut text, start, piece_start);
+                self.push(
+                    TokenKind::IndStrContent {
+                        text: escaped,
+                        escaped: true,
+                    },
+                    piece_start,
+                );
+                return Ok(());
+            }
+            if self.rest().starts_with("${") {
+                self.flush_indented(&mut text, start, self.pos);
+                self.start_interpolation(Mode::IndStr);
+                return Ok(());
+            }
+            if self.eat("$$") {
+                text.push_str("$$");
+                continue;
+            }
+            match self.bump() {
+                None => return Err(self.error_at(start, "unterminated string")),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    fn flush_indented(&mut self, text: &mut String, start: usize, end: usize) {
+        if !text.is_empty() {
+            self.push_span(
+                TokenKind::IndStrContent {
+                    text: std::mem::take(text),
+                    escaped: false,
+                },
+                start,
+                end,
+            );
+        }
+    }
+
+    // https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l#L107-L112
+    fn lex_path(&mut self) -> Result<(), SyntaxError> {
+        let start = self.pos;
+        if self.tokens.last().map(|t| &t.kind) == Some(&TokenKind::PathStart) {
+            self.eat("~");
+        }
+        while let Some(c) = self.peek() {
+            let next_is_segment = || {
+                self.peek_second().is_some_and(is_path_char) || self.rest()[1..].starts_with("${")
+            };
+            if is_path_char(c) || (c == '/' && next_is_segment()) {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        if self.pos > start {
+            let text = self.src[start..self.pos].to_string();
+            self.push(TokenKind::StrContent(text), start);
+        }
+        if self.rest().starts_with("${") {
+            self.start_interpolation(Mode::Path);
+            return Ok(());
+        }
+        if self.peek() == Some('/') {
+            return Err(self.error_at(self.pos, "path has a trailing slash"));
+        }
+        self.push(TokenKind::PathEnd, self.pos);
+        self.mode = Mode::Normal;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! Parses nix source into a syntax tree.
+//!
+//! This understands the whole nix language, not just the data subset [`crate::de`] reads:
+//! `let`, `with`, `rec`, functions, `inherit`, string interpolation, `or` and all the operators,
+//! following the precedence rules of nix itself. Every node keeps the byte range of the source
+//! it came from, so callers can point at problems or splice edits into an existing file.
+//!
+//! Parentheses are kept as [`ExprKind::Paren`] nodes; comments and whitespace are dropped.
+//!
+//! ```
+//! use serde_nix::syntax::{self, ExprKind};
+//!
+//! let src = "{ pkgs, ... }: { environment.systemPackages = [ pkgs.hello ]; }";
+//! let expr = syntax::parse(src).unwrap();
+//! match expr.kind {
+//!     ExprKind::Lambda { body, .. } => assert_eq!(&src[body.span.start..body.span.end], "{ environment.systemPackages = [ pkgs.hello ]; }"),
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+mod ast;
+mod lexer;
+mod parser;
+
+pub use self::ast::*;
+pub(crate) use self::lexer::{is_ident, is_path_char};
+
+use crate::error::Error;
+
+/// A byte range in the parsed source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+// Syntax errors keep the byte offset until they leave this module, where it becomes a line and
+// column.
+pub(crate) struct SyntaxError {
+    msg: String,
+    span: Span,
+}
+
+/// Parses a single nix expression.
+///
+/// Fails with [`Error::Syntax`] if `src` is not well-formed nix.
+pub fn parse(src: &str) -> Result<Expr, Error> {
+    lexer::tokenize(src)
+        .and_then(|tokens| parser::Parser::new(tokens).parse_document())
+        .map_err(|e| {
+            let (line, column) = line_column(src, e.span.start);
+            Error::Syntax {
+                msg: e.msg,
+                line,
+                column,
+            }
+        })
+}
+
+/// The 1-based line and column of the byte offset `pos` in `src`.
+pub(crate) fn line_column(src: &str, pos: usize) -> (usize, usize) {
+    let before = &src[..pos];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap().chars().count() + 1;
+    (line, column)
+}
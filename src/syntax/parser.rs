@@ -0,0 +1,753 @@
+//! A recursive descent parser following the grammar and operator precedences of nix's own.
+//! https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/parser.y
+
+use super::ast::*;
+use super::lexer::{Token, TokenKind};
+use super::{Span, SyntaxError};
+
+type Result<T> = std::result::Result<T, SyntaxError>;
+
+pub(crate) struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+// How tightly a binary operator binds, and which way it associates.
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/parser.y#L336-L348
+#[derive(Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+    None,
+}
+
+const NOT_PRECEDENCE: u8 = 7;
+const NEGATE_PRECEDENCE: u8 = 12;
+
+fn binary_op(kind: &TokenKind) -> Option<(BinOp, u8, Assoc)> {
+    Some(match kind {
+        TokenKind::Impl => (BinOp::Implication, 1, Assoc::Right),
+        TokenKind::Or => (BinOp::Or, 2, Assoc::Left),
+        TokenKind::And => (BinOp::And, 3, Assoc::Left),
+        TokenKind::Eq => (BinOp::Equal, 4, Assoc::None),
+        TokenKind::NotEq => (BinOp::NotEqual, 4, Assoc::None),
+        TokenKind::Lt => (BinOp::Less, 5, Assoc::None),
+        TokenKind::Le => (BinOp::LessOrEqual, 5, Assoc::None),
+        TokenKind::Gt => (BinOp::Greater, 5, Assoc::None),
+        TokenKind::Ge => (BinOp::GreaterOrEqual, 5, Assoc::None),
+        TokenKind::Update => (BinOp::Update, 6, Assoc::Right),
+        TokenKind::Plus => (BinOp::Add, 8, Assoc::Left),
+        TokenKind::Minus => (BinOp::Subtract, 8, Assoc::Left),
+        TokenKind::Star => (BinOp::Multiply, 9, Assoc::Left),
+        TokenKind::Slash => (BinOp::Divide, 9, Assoc::Left),
+        TokenKind::Concat => (BinOp::Concat, 10, Assoc::Right),
+        _ => return None,
+    })
+}
+
+// `?` sits between `++` and unary minus.
+const HAS_ATTR_PRECEDENCE: u8 = 11;
+
+// An indented string piece before indentation stripping.
+enum IndentedPart {
+    Text { text: String, escaped: bool },
+    Interpolation(Expr),
+}
+
+impl Parser {
+    pub(crate) fn new(tokens: Vec<Token>) -> Self {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> &TokenKind {
+        &self.tokens[self.pos].kind
+    }
+
+    fn peek_nth(&self, n: usize) -> &TokenKind {
+        let i = (self.pos + n).min(self.tokens.len() - 1);
+        &self.tokens[i].kind
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.pos].span
+    }
+
+    // The end of the last token consumed.
+    fn last_end(&self) -> usize {
+        match self.pos {
+            0 => 0,
+            pos => self.tokens[pos - 1].span.end,
+        }
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.peek() == kind {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> SyntaxError {
+        SyntaxError {
+            msg: format!("expected {}, found {}", expected, self.peek().describe()),
+            span: self.span(),
+        }
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> Result<Span> {
+        if self.peek() == kind {
+            Ok(self.bump().span)
+        } else {
+            Err(self.unexpected(&kind.describe()))
+        }
+    }
+
+    fn expr(&self, kind: ExprKind, start: usize) -> Expr {
+        Expr {
+            kind,
+            span: Span::new(start, self.last_end()),
+        }
+    }
+
+    pub(crate) fn parse_document(&mut self) -> Result<Expr> {
+        let expr = self.parse_expr()?;
+        if self.peek() != &TokenKind::Eof {
+            return Err(self.unexpected("end of input"));
+        }
+        Ok(expr)
+    }
+
+    pub(crate) fn parse_expr(&mut self) -> Result<Expr> {
+        let start = self.span().start;
+        match self.peek().clone() {
+            TokenKind::Ident(name) if self.peek_nth(1) == &TokenKind::Colon => {
+                self.bump();
+                self.bump();
+                let body = self.parse_expr()?;
+                Ok(self.expr(
+                    ExprKind::Lambda {
+                        param: Param::Ident(name),
+                        body: Box::new(body),
+                    },
+                    start,
+                ))
+            }
+            TokenKind::Ident(name) if self.peek_nth(1) == &TokenKind::At => {
+                self.bump();
+                self.bump();
+                self.parse_pattern_lambda(Some(name), start)
+            }
+            TokenKind::LBrace if self.at_formals() => self.parse_pattern_lambda(None, start),
+            TokenKind::Assert => {
+                self.bump();
+                let cond = self.parse_expr()?;
+                self.expect(&TokenKind::Semicolon)?;
+                let body = self.parse_expr()?;
+                Ok(self.expr(
+                    ExprKind::Assert {
+                        cond: Box::new(cond),
+                        body: Box::new(body),
+                    },
+                    start,
+                ))
+            }
+            TokenKind::With => {
+                self.bump();
+                let env = self.parse_expr()?;
+                self.expect(&TokenKind::Semicolon)?;
+                let body = self.parse_expr()?;
+                Ok(self.expr(
+                    ExprKind::With {
+                        env: Box::new(env),
+                        body: Box::new(body),
+                    },
+                    start,
+                ))
+            }
+            TokenKind::Let if self.peek_nth(1) != &TokenKind::LBrace => {
+                self.bump();
+                let bindings = self.parse_bindings(&TokenKind::In)?;
+                self.expect(&TokenKind::In)?;
+                let body = self.parse_expr()?;
+                Ok(self.expr(
+                    ExprKind::Let {
+                        bindings,
+                        body: Box::new(body),
+                    },
+                    start,
+                ))
+            }
+            TokenKind::If => {
+                self.bump();
+                let cond = self.parse_expr()?;
+                self.expect(&TokenKind::Then)?;
+                let then = self.parse_expr()?;
+                self.expect(&TokenKind::Else)?;
+                let else_ = self.parse_expr()?;
+                Ok(self.expr(
+                    ExprKind::If {
+                        cond: Box::new(cond),
+                        then: Box::new(then),
+                        else_: Box::new(else_),
+                    },
+                    start,
+                ))
+            }
+            _ => self.parse_op(0),
+        }
+    }
+
+    // Tells `{ a, b }: ...` apart from `{ a = b; }` by looking at the first few tokens.
+    fn at_formals(&self) -> bool {
+        matches!(
+            (self.peek_nth(1), self.peek_nth(2), self.peek_nth(3)),
+            (TokenKind::RBrace, TokenKind::Colon | TokenKind::At, _)
+                | (TokenKind::Ellipsis, _, _)
+                | (
+                    TokenKind::Ident(_),
+                    TokenKind::Comma | TokenKind::Question,
+                    _
+                )
+                | (
+                    TokenKind::Ident(_),
+                    TokenKind::RBrace,
+                    TokenKind::Colon | TokenKind::At
+                )
+        )
+    }
+
+    fn parse_pattern_lambda(&mut self, mut bind: Option<String>, start: usize) -> Result<Expr> {
+        self.expect(&TokenKind::LBrace)?;
+        let mut formals = Vec::new();
+        let mut ellipsis = false;
+        loop {
+            match self.peek().clone() {
+                TokenKind::RBrace => break,
+                TokenKind::Ellipsis => {
+                    self.bump();
+                    ellipsis = true;
+                    break;
+                }
+                TokenKind::Ident(name) => {
+                    let formal_start = self.bump().span.start;
+                    let default = if self.eat(&TokenKind::Question) {
+                        Some(self.parse_expr()?)
+                    } else {
+                        None
+                    };
+                    formals.push(Formal {
+                        name,
+                        default,
+                        span: Span::new(formal_start, self.last_end()),
+                    });
+                    if !self.eat(&TokenKind::Comma) {
+                        break;
+                    }
+                }
+                _ => return Err(self.unexpected("a function argument")),
+            }
+        }
+        self.expect(&TokenKind::RBrace)?;
+        if bind.is_none() && self.eat(&TokenKind::At) {
+            match self.peek().clone() {
+                TokenKind::Ident(name) => {
+                    self.bump();
+                    bind = Some(name);
+                }
+                _ => return Err(self.unexpected("an identifier")),
+            }
+        }
+        self.expect(&TokenKind::Colon)?;
+        let body = self.parse_expr()?;
+        Ok(self.expr(
+            ExprKind::Lambda {
+                param: Param::Pattern {
+                    formals,
+                    ellipsis,
+                    bind,
+                },
+                body: Box::new(body),
+            },
+            start,
+        ))
+    }
+
+    // Operators, by precedence climbing.
+    fn parse_op(&mut self, min_precedence: u8) -> Result<Expr> {
+        let start = self.span().start;
+        let mut lhs = match self.peek() {
+            TokenKind::Not => {
+                self.bump();
+                let expr = self.parse_op(NOT_PRECEDENCE)?;
+                self.expr(
+                    ExprKind::UnaryOp {
+                        op: UnaryOp::Not,
+                        expr: Box::new(expr),
+                    },
+                    start,
+                )
+            }
+            TokenKind::Minus => {
+                self.bump();
+                let expr = self.parse_op(NEGATE_PRECEDENCE)?;
+                self.expr(
+                    ExprKind::UnaryOp {
+                        op: UnaryOp::Negate,
+                        expr: Box::new(expr),
+                    },
+                    start,
+                )
+            }
+            _ => self.parse_apply()?,
+        };
+
+        let mut last_non_assoc = None;
+        loop {
+            if self.peek() == &TokenKind::Question {
+                if HAS_ATTR_PRECEDENCE < min_precedence {
+                    break;
+                }
+                if last_non_assoc == Some(HAS_ATTR_PRECEDENCE) {
+                    return Err(self.unexpected("an operator that can be chained"));
+                }
+                self.bump();
+                let path = self.parse_attr_path()?;
+                lhs = self.expr(
+                    ExprKind::HasAttr {
+                        expr: Box::new(lhs),
+                        path,
+                    },
+                    start,
+                );
+                last_non_assoc = Some(HAS_ATTR_PRECEDENCE);
+                continue;
+            }
+            let (op, precedence, assoc) = match binary_op(self.peek()) {
+                Some(op) if op.1 >= min_precedence => op,
+                _ => break,
+            };
+            if last_non_assoc == Some(precedence) {
+                return Err(self.unexpected("an operator that can be chained"));
+            }
+            self.bump();
+            let rhs = self.parse_op(match assoc {
+                Assoc::Right => precedence,
+                Assoc::Left | Assoc::None => precedence + 1,
+            })?;
+            lhs = self.expr(
+                ExprKind::BinOp {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                start,
+            );
+            last_non_assoc = if assoc == Assoc::None {
+                Some(precedence)
+            } else {
+                None
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_apply(&mut self) -> Result<Expr> {
+        let start = self.span().start;
+        let mut func = self.parse_select()?;
+        while self.at_simple_expr() {
+            let arg = self.parse_select()?;
+            func = self.expr(
+                ExprKind::Apply {
+                    func: Box::new(func),
+                    arg: Box::new(arg),
+                },
+                start,
+            );
+        }
+        Ok(func)
+    }
+
+    fn at_simple_expr(&self) -> bool {
+        match self.peek() {
+            TokenKind::Ident(_)
+            | TokenKind::Int(_)
+            | TokenKind::Float(_)
+            | TokenKind::Uri(_)
+            | TokenKind::SearchPath(_)
+            | TokenKind::StrStart
+            | TokenKind::IndStrStart
+            | TokenKind::PathStart
+            | TokenKind::LParen
+            | TokenKind::LBracket
+            | TokenKind::LBrace
+            | TokenKind::Rec => true,
+            TokenKind::Let => self.peek_nth(1) == &TokenKind::LBrace,
+            _ => false,
+        }
+    }
+
+    // A simple expression optionally followed by `.attr.path` and `or default`.
+    fn parse_select(&mut self) -> Result<Expr> {
+        let start = self.span().start;
+        let expr = self.parse_simple()?;
+        if !self.eat(&TokenKind::Dot) {
+            return Ok(expr);
+        }
+        let path = self.parse_attr_path()?;
+        let default = if self.eat(&TokenKind::OrKw) {
+            Some(Box::new(self.parse_select()?))
+        } else {
+            None
+        };
+        Ok(self.expr(
+            ExprKind::Select {
+                expr: Box::new(expr),
+                path,
+                default,
+            },
+            start,
+        ))
+    }
+
+    fn parse_simple(&mut self) -> Result<Expr> {
+        let start = self.span().start;
+        let kind = match self.peek().clone() {
+            TokenKind::Ident(name) => {
+                self.bump();
+                ExprKind::Ident(name)
+            }
+            TokenKind::Int(i) => {
+                self.bump();
+                ExprKind::Int(i)
+            }
+            TokenKind::Float(f) => {
+                self.bump();
+                ExprKind::Float(f)
+            }
+            TokenKind::Uri(uri) => {
+                self.bump();
+                ExprKind::Uri(uri)
+            }
+            TokenKind::SearchPath(path) => {
+                self.bump();
+                ExprKind::SearchPath(path)
+            }
+            TokenKind::StrStart => ExprKind::Str(self.parse_string()?),
+            TokenKind::IndStrStart => ExprKind::IndStr(self.parse_indented_string()?),
+            TokenKind::PathStart => {
+                self.bump();
+                let parts = self.parse_string_parts(&TokenKind::PathEnd)?;
+                self.expect(&TokenKind::PathEnd)?;
+                ExprKind::Path(parts)
+            }
+            TokenKind::LParen => {
+                self.bump();
+                let expr = self.parse_expr()?;
+                self.expect(&TokenKind::RParen)?;
+                ExprKind::Paren(Box::new(expr))
+            }
+            TokenKind::LBracket => {
+                self.bump();
+                let mut list = Vec::new();
+                while !self.eat(&TokenKind::RBracket) {
+                    if !self.at_simple_expr() {
+                        return Err(self.unexpected("a list element or `]`"));
+                    }
+                    list.push(self.parse_select()?);
+                }
+                ExprKind::List(list)
+            }
+            TokenKind::LBrace => {
+                self.bump();
+                let bindings = self.parse_bindings(&TokenKind::RBrace)?;
+                self.expect(&TokenKind::RBrace)?;
+                ExprKind::Attrs {
+                    rec: false,
+                    bindings,
+                }
+            }
+            TokenKind::Rec | TokenKind::Let if self.peek_nth(1) == &TokenKind::LBrace => {
+                // `let { body = ...; }` is an old spelling of `rec { ... }.body`, which nix still
+                // accepts. It's kept as a recursive attrset selecting `body`.
+                let legacy_let = self.peek() == &TokenKind::Let;
+                self.bump();
+                self.bump();
+                let bindings = self.parse_bindings(&TokenKind::RBrace)?;
+                self.expect(&TokenKind::RBrace)?;
+                let attrs = ExprKind::Attrs {
+                    rec: true,
+                    bindings,
+                };
+                if !legacy_let {
+                    attrs
+                } else {
+                    let span = Span::new(start, self.last_end());
+                    ExprKind::Select {
+                        expr: Box::new(Expr { kind: attrs, span }),
+                        path: AttrPath {
+                            attrs: vec![Attr {
+                                kind: AttrKind::Ident("body".to_string()),
+                                span,
+                            }],
+                            span,
+                        },
+                        default: None,
+                    }
+                }
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(self.expr(kind, start))
+    }
+
+    fn parse_bindings(&mut self, end: &TokenKind) -> Result<Vec<Binding>> {
+        let mut bindings = Vec::new();
+        while self.peek() != end {
+            let start = self.span().start;
+            if self.eat(&TokenKind::Inherit) {
+                let from = if self.eat(&TokenKind::LParen) {
+                    let from = self.parse_expr()?;
+                    self.expect(&TokenKind::RParen)?;
+                    Some(from)
+                } else {
+                    None
+                };
+                let mut attrs = Vec::new();
+                while self.peek() != &TokenKind::Semicolon {
+                    attrs.push(self.parse_attr()?);
+                }
+                self.bump();
+                bindings.push(Binding::Inherit {
+                    from,
+                    attrs,
+                    span: Span::new(start, self.last_end()),
+                });
+            } else {
+                let path = self.parse_attr_path()?;
+                self.expect(&TokenKind::Assign)?;
+                let value = self.parse_expr()?;
+                self.expect(&TokenKind::Semicolon)?;
+                bindings.push(Binding::Attr {
+                    path,
+                    value,
+                    span: Span::new(start, self.last_end()),
+                });
+            }
+        }
+        Ok(bindings)
+    }
+
+    fn parse_attr_path(&mut self) -> Result<AttrPath> {
+        let start = self.span().start;
+        let mut attrs = vec![self.parse_attr()?];
+        while self.eat(&TokenKind::Dot) {
+            attrs.push(self.parse_attr()?);
+        }
+        Ok(AttrPath {
+            attrs,
+            span: Span::new(start, self.last_end()),
+        })
+    }
+
+    fn parse_attr(&mut self) -> Result<Attr> {
+        let start = self.span().start;
+        let kind = match self.peek().clone() {
+            TokenKind::Ident(name) => {
+                self.bump();
+                AttrKind::Ident(name)
+            }
+            TokenKind::OrKw => {
+                self.bump();
+                AttrKind::Ident("or".to_string())
+            }
+            TokenKind::StrStart => AttrKind::Str(self.parse_string()?),
+            TokenKind::InterpStart => {
+                self.bump();
+                let expr = self.parse_expr()?;
+                self.expect(&TokenKind::RBrace)?;
+                AttrKind::Dynamic(expr)
+            }
+            _ => return Err(self.unexpected("an attribute name")),
+        };
+        Ok(Attr {
+            kind,
+            span: Span::new(start, self.last_end()),
+        })
+    }
+
+    fn parse_string(&mut self) -> Result<Vec<StrPart>> {
+        self.expect(&TokenKind::StrStart)?;
+        let parts = self.parse_string_parts(&TokenKind::StrEnd)?;
+        self.expect(&TokenKind::StrEnd)?;
+        Ok(parts)
+    }
+
+    fn parse_string_parts(&mut self, end: &TokenKind) -> Result<Vec<StrPart>> {
+        let mut parts = Vec::new();
+        while self.peek() != end {
+            match self.peek().clone() {
+                TokenKind::StrContent(s) => {
+                    self.bump();
+                    parts.push(StrPart::Literal(s));
+                }
+                TokenKind::InterpStart => {
+                    parts.push(StrPart::Interpolation(self.parse_interpolation()?))
+                }
+                _ => return Err(self.unexpected(&end.describe())),
+            }
+        }
+        Ok(parts)
+    }
+
+    fn parse_interpolation(&mut self) -> Result<Expr> {
+        self.expect(&TokenKind::InterpStart)?;
+        let expr = self.parse_expr()?;
+        self.expect(&TokenKind::InterpEnd)?;
+        Ok(expr)
+    }
+
+    fn parse_indented_string(&mut self) -> Result<Vec<StrPart>> {
+        self.expect(&TokenKind::IndStrStart)?;
+        let mut parts = Vec::new();
+        loop {
+            match self.peek().clone() {
+                TokenKind::IndStrEnd => {
+                    self.bump();
+                    return Ok(strip_indentation(parts));
+                }
+                TokenKind::IndStrContent { text, escaped } => {
+                    self.bump();
+                    parts.push(IndentedPart::Text { text, escaped });
+                }
+                TokenKind::InterpStart => {
+                    parts.push(IndentedPart::Interpolation(self.parse_interpolation()?))
+                }
+                _ => return Err(self.unexpected("`''`")),
+            }
+        }
+    }
+}
+
+// Mirrors stripIndentation in nix's parser: remove the smallest indentation of any line from all
+// lines, then drop a final line made only of spaces. Escapes and interpolations don't count as
+// indentation.
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/parser.y#L213-L296
+fn strip_indentation(parts: Vec<IndentedPart>) -> Vec<StrPart> {
+    let mut at_start_of_line = true;
+    let mut min_indent = usize::MAX;
+    let mut cur_indent = 0;
+    for part in &parts {
+        let text = match part {
+            IndentedPart::Text {
+                text,
+                escaped: false,
+            } => text,
+            _ => {
+                if at_start_of_line {
+                    at_start_of_line = false;
+                    min_indent = min_indent.min(cur_indent);
+                }
+                continue;
+            }
+        };
+        for c in text.chars() {
+            if at_start_of_line {
+                match c {
+                    ' ' => cur_indent += 1,
+                    '\n' => cur_indent = 0,
+                    _ => {
+                        at_start_of_line = false;
+                        min_indent = min_indent.min(cur_indent);
+                    }
+                }
+            } else if c == '\n' {
+                at_start_of_line = true;
+                cur_indent = 0;
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut at_start_of_line = true;
+    let mut cur_dropped = 0;
+    let last = parts.len().saturating_sub(1);
+    for (i, part) in parts.into_iter().enumerate() {
+        let text = match part {
+            IndentedPart::Text {
+                text,
+                escaped: false,
+            } => text,
+            IndentedPart::Text {
+                text,
+                escaped: true,
+            } => {
+                at_start_of_line = false;
+                cur_dropped = 0;
+                push_literal(&mut result, &text);
+                continue;
+            }
+            IndentedPart::Interpolation(expr) => {
+                at_start_of_line = false;
+                cur_dropped = 0;
+                result.push(StrPart::Interpolation(expr));
+                continue;
+            }
+        };
+        let mut s = String::new();
+        for c in text.chars() {
+            if at_start_of_line {
+                match c {
+                    ' ' => {
+                        if cur_dropped >= min_indent {
+                            s.push(c);
+                        }
+                        cur_dropped += 1;
+                    }
+                    '\n' => {
+                        cur_dropped = 0;
+                        s.push(c);
+                    }
+                    _ => {
+                        at_start_of_line = false;
+                        cur_dropped = 0;
+                        s.push(c);
+                    }
+                }
+            } else {
+                s.push(c);
+                if c == '\n' {
+                    at_start_of_line = true;
+                }
+            }
+        }
+        if i == last {
+            if let Some(p) = s.rfind('\n') {
+                if s[p + 1..].chars().all(|c| c == ' ') {
+                    s.truncate(p + 1);
+                }
+            }
+        }
+        push_literal(&mut result, &s);
+    }
+    result
+}
+
+fn push_literal(parts: &mut Vec<StrPart>, s: &str) {
+    if s.is_empty() {
+        return;
+    }
+    match parts.last_mut() {
+        Some(StrPart::Literal(last)) => last.push_str(s),
+        _ => parts.push(StrPart::Literal(s.to_string())),
+    }
+}
@@ -0,0 +1,552 @@
+use std::fmt;
+
+use serde::de::{
+    self, Deserialize, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess,
+    SeqAccess, Unexpected, VariantAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+
+use super::{Map, Value};
+use crate::bytes;
+use crate::error::Error;
+use crate::ser::Bytes;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Converts a [`Value`] into any type implementing [`Deserialize`].
+pub fn from_value<T>(value: Value) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(value)
+}
+
+// Like `from_value`, for values with byte buffers in them that are encoded as `bytes` says.
+pub(crate) fn from_value_with_bytes<T>(value: Value, bytes: Bytes) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(Decoder::new(value, bytes))
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("any nix value")
+            }
+
+            fn visit_bool<E>(self, value: bool) -> std::result::Result<Value, E> {
+                Ok(Value::Bool(value))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> std::result::Result<Value, E> {
+                Ok(Value::Int(value))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> std::result::Result<Value, E>
+            where
+                E: de::Error,
+            {
+                if value > i64::MAX as u64 {
+                    return Err(E::invalid_value(
+                        Unexpected::Unsigned(value),
+                        &"a signed 64-bit integer",
+                    ));
+                }
+                Ok(Value::Int(value as i64))
+            }
+
+            fn visit_f64<E>(self, value: f64) -> std::result::Result<Value, E> {
+                Ok(Value::Float(value))
+            }
+
+            fn visit_str<E>(self, value: &str) -> std::result::Result<Value, E> {
+                Ok(Value::String(value.to_string()))
+            }
+
+            fn visit_string<E>(self, value: String) -> std::result::Result<Value, E> {
+                Ok(Value::String(value))
+            }
+
+            fn visit_none<E>(self) -> std::result::Result<Value, E> {
+                Ok(Value::Null)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> std::result::Result<Value, D::Error>
+            where
+                D: de::Deserializer<'de>,
+            {
+                Deserialize::deserialize(deserializer)
+            }
+
+            fn visit_unit<E>(self) -> std::result::Result<Value, E> {
+                Ok(Value::Null)
+            }
+
+            fn visit_seq<V>(self, mut visitor: V) -> std::result::Result<Value, V::Error>
+            where
+                V: SeqAccess<'de>,
+            {
+                let mut list = Vec::new();
+                while let Some(elem) = visitor.next_element()? {
+                    list.push(elem);
+                }
+                Ok(Value::List(list))
+            }
+
+            fn visit_map<V>(self, mut visitor: V) -> std::result::Result<Value, V::Error>
+            where
+                V: MapAccess<'de>,
+            {
+                let mut attrs = Map::new();
+                while let Some((key, value)) = visitor.next_entry()? {
+                    attrs.insert(key, value);
+                }
+                Ok(Value::Attrs(attrs))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Decoder::new(self, Bytes::List).deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Decoder::new(self, Bytes::List).deserialize_option(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Decoder::new(self, Bytes::List).deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Decoder::new(self, Bytes::List).deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+// A value being deserialized, along with how the byte buffers in it are encoded, which it can't
+// tell by itself.
+struct Decoder {
+    value: Value,
+    bytes: Bytes,
+}
+
+impl Decoder {
+    fn new(value: Value, bytes: Bytes) -> Self {
+        Decoder { value, bytes }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Decoder {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Int(i) => visitor.visit_i64(i),
+            Value::Float(f) => visitor.visit_f64(f),
+            Value::String(s) | Value::Path(s) => visitor.visit_string(s),
+            Value::List(list) => visitor.visit_seq(SeqDeserializer {
+                iter: list.into_iter(),
+                bytes: self.bytes,
+            }),
+            Value::Attrs(attrs) => visitor.visit_map(MapDeserializer {
+                iter: attrs.into_iter(),
+                value: None,
+                bytes: self.bytes,
+            }),
+            Value::Raw(_) => Err(de::Error::invalid_type(self.value.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Enums are written as their name for unit variants, or a single attribute `{ Name = ...; }`
+    // for everything else.
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let (variant, value) = match self.value {
+            Value::String(variant) => (variant, None),
+            Value::Attrs(attrs) => {
+                let mut iter = attrs.into_iter();
+                let (variant, value) = match (iter.next(), iter.next()) {
+                    (Some(entry), None) => entry,
+                    _ => {
+                        return Err(de::Error::invalid_value(
+                            Unexpected::Map,
+                            &"an attrset with a single attribute",
+                        ))
+                    }
+                };
+                (variant, Some(value))
+            }
+            other => {
+                return Err(de::Error::invalid_type(
+                    other.unexpected(),
+                    &"a string or an attrset",
+                ))
+            }
+        };
+        visitor.visit_enum(EnumDeserializer {
+            variant,
+            value,
+            bytes: self.bytes,
+        })
+    }
+
+    // Strings are decoded as the serializer encoded them. Lists of integers are read as they are
+    // whatever the encoding.
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let s = match self.value {
+            Value::String(s) if self.bytes != Bytes::List => s,
+            _ => return self.deserialize_any(visitor),
+        };
+        match bytes::decode(&s, self.bytes) {
+            Some(bytes) => visitor.visit_byte_buf(bytes),
+            None => {
+                let expected = match self.bytes {
+                    Bytes::Base64 => "a base64 string",
+                    Bytes::Hex => "a hex string",
+                    Bytes::Sri => "an SRI hash",
+                    Bytes::List | Bytes::Utf8 => "a string",
+                };
+                Err(de::Error::invalid_value(Unexpected::Str(&s), &expected))
+            }
+        }
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_bytes(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl Value {
+    fn unexpected(&self) -> Unexpected<'_> {
+        match self {
+            Value::Null => Unexpected::Unit,
+            Value::Bool(b) => Unexpected::Bool(*b),
+            Value::Int(i) => Unexpected::Signed(*i),
+            Value::Float(f) => Unexpected::Float(*f),
+            Value::String(s) | Value::Path(s) => Unexpected::Str(s),
+            Value::List(_) => Unexpected::Seq,
+            Value::Attrs(_) => Unexpected::Map,
+            Value::Raw(_) => Unexpected::Other("raw nix expression"),
+        }
+    }
+}
+
+struct SeqDeserializer {
+    iter: std::vec::IntoIter<Value>,
+    bytes: Bytes,
+}
+
+impl<'de> SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.iter.next() {
+            Some(value) => seed.deserialize(Decoder::new(value, self.bytes)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapDeserializer {
+    iter: indexmap::map::IntoIter<String, Value>,
+    value: Option<Value>,
+    bytes: Bytes,
+}
+
+impl<'de> MapAccess<'de> for MapDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(MapKeyDeserializer { key }).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some(value) => seed.deserialize(Decoder::new(value, self.bytes)),
+            None => Err(de::Error::custom("value is missing")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+// An attribute name, which is read back as a bool or number if that's what's asked for, the way
+// `MapKeys::Stringify` writes them.
+struct MapKeyDeserializer {
+    key: String,
+}
+
+macro_rules! deserialize_parsed_key {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: Visitor<'de>,
+            {
+                match self.key.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => self.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for MapKeyDeserializer {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_string(self.key)
+    }
+
+    deserialize_parsed_key! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Value::String(self.key).deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct EnumDeserializer {
+    variant: String,
+    value: Option<Value>,
+    bytes: Bytes,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, VariantDeserializer)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(Value::String(self.variant))?;
+        let variant_access = VariantDeserializer {
+            value: self.value,
+            bytes: self.bytes,
+        };
+        Ok((variant, variant_access))
+    }
+}
+
+struct VariantDeserializer {
+    value: Option<Value>,
+    bytes: Bytes,
+}
+
+impl<'de> VariantAccess<'de> for VariantDeserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.value {
+            None => Ok(()),
+            Some(value) => Deserialize::deserialize(value),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.value {
+            Some(value) => seed.deserialize(Decoder::new(value, self.bytes)),
+            None => Err(de::Error::invalid_type(
+                Unexpected::UnitVariant,
+                &"newtype variant",
+            )),
+        }
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Some(Value::List(list)) => visitor.visit_seq(SeqDeserializer {
+                iter: list.into_iter(),
+                bytes: self.bytes,
+            }),
+            Some(other) => Err(de::Error::invalid_type(
+                other.unexpected(),
+                &"tuple variant",
+            )),
+            None => Err(de::Error::invalid_type(
+                Unexpected::UnitVariant,
+                &"tuple variant",
+            )),
+        }
+    }
+
+    fn struct_variant<V>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Some(Value::Attrs(attrs)) => visitor.visit_map(MapDeserializer {
+                iter: attrs.into_iter(),
+                value: None,
+                bytes: self.bytes,
+            }),
+            Some(other) => Err(de::Error::invalid_type(
+                other.unexpected(),
+                &"struct variant",
+            )),
+            None => Err(de::Error::invalid_type(
+                Unexpected::UnitVariant,
+                &"struct variant",
+            )),
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! An in-memory representation of the nix values this crate can write.
+//!
+//! A [`Value`] can be built by hand or from any [`Serialize`] type with [`to_value`], inspected
+//! or modified, and then written out like any other value with [`crate::to_string`]. Its
+//! [`Display`](fmt::Display) implementation renders the nix expression directly, with `{:#}`
+//! selecting the pretty layout.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::raw::RawExpr;
+use crate::ser::{self as nix_ser, NonFiniteFloats, UnrepresentableChars};
+
+mod de;
+mod from;
+mod ser;
+
+pub use self::de::from_value;
+pub(crate) use self::de::from_value_with_bytes;
+pub(crate) use self::ser::key_to_string;
+pub use self::ser::{to_value, Serializer};
+
+/// The attributes of an attrset, in the order they will be written.
+pub type Map = IndexMap<String, Value>;
+
+/// Any nix value without functions in it. Anything beyond plain data can only be carried along
+/// as a [`RawExpr`].
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum Value {
+    #[default]
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    /// A path, such as `./hardware-configuration.nix` or `/etc/nixos`.
+    Path(String),
+    List(Vec<Value>),
+    Attrs(Map),
+    /// A nix expression written out verbatim.
+    Raw(RawExpr),
+}
+
+impl Value {
+    /// Looks up an attribute if this is an attrset.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_attrs()?.get(key)
+    }
+
+    /// Looks up an attribute for modification if this is an attrset.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.as_attrs_mut()?.get_mut(key)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_attrs(&self) -> Option<&Map> {
+        match self {
+            Value::Attrs(attrs) => Some(attrs),
+            _ => None,
+        }
+    }
+
+    pub fn as_attrs_mut(&mut self) -> Option<&mut Map> {
+        match self {
+            Value::Attrs(attrs) => Some(attrs),
+            _ => None,
+        }
+    }
+}
+
+// An attrset being built from its bindings the way nix does it: `a.b = 1; a.c = 2;` creates `a`
+// and extends it, and `a.b = 1; a = { c = 2; };` merges the two definitions of `a`. Two attrsets
+// that are both written out don't merge. Defining the same attribute twice is an error in nix, and
+// a panic here.
+#[doc(hidden)]
+#[derive(Default)]
+pub struct AttrsBuilder {
+    attrs: Map,
+    // The attrsets that were made up for dotted paths rather than written out, which are the only
+    // ones a later attrset can be merged into.
+    implicit: HashSet<Vec<String>>,
+}
+
+impl AttrsBuilder {
+    pub fn insert(&mut self, path: Vec<String>, value: Value) {
+        if let Err(defined) = self.try_insert(path, value) {
+            panic!("attribute '{}' already defined", defined);
+        }
+    }
+
+    // Like insert, but returns the path of the attribute that was already defined. The path has at
+    // least one key, as nix! and the parser never make an empty one.
+    pub(crate) fn try_insert(&mut self, path: Vec<String>, value: Value) -> Result<(), String> {
+        debug_assert!(!path.is_empty(), "empty attribute path");
+        let mut attrs = &mut self.attrs;
+        for depth in 0..path.len() - 1 {
+            let key = &path[depth];
+            if !attrs.contains_key(key) {
+                self.implicit.insert(path[..=depth].to_vec());
+            }
+            attrs = match attrs
+                .entry(key.clone())
+                .or_insert_with(|| Value::Attrs(Map::new()))
+            {
+                Value::Attrs(nested) => nested,
+                _ => return Err(path[..=depth].join(".")),
+            };
+        }
+        let key = &path[path.len() - 1];
+        match (attrs.get_mut(key), value) {
+            (Some(Value::Attrs(existing)), Value::Attrs(new)) if self.implicit.remove(&path) => {
+                for (k, v) in new {
+                    if existing.contains_key(&k) {
+                        return Err(format!("{}.{}", path.join("."), k));
+                    }
+                    existing.insert(k, v);
+                }
+                Ok(())
+            }
+            (Some(_), _) => Err(path.join(".")),
+            (None, value) => {
+                attrs.insert(key.clone(), value);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish(self) -> Map {
+        self.attrs
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::Float(f) => serializer.serialize_f64(*f),
+            Value::String(s) => serializer.serialize_str(s),
+            Value::Path(p) => serializer.serialize_newtype_struct(crate::ser::PATH_TOKEN, p),
+            Value::List(list) => list.serialize(serializer),
+            Value::Attrs(attrs) => {
+                use serde::ser::SerializeMap;
+                let mut map = serializer.serialize_map(Some(attrs.len()))?;
+                for (k, v) in attrs {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+            Value::Raw(raw) => raw.serialize(serializer),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Renders the nix expression, on a single line or, with `{:#}`, pretty printed. Unlike
+    /// [`crate::to_string`] this can't fail: NaN and the infinities are written as `null`, null
+    /// characters as U+FFFD and paths that nix has no expression for as strings.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rendered = match render(self, f.alternate()) {
+            Ok(rendered) => rendered,
+            Err(_) => render(&self.clone().with_paths_as_strings(), f.alternate())
+                .expect("only invalid paths make rendering fail"),
+        };
+        f.write_str(&rendered)
+    }
+}
+
+fn render(value: &Value, pretty: bool) -> Result<String, Error> {
+    let mut out = Vec::new();
+    if pretty {
+        value.serialize(&mut lenient(nix_ser::Serializer::pretty(&mut out)))?;
+    } else {
+        value.serialize(&mut lenient(nix_ser::Serializer::new(&mut out)))?;
+    }
+    Ok(String::from_utf8(out).expect("nix output is UTF-8"))
+}
+
+fn lenient<W, F>(ser: nix_ser::Serializer<W, F>) -> nix_ser::Serializer<W, F>
+where
+    W: std::io::Write,
+    F: nix_ser::Formatter,
+{
+    ser.non_finite_floats(NonFiniteFloats::Null)
+        .unrepresentable_chars(UnrepresentableChars::Replace)
+}
+
+impl Value {
+    // Turns the paths nix has no expression for, like `""`, into strings.
+    fn with_paths_as_strings(self) -> Value {
+        match self {
+            Value::Path(path) if !nix_ser::is_expressible_path(&path) => Value::String(path),
+            Value::List(list) => {
+                Value::List(list.into_iter().map(Value::with_paths_as_strings).collect())
+            }
+            Value::Attrs(attrs) => Value::Attrs(
+                attrs
+                    .into_iter()
+                    .map(|(k, v)| (k, v.with_paths_as_strings()))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+}
@@ -0,0 +1,752 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::ser::{self, Impossible, Serialize};
+
+use super::{Map, Value};
+use crate::bytes;
+use crate::content::{self, Content};
+use crate::mk::APPLY_TOKEN;
+use crate::raw::{self, RawExpr, RAW_EXPR_TOKEN};
+use crate::ser::{
+    float_literal, is_omitted_null, representable, Bytes, Error, IntegerOverflow, MapKeys,
+    NonFiniteFloats, StrCollector, UnrepresentableChars, COMMENTED_TOKEN, PATH_TOKEN,
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Converts `T` into a [`Value`], following the same rules as [`crate::ser::Serializer`] with its
+/// default settings.
+pub fn to_value<T>(value: &T) -> Result<Value>
+where
+    T: ?Sized + Serialize,
+{
+    value.serialize(Serializer::default())
+}
+
+/// Serializes values into a [`Value`] rather than text.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Serializer {
+    // The settings of the `crate::ser::Serializer` that the value is converted for, where they
+    // decide what the value is rather than how it's written.
+    pub(crate) non_finite_floats: NonFiniteFloats,
+    pub(crate) integer_overflow: IntegerOverflow,
+    pub(crate) bytes: Bytes,
+    pub(crate) unrepresentable_chars: UnrepresentableChars,
+    pub(crate) map_keys: MapKeys,
+}
+
+impl Serializer {
+    // `s` with the characters nix can't hold dealt with as `unrepresentable_chars` says, wherever
+    // it ends up in the value.
+    fn representable(self, s: &str) -> Result<String> {
+        Ok(representable(s, self.unrepresentable_chars)?.into_owned())
+    }
+
+    fn serialize_out_of_range<T>(self, value: T, as_float: f64) -> Result<Value>
+    where
+        T: fmt::Display,
+    {
+        match self.integer_overflow {
+            IntegerOverflow::Error => Err(Error::IntegerOutOfRange(value.to_string())),
+            IntegerOverflow::String => ser::Serializer::serialize_str(self, &value.to_string()),
+            IntegerOverflow::Float => ser::Serializer::serialize_f64(self, as_float),
+        }
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeTupleVariant;
+    type SerializeMap = SerializeAttrs;
+    type SerializeStruct = SerializeStruct;
+    type SerializeStructVariant = SerializeStructVariant;
+
+    fn serialize_bool(self, value: bool) -> Result<Value> {
+        Ok(Value::Bool(value))
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<Value> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<Value> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<Value> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<Value> {
+        Ok(Value::Int(value))
+    }
+
+    fn serialize_i128(self, value: i128) -> Result<Value> {
+        match i64::try_from(value) {
+            Ok(value) => self.serialize_i64(value),
+            Err(_) => self.serialize_out_of_range(value, value as f64),
+        }
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<Value> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<Value> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<Value> {
+        self.serialize_i64(value.into())
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<Value> {
+        match i64::try_from(value) {
+            Ok(value) => self.serialize_i64(value),
+            Err(_) => self.serialize_out_of_range(value, value as f64),
+        }
+    }
+
+    fn serialize_u128(self, value: u128) -> Result<Value> {
+        match u64::try_from(value) {
+            Ok(value) => self.serialize_u64(value),
+            Err(_) => self.serialize_out_of_range(value, value as f64),
+        }
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<Value> {
+        if !value.is_finite() {
+            return self.serialize_f64(value.into());
+        }
+        Ok(Value::from(value))
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<Value> {
+        if !value.is_finite() {
+            return match self.non_finite_floats {
+                NonFiniteFloats::Error => Err(Error::NonFiniteFloat(value)),
+                NonFiniteFloats::Null => self.serialize_unit(),
+            };
+        }
+        Ok(Value::Float(value))
+    }
+
+    fn serialize_char(self, value: char) -> Result<Value> {
+        self.serialize_str(&value.to_string())
+    }
+
+    fn serialize_str(self, value: &str) -> Result<Value> {
+        Ok(Value::String(self.representable(value)?))
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<Value> {
+        if let Some(s) = bytes::encode(value, self.bytes)? {
+            return self.serialize_str(&s);
+        }
+        Ok(Value::List(
+            value.iter().map(|&b| Value::Int(b.into())).collect(),
+        ))
+    }
+
+    fn serialize_none(self) -> Result<Value> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        if name == PATH_TOKEN {
+            return Ok(Value::Path(
+                self.representable(&value.serialize(StrCollector)?)?,
+            ));
+        }
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value>
+    where
+        T: ?Sized + Serialize,
+    {
+        let mut attrs = Map::new();
+        attrs.insert(self.representable(variant)?, value.serialize(self)?);
+        Ok(Value::Attrs(attrs))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Ok(SerializeList {
+            ser: self,
+            list: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Ok(SerializeTupleVariant {
+            ser: self,
+            name: self.representable(variant)?,
+            list: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(SerializeAttrs::new(self, self.map_keys == MapKeys::Pairs))
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        if name == RAW_EXPR_TOKEN {
+            return Ok(SerializeStruct::Raw(None));
+        }
+        if name == COMMENTED_TOKEN {
+            return Ok(SerializeStruct::Commented(self, None));
+        }
+        if name == APPLY_TOKEN {
+            return Ok(SerializeStruct::Apply(String::new()));
+        }
+        Ok(SerializeStruct::Attrs(SerializeAttrs::new(self, false)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Ok(SerializeStructVariant {
+            ser: self,
+            name: self.representable(variant)?,
+            attrs: Map::new(),
+        })
+    }
+}
+
+pub struct SerializeList {
+    ser: Serializer,
+    list: Vec<Value>,
+}
+
+pub struct SerializeTupleVariant {
+    ser: Serializer,
+    name: String,
+    list: Vec<Value>,
+}
+
+pub struct SerializeAttrs {
+    ser: Serializer,
+    attrs: Map,
+    next_key: Option<String>,
+    // The entries of a map under `MapKeys::Pairs`, held until it's known whether it's an attrset
+    // or a list of pairs.
+    pairs: Option<Vec<(Content, Content)>>,
+    next_pair_key: Option<Content>,
+}
+
+impl SerializeAttrs {
+    fn new(ser: Serializer, pairs: bool) -> Self {
+        SerializeAttrs {
+            ser,
+            attrs: Map::new(),
+            next_key: None,
+            pairs: pairs.then(Vec::new),
+            next_pair_key: None,
+        }
+    }
+
+    fn insert<T>(&mut self, key: String, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if !is_omitted_null(value) {
+            self.attrs.insert(key, value.serialize(self.ser)?);
+        }
+        Ok(())
+    }
+
+    // A map under `MapKeys::Pairs` with a key that isn't a string, as a list of `{ name = ...;
+    // value = ...; }` attrsets like `crate::ser::Serializer` writes it.
+    fn pairs_list(self, pairs: Vec<(Content, Content)>) -> Result<Value> {
+        let mut list = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let mut pair = Map::new();
+            pair.insert("name".to_string(), key.serialize(self.ser)?);
+            // A raw `null` rather than `Value::Null`, which would be left out of the pair when
+            // it's written under `NullAttrs::Omit`.
+            let value = match value.serialize(self.ser)? {
+                Value::Null => Value::Raw(RawExpr::new("null")),
+                value => value,
+            };
+            pair.insert("value".to_string(), value);
+            list.push(Value::Attrs(pair));
+        }
+        Ok(Value::List(list))
+    }
+}
+
+pub enum SerializeStruct {
+    Attrs(SerializeAttrs),
+    Raw(Option<RawExpr>),
+    Commented(Serializer, Option<Value>),
+    // A function call, kept as the text of a raw expression.
+    Apply(String),
+}
+
+pub struct SerializeStructVariant {
+    ser: Serializer,
+    name: String,
+    attrs: Map,
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.list.push(value.serialize(self.ser)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::List(self.list))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTupleVariant {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.list.push(value.serialize(self.ser)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        let mut attrs = Map::new();
+        attrs.insert(self.name, Value::List(self.list));
+        Ok(Value::Attrs(attrs))
+    }
+}
+
+impl ser::SerializeMap for SerializeAttrs {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if self.pairs.is_some() {
+            self.next_pair_key = Some(content::to_content(key)?);
+            return Ok(());
+        }
+        let stringify = self.ser.map_keys == MapKeys::Stringify;
+        self.next_key = Some(self.ser.representable(&key_to_string(key, stringify)?)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if let Some(pairs) = &mut self.pairs {
+            let key = self
+                .next_pair_key
+                .take()
+                .expect("serialize_value called before serialize_key");
+            pairs.push((key, content::to_content(value)?));
+            return Ok(());
+        }
+        let key = self
+            .next_key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.insert(key, value)
+    }
+
+    fn end(mut self) -> Result<Value> {
+        if let Some(pairs) = self.pairs.take() {
+            if !pairs
+                .iter()
+                .all(|(key, _)| key_to_string(key, false).is_ok())
+            {
+                return self.pairs_list(pairs);
+            }
+            for (key, value) in &pairs {
+                let key = self.ser.representable(&key_to_string(key, false)?)?;
+                self.insert(key, value)?;
+            }
+        }
+        Ok(Value::Attrs(self.attrs))
+    }
+}
+
+impl ser::SerializeStruct for SerializeStruct {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        match self {
+            SerializeStruct::Attrs(attrs) => ser::SerializeMap::serialize_entry(attrs, key, value),
+            SerializeStruct::Raw(raw) => {
+                *raw = Some(RawExpr::new(value.serialize(StrCollector)?));
+                Ok(())
+            }
+            SerializeStruct::Commented(_, _) if key == COMMENTED_TOKEN => Ok(()),
+            SerializeStruct::Commented(ser, commented) => {
+                *commented = Some(value.serialize(*ser)?);
+                Ok(())
+            }
+            SerializeStruct::Apply(text) if key == APPLY_TOKEN => {
+                *text = value.serialize(StrCollector)?;
+                Ok(())
+            }
+            SerializeStruct::Apply(text) => {
+                let arg = crate::to_string(value)?;
+                if raw::is_select_expr(&arg) {
+                    *text += &format!(" {}", arg);
+                } else {
+                    *text += &format!(" ({})", arg);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn end(self) -> Result<Value> {
+        match self {
+            SerializeStruct::Attrs(attrs) => ser::SerializeMap::end(attrs),
+            SerializeStruct::Raw(raw) => Ok(Value::Raw(raw.expect("raw expression without text"))),
+            SerializeStruct::Commented(_, value) => Ok(value.expect("comment without a value")),
+            SerializeStruct::Apply(text) => Ok(Value::Raw(RawExpr::new(text))),
+        }
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeStructVariant {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if !is_omitted_null(value) {
+            self.attrs
+                .insert(self.ser.representable(key)?, value.serialize(self.ser)?);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        let mut attrs = Map::new();
+        attrs.insert(self.name, Value::Attrs(self.attrs));
+        Ok(Value::Attrs(attrs))
+    }
+}
+
+// The name an attribute key is written as. Only strings and chars make attribute names, unless
+// `stringify` is set, which turns bools and numbers into strings too. Null characters are left for
+// the caller to deal with.
+pub(crate) fn key_to_string<T>(key: &T, stringify: bool) -> Result<String>
+where
+    T: ?Sized + Serialize,
+{
+    key.serialize(MapKeySerializer { stringify })
+}
+
+struct MapKeySerializer {
+    stringify: bool,
+}
+
+impl MapKeySerializer {
+    fn stringify<T: ToString>(self, value: T) -> Result<String> {
+        if self.stringify {
+            Ok(value.to_string())
+        } else {
+            Err(Error::MapKeyMustBeAString)
+        }
+    }
+
+    fn stringify_float<T>(self, value: T) -> Result<String>
+    where
+        T: Copy + Into<f64> + fmt::Display + fmt::LowerExp,
+    {
+        if !self.stringify {
+            return Err(Error::MapKeyMustBeAString);
+        }
+        if !value.into().is_finite() {
+            return Err(Error::NonFiniteFloat(value.into()));
+        }
+        Ok(float_literal(value))
+    }
+}
+
+impl ser::Serializer for MapKeySerializer {
+    type Ok = String;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    fn serialize_str(self, value: &str) -> Result<String> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_char(self, value: char) -> Result<String> {
+        self.serialize_str(&value.to_string())
+    }
+
+    fn serialize_bool(self, value: bool) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<String> {
+        self.stringify_float(value)
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<String> {
+        self.stringify_float(value)
+    }
+
+    fn serialize_i128(self, value: i128) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_u128(self, value: u128) -> Result<String> {
+        self.stringify(value)
+    }
+
+    fn serialize_bytes(self, _value: &[u8]) -> Result<String> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::MapKeyMustBeAString)
+    }
+
+    fn collect_str<T>(self, value: &T) -> Result<String>
+    where
+        T: ?Sized + fmt::Display,
+    {
+        self.serialize_str(&value.to_string())
+    }
+}
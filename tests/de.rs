@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use quickcheck_macros::quickcheck;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_nix::{from_str, Error, Value};
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+struct Person {
+    name: String,
+    age: i32,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+enum Shape {
+    Empty,
+    Circle(u32),
+    Move(i32, i32),
+    Point { x: i32, y: i32 },
+}
+
+// Like the round trip through nix-instantiate in test.rs, but using our own deserializer.
+fn round_trip<T>(v: T)
+where
+    T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    for nix_str in [
+        serde_nix::to_string(&v).unwrap(),
+        serde_nix::to_string_pretty(&v).unwrap(),
+    ] {
+        let parsed: T = from_str(&nix_str).unwrap();
+        assert_eq!(v, parsed, "{}", nix_str);
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    round_trip(1);
+    round_trip(vec![1, 2, 3, 4]);
+    round_trip("foo".to_string());
+    round_trip(Person {
+        age: 10,
+        name: "foo".to_string(),
+    });
+    round_trip((1, 2, 3));
+    round_trip(vec![-1, 2, -3]);
+    round_trip(vec![i64::MIN, i64::MAX]);
+    round_trip(vec![-1.5, 2.5, 1.0, 1e-7, 1e300, f64::MIN_POSITIVE]);
+    round_trip(Shape::Empty);
+    round_trip(Shape::Circle(1));
+    round_trip(vec![Shape::Move(1, -2), Shape::Point { x: 3, y: 4 }]);
+    round_trip(Some(vec![None, Some(true), Some(false)]));
+    round_trip("if".to_string());
+    round_trip(
+        ["/etc/nixos", "./a.nix", "/a b", "./a b", "./${x}"]
+            .iter()
+            .map(|p| serde_nix::Path(p.to_string()))
+            .collect::<Vec<_>>(),
+    );
+    round_trip("a\"b\\c${d}$${e}\n\t\r".to_string());
+    let m: HashMap<String, String> = [
+        "if", "then", "else", "assert", "with", "let", "in", "rec", "inherit", "or", "",
+    ]
+    .iter()
+    .map(|kw| (kw.to_string(), kw.to_string()))
+    .collect();
+    round_trip(m);
+}
+
+#[quickcheck]
+fn quickcheck_strings(m: HashMap<String, Vec<String>>) -> bool {
+    if m.keys()
+        .chain(m.values().flatten())
+        .any(|s| s.contains('\0'))
+    {
+        return true;
+    }
+    round_trip(m);
+    true
+}
+
+#[quickcheck]
+fn quickcheck_numbers(ints: Vec<i64>, floats: Vec<f64>) -> bool {
+    round_trip(ints);
+    if floats.iter().all(|f| f.is_finite()) {
+        round_trip(floats);
+    }
+    true
+}
+
+#[test]
+fn test_literals() {
+    let v: Value = from_str(
+        r#"
+        # a comment
+        {
+          a.b = 1; /* another
+          comment */
+          a.c = [ ./foo.nix /etc/nixos ~/x a/b ];
+          "quoted key" = .5;
+          d = (-1.5e3);
+          e = - 2;
+          f = null;
+          or = true;
+        }
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        v.to_string(),
+        r#"{ a = { b = 1; c = [ "./foo.nix" "/etc/nixos" "~/x" "a/b" ]; }; "quoted key" = 0.5; d = -1500.0; e = -2; f = null; "or" = true; }"#,
+    );
+
+    let paths: Vec<String> = from_str("[ ./a /b ]").unwrap();
+    assert_eq!(paths, vec!["./a", "/b"]);
+}
+
+#[test]
+fn test_indented_strings() {
+    for (nix, expected) in [
+        ("''\n  foo\n    bar\n  ''", "foo\n  bar\n"),
+        ("''foo''", "foo"),
+        ("''  \n  a\n\n  b''", "a\n\nb"),
+        ("''\n  a\n  ''", "a\n"),
+        ("''\n  a\n  b  ''", "a\nb  "),
+        ("''\n    a\n  ''\\tb\n''", "  a\n\tb\n"),
+        ("'''''''$''\\n''\\' ''", "''$\n' "),
+        ("''$${x} $ '' ", "$${x} $ "),
+        ("''\n\tfoo\n''", "\tfoo\n"),
+    ] {
+        assert_eq!(from_str::<String>(nix).unwrap(), expected, "{:?}", nix);
+    }
+}
+
+#[test]
+fn test_requires_evaluation() {
+    for nix in [
+        "let a = 1; in a",
+        "pkgs.hello",
+        "rec { a = 1; }",
+        "with pkgs; [ ]",
+        "{ inherit a; }",
+        "\"${a}\"",
+        "''${a}''",
+        "{ ${a} = 1; }",
+        "1 + 2",
+        "{ a = 1 + 2; }",
+        "[ 1 (2 * 3) ]",
+        "{ a = f 1; }",
+        "x: x",
+        "<nixpkgs>",
+        "./foo/${bar}",
+        "{ a = { b = 1; }.b; }",
+        "if true then 1 else 2",
+    ] {
+        match from_str::<Value>(nix) {
+            Err(Error::RequiresEvaluation { .. }) => {}
+            other => panic!("{:?}: {:?}", nix, other),
+        }
+    }
+    assert_eq!(
+        from_str::<Value>("{\n  a = pkgs.hello;\n}")
+            .unwrap_err()
+            .to_string(),
+        "variable `pkgs` at line 2 column 7 would need evaluation, which is not supported",
+    );
+}
+
+#[test]
+fn test_syntax_errors() {
+    for nix in [
+        "",
+        "{ a = 1 }",
+        "{ a = 1; a = 2; }",
+        "{ a.b = 1; a = 2; }",
+        "[ 1 -2 ]",
+        "\"abc",
+        "/* abc",
+        "{ if = 1; }",
+        "9223372036854775808",
+        "./foo/",
+        "[ 1 2",
+        "{ a = { x = 1; }; a = { y = 2; }; }",
+        "-(-9223372036854775807 - 1)",
+    ] {
+        match from_str::<Value>(nix) {
+            Err(Error::Syntax { .. }) => {}
+            other => panic!("{:?}: {:?}", nix, other),
+        }
+    }
+    assert_eq!(
+        from_str::<Value>("{ a = 1;\n  a = 2; }")
+            .unwrap_err()
+            .to_string(),
+        "attribute 'a' already defined at line 2 column 3",
+    );
+}
+
+#[test]
+fn test_from_reader_and_slice() {
+    let p: Person = serde_nix::from_slice(br#"{ name = "foo"; age = 20; }"#).unwrap();
+    assert_eq!(
+        p,
+        Person {
+            name: "foo".to_string(),
+            age: 20
+        }
+    );
+    let p: Person = serde_nix::from_reader(&br#"{ age = 20; name = "foo"; }"#[..]).unwrap();
+    assert_eq!(p.age, 20);
+    assert!(matches!(
+        serde_nix::from_slice::<Value>(b"\"\xff\""),
+        Err(Error::Syntax { .. })
+    ));
+}
+
+#[test]
+fn test_bytes() {
+    use serde_bytes::ByteBuf;
+    use serde_nix::de::Options;
+    use serde_nix::ser::{Bytes, Serializer};
+
+    let blobs = [&b""[..], b"h", b"hi", b"hi!", b"\x00\xff\x80", &[7; 32]];
+    for encoding in [Bytes::List, Bytes::Base64, Bytes::Hex, Bytes::Sri] {
+        for blob in blobs {
+            let mut out = Vec::new();
+            let mut ser = Serializer::new(&mut out).bytes(encoding);
+            if ByteBuf::from(blob).serialize(&mut ser).is_err() {
+                assert_eq!(encoding, Bytes::Sri);
+                continue;
+            }
+            let parsed: ByteBuf = Options::new().bytes(encoding).from_slice(&out).unwrap();
+            assert_eq!(parsed, blob, "{:?}", encoding);
+        }
+    }
+
+    // strings are read as UTF-8 by default, and lists whatever the encoding
+    let bytes: ByteBuf = from_str(r#""hi""#).unwrap();
+    assert_eq!(bytes, b"hi");
+    let bytes: ByteBuf = Options::new()
+        .bytes(Bytes::Utf8)
+        .from_str(r#""hi""#)
+        .unwrap();
+    assert_eq!(bytes, b"hi");
+    let bytes: Vec<ByteBuf> = Options::new()
+        .bytes(Bytes::Hex)
+        .from_str("[ [ 104 105 ] \"6869\" ]")
+        .unwrap();
+    assert_eq!(bytes, [b"hi", b"hi"]);
+
+    for (encoding, s) in [
+        (Bytes::Base64, r#""aGk""#),
+        (Bytes::Base64, r#""aGl=""#),
+        (Bytes::Base64, r#""a?k=""#),
+        (Bytes::Hex, r#""686""#),
+        (Bytes::Hex, r#""+f""#),
+        (Bytes::Sri, r#""sha256-aGk=""#),
+        (Bytes::Sri, r#""sha3-AAAAAAAAAAAAAAAAAAAAAA==""#),
+    ] {
+        let result = Options::new().bytes(encoding).from_str::<ByteBuf>(s);
+        assert!(matches!(result, Err(Error::Custom(_))), "{}", s);
+    }
+}
@@ -0,0 +1,128 @@
+use serde::Serialize;
+use serde_nix::{nix, Value};
+
+#[derive(Serialize)]
+struct Nginx {
+    enable: bool,
+    #[serde(rename = "recommended-settings")]
+    recommended_settings: bool,
+    ports: Vec<i32>,
+    user: Option<String>,
+}
+
+#[test]
+fn test_matches_to_string() {
+    let nginx = Nginx {
+        enable: true,
+        recommended_settings: false,
+        ports: vec![-80, 443],
+        user: None,
+    };
+    let user: Option<String> = None;
+    let value = nix!({
+        enable = true;
+        "recommended-settings" = false;
+        ports = [ (-80) 443 ];
+        user = user;
+    });
+    assert_eq!(
+        serde_nix::to_string(&value).unwrap(),
+        serde_nix::to_string(&nginx).unwrap(),
+    );
+    assert_eq!(value, serde_nix::to_value(&nginx).unwrap());
+}
+
+#[test]
+fn test_scalars() {
+    assert_eq!(nix!(null), Value::Null);
+    assert_eq!(nix!(true), Value::Bool(true));
+    assert_eq!(nix!(1.5), Value::Float(1.5));
+    assert_eq!(nix!(-1), Value::Int(-1));
+    assert_eq!(nix!("a"), Value::String("a".to_string()));
+    assert_eq!(nix!([]), Value::List(vec![]));
+    assert_eq!(nix!({}).to_string(), "{ }");
+    let x = 2;
+    assert_eq!(
+        nix!([ x (x + 1) "s" null ]).to_string(),
+        r#"[ 2 3 "s" null ]"#
+    );
+}
+
+#[test]
+fn test_attr_paths() {
+    let name = "web";
+    let value = nix!({
+        services.nginx.enable = true;
+        services.nginx.virtualHosts.(name).root = "/srv";
+        services.nginx.virtualHosts."example.com" = { forceSSL = true; };
+        services.nginx.virtualHosts."example.com".enableACME = true;
+        if = 1;
+        in.let = [ { a = 1; } ];
+        "" = 1;
+    });
+    assert_eq!(
+        value.to_string(),
+        r#"{ services = { nginx = { enable = true; virtualHosts = { web = { root = "/srv"; }; "example.com" = { forceSSL = true; enableACME = true; }; }; }; }; "if" = 1; "in" = { "let" = [ { a = 1; } ]; }; "" = 1; }"#,
+    );
+}
+
+#[test]
+fn test_interpolation() {
+    let nginx = Nginx {
+        enable: true,
+        recommended_settings: true,
+        ports: vec![],
+        user: Some("www".to_string()),
+    };
+    let value = nix!({
+        services.nginx = nginx;
+        extra = vec![1, 2];
+    });
+    assert_eq!(
+        value.to_string(),
+        r#"{ services = { nginx = { enable = true; recommended-settings = true; ports = [ ]; user = "www"; }; }; extra = [ 1 2 ]; }"#,
+    );
+}
+
+#[test]
+fn test_merged_attrs() {
+    let value = nix!({
+        a.b = 1;
+        a = {
+            c = 2;
+        };
+        a.d = 3;
+    });
+    assert_eq!(value.to_string(), "{ a = { b = 1; c = 2; d = 3; }; }");
+}
+
+#[test]
+#[should_panic(expected = "attribute 'a.b' already defined")]
+fn test_duplicate_attr() {
+    nix!({
+        a.b = 1;
+        a.b.c = 2;
+    });
+}
+
+#[test]
+#[should_panic(expected = "attribute 'a' already defined")]
+fn test_duplicate_attrset() {
+    nix!({
+        a = {
+            x = 1;
+        };
+        a = {
+            y = 2;
+        };
+    });
+}
+
+#[test]
+#[should_panic(expected = "attribute 'a' already defined")]
+fn test_duplicate_leaf() {
+    nix!({
+        a = 1;
+        a = 2;
+    });
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_nix::options::{Declarations, MkOption, Nested, NixOptions, NixType, OptionType};
+use serde_nix::{Module, RawExpr};
+#[cfg(not(feature = "derive"))]
+use serde_nix_derive::NixOptions;
+
+#[derive(Serialize, NixOptions)]
+#[serde(rename_all = "camelCase")]
+struct Foo {
+    /// Whether to run foo.
+    enable: bool,
+    /// The address to listen on.
+    ///
+    /// Defaults to all interfaces.
+    listen_address: String,
+    #[nix(type = "lib.types.port")]
+    port: u16,
+    user: Option<String>,
+    extra_args: Vec<String>,
+    environment: HashMap<String, String>,
+    log_level: LogLevel,
+    #[serde(rename = "tls")]
+    tls_settings: Tls,
+    #[serde(skip)]
+    #[allow(dead_code)]
+    state: u64,
+}
+
+impl Default for Foo {
+    fn default() -> Self {
+        Foo {
+            enable: false,
+            listen_address: "0.0.0.0".to_string(),
+            port: 8080,
+            user: None,
+            extra_args: Vec::new(),
+            environment: HashMap::new(),
+            log_level: LogLevel::Info,
+            tls_settings: Tls::default(),
+            state: 0,
+        }
+    }
+}
+
+#[derive(Serialize, NixOptions)]
+#[serde(rename_all = "kebab-case")]
+#[allow(dead_code)]
+enum LogLevel {
+    Info,
+    VeryVerbose,
+    #[serde(rename = "off")]
+    Quiet,
+}
+
+#[derive(Default, Serialize, NixOptions)]
+struct Tls {
+    cert_file: Option<std::path::PathBuf>,
+}
+
+#[test]
+fn test_option_types() {
+    assert_eq!(
+        <Vec<Option<u8>>>::option_type(),
+        OptionType::ListOf(Box::new(OptionType::NullOr(Box::new(OptionType::Int)))),
+    );
+    assert_eq!(
+        serde_nix::to_string(&LogLevel::option_type()).unwrap(),
+        r#"lib.types.enum [ "info" "very-verbose" "off" ]"#,
+    );
+    assert_eq!(
+        serde_nix::to_string(&<HashMap<String, Vec<Tls>>>::option_type()).unwrap(),
+        "lib.types.attrsOf (lib.types.listOf (lib.types.submodule { options = { cert_file = lib.mkOption { type = lib.types.nullOr lib.types.path; default = null; }; }; }))",
+    );
+
+    let mut options = Declarations::default();
+    options.0.insert(
+        "package".to_string(),
+        MkOption::new(OptionType::Raw(RawExpr::new("lib.types.package"))),
+    );
+    assert_eq!(
+        serde_nix::to_string(&options).unwrap(),
+        "{ package = lib.mkOption { type = lib.types.package; }; }",
+    );
+}
+
+#[test]
+fn test_derived_options() {
+    let module = Module::new().options(Nested::new(["services", "foo"], Foo::nix_options()));
+    let config = Nested::new(["services", "foo"], Foo::default());
+    let pretty = serde_nix::to_module_string_pretty(&config, &module).unwrap();
+    assert_eq!(
+        pretty,
+        r#"{ config, lib, pkgs, ... }:
+{
+  options = {
+    services = {
+      foo = {
+        enable = lib.mkOption {
+          type = lib.types.bool;
+          default = false;
+          description = "Whether to run foo.";
+        };
+        listenAddress = lib.mkOption {
+          type = lib.types.str;
+          default = "0.0.0.0";
+          description = "The address to listen on.\n\nDefaults to all interfaces.";
+        };
+        port = lib.mkOption {
+          type = lib.types.port;
+          default = 8080;
+        };
+        user = lib.mkOption {
+          type = lib.types.nullOr lib.types.str;
+          default = null;
+        };
+        extraArgs = lib.mkOption {
+          type = lib.types.listOf lib.types.str;
+          default = [ ];
+        };
+        environment = lib.mkOption {
+          type = lib.types.attrsOf lib.types.str;
+          default = { };
+        };
+        logLevel = lib.mkOption {
+          type = lib.types.enum [
+            "info"
+            "very-verbose"
+            "off"
+          ];
+          default = "info";
+        };
+        tls = lib.mkOption {
+          type = lib.types.submodule {
+            options = {
+              cert_file = lib.mkOption {
+                type = lib.types.nullOr lib.types.path;
+                default = null;
+              };
+            };
+          };
+          default = {
+            cert_file = null;
+          };
+        };
+      };
+    };
+  };
+  config = {
+    services = {
+      foo = {
+        enable = false;
+        listenAddress = "0.0.0.0";
+        port = 8080;
+        user = null;
+        extraArgs = [ ];
+        environment = { };
+        logLevel = "info";
+        tls = {
+          cert_file = null;
+        };
+      };
+    };
+  };
+}"#,
+    );
+    assert!(serde_nix::syntax::parse(&pretty).is_ok());
+}
+
+#[derive(Serialize, NixOptions)]
+struct Site {
+    #[serde(with = "serde_nix::path")]
+    root: std::path::PathBuf,
+}
+
+impl Default for Site {
+    fn default() -> Self {
+        Site {
+            root: "/srv/www".into(),
+        }
+    }
+}
+
+#[derive(Serialize, NixOptions)]
+struct Ports {
+    names: HashMap<u16, String>,
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        let mut names = HashMap::new();
+        names.insert(80, "http".to_string());
+        Ports { names }
+    }
+}
+
+#[test]
+fn test_derived_defaults() {
+    // defaults are serialized the way the struct is, so `with` applies to them
+    assert_eq!(
+        serde_nix::to_string(&Site::nix_options()).unwrap(),
+        "{ root = lib.mkOption { type = lib.types.path; default = /srv/www; }; }",
+    );
+    // and they're left out rather than panicking when the struct can't be serialized
+    assert_eq!(
+        serde_nix::to_string(&Ports::nix_options()).unwrap(),
+        "{ names = lib.mkOption { type = lib.types.attrsOf lib.types.str; }; }",
+    );
+}
+
+#[derive(Serialize, NixOptions)]
+struct Listener {
+    #[nix(default = "8080")]
+    port: u16,
+    address: String,
+}
+
+#[derive(Default, Serialize, NixOptions)]
+struct Upstream<T> {
+    servers: Vec<T>,
+}
+
+#[test]
+fn test_derived_without_default() {
+    // types without `Default`, and generic ones, only have the defaults given with the attribute
+    assert_eq!(
+        serde_nix::to_string(&Listener::nix_options()).unwrap(),
+        "{ port = lib.mkOption { type = lib.types.int; default = 8080; }; address = lib.mkOption { type = lib.types.str; }; }",
+    );
+    assert_eq!(
+        serde_nix::to_string(&Upstream::<String>::nix_options()).unwrap(),
+        "{ servers = lib.mkOption { type = lib.types.listOf lib.types.str; }; }",
+    );
+    assert_eq!(
+        serde_nix::to_string(&<Vec<Upstream<u16>>>::option_type()).unwrap(),
+        "lib.types.listOf (lib.types.submodule { options = { servers = lib.mkOption { type = lib.types.listOf lib.types.int; }; }; })",
+    );
+}
@@ -0,0 +1,269 @@
+use serde_nix::syntax::{self, AttrKind, BinOp, Binding, Expr, ExprKind, Param, StrPart, UnaryOp};
+use serde_nix::Error;
+
+// Prints an expression with explicit parentheses around every operator, to check precedence.
+fn show(e: &Expr) -> String {
+    match &e.kind {
+        ExprKind::Int(i) => i.to_string(),
+        ExprKind::Float(f) => format!("{:?}", f),
+        ExprKind::Ident(i) => i.clone(),
+        ExprKind::Str(parts) | ExprKind::IndStr(parts) | ExprKind::Path(parts) => parts
+            .iter()
+            .map(|p| match p {
+                StrPart::Literal(s) => format!("{:?}", s),
+                StrPart::Interpolation(e) => format!("${{{}}}", show(e)),
+            })
+            .collect::<Vec<_>>()
+            .join("+"),
+        ExprKind::SearchPath(p) => format!("<{}>", p),
+        ExprKind::Uri(u) => format!("uri:{}", u),
+        ExprKind::List(l) => format!("[{}]", l.iter().map(show).collect::<Vec<_>>().join(" ")),
+        ExprKind::Attrs { rec, bindings } => {
+            format!("{}{{{}}}", if *rec { "rec" } else { "" }, bindings.len())
+        }
+        ExprKind::Let { body, .. } => format!("(let {})", show(body)),
+        ExprKind::With { env, body } => format!("(with {}; {})", show(env), show(body)),
+        ExprKind::Assert { cond, body } => format!("(assert {}; {})", show(cond), show(body)),
+        ExprKind::If { cond, then, else_ } => {
+            format!("(if {} {} {})", show(cond), show(then), show(else_))
+        }
+        ExprKind::Lambda { body, .. } => format!("(fn {})", show(body)),
+        ExprKind::Apply { func, arg } => format!("({} {})", show(func), show(arg)),
+        ExprKind::Select {
+            expr,
+            path,
+            default,
+        } => format!(
+            "({}.{}{})",
+            show(expr),
+            path.attrs.len(),
+            default
+                .as_ref()
+                .map(|d| format!(" or {}", show(d)))
+                .unwrap_or_default()
+        ),
+        ExprKind::HasAttr { expr, path } => format!("({} ? {})", show(expr), path.attrs.len()),
+        ExprKind::UnaryOp { op, expr } => format!(
+            "({}{})",
+            match op {
+                UnaryOp::Negate => "-",
+                UnaryOp::Not => "!",
+            },
+            show(expr)
+        ),
+        ExprKind::BinOp { op, lhs, rhs } => {
+            let op = match op {
+                BinOp::Implication => "->",
+                BinOp::Or => "||",
+                BinOp::And => "&&",
+                BinOp::Equal => "==",
+                BinOp::NotEqual => "!=",
+                BinOp::Less => "<",
+                BinOp::LessOrEqual => "<=",
+                BinOp::Greater => ">",
+                BinOp::GreaterOrEqual => ">=",
+                BinOp::Update => "//",
+                BinOp::Add => "+",
+                BinOp::Subtract => "-",
+                BinOp::Multiply => "*",
+                BinOp::Divide => "/",
+                BinOp::Concat => "++",
+            };
+            format!("({} {} {})", show(lhs), op, show(rhs))
+        }
+        ExprKind::Paren(e) => show(e),
+    }
+}
+
+fn parse_show(src: &str) -> String {
+    show(&syntax::parse(src).unwrap_or_else(|e| panic!("{:?}: {}", src, e)))
+}
+
+#[test]
+fn test_precedence() {
+    for (src, expected) in [
+        ("1 + 2 * 3", "(1 + (2 * 3))"),
+        ("1 - 2 - 3", "((1 - 2) - 3)"),
+        ("a ++ b ++ c", "(a ++ (b ++ c))"),
+        ("a // b // c", "(a // (b // c))"),
+        ("a -> b -> c", "(a -> (b -> c))"),
+        ("!a && b || c", "(((!a) && b) || c)"),
+        ("!a + b", "(!(a + b))"),
+        ("!a // b", "((!a) // b)"),
+        ("-a.b", "(-(a.1))"),
+        ("- f x", "(-(f x))"),
+        ("-1 - 1", "((-1) - 1)"),
+        ("f x y", "((f x) y)"),
+        ("f x.y or z", "(f (x.1 or z))"),
+        ("a ? b.c && d", "((a ? 2) && d)"),
+        ("a < b == c", "((a < b) == c)"),
+        ("a.b.c or [ ]", "(a.2 or [])"),
+        ("[ f x (-1) ]", "[f x (-1)]"),
+        ("x: y: x + y", "(fn (fn (x + y)))"),
+        ("if a then b else c + 1", "(if a b (c + 1))"),
+        ("with pkgs; [ hello ]", "(with pkgs; [hello])"),
+        ("assert a; b", "(assert a; b)"),
+        ("let a = 1; in a", "(let a)"),
+        ("1.5/2", "\"1.5/2\""),
+        ("a / b", "(a / b)"),
+        ("https://nixos.org", "uri:https://nixos.org"),
+        ("<nixpkgs>", "<nixpkgs>"),
+        ("let { body = 1; }", "(rec{1}.1)"),
+    ] {
+        assert_eq!(parse_show(src), expected, "{}", src);
+    }
+}
+
+#[test]
+fn test_bindings() {
+    let src = r#"rec {
+      inherit a;
+      inherit (pkgs) hello "world";
+      a.${b}."c${d}".or = 1;
+    }"#;
+    let expr = syntax::parse(src).unwrap();
+    let bindings = match expr.kind {
+        ExprKind::Attrs {
+            rec: true,
+            bindings,
+        } => bindings,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(bindings.len(), 3);
+    match &bindings[1] {
+        Binding::Inherit {
+            from: Some(from),
+            attrs,
+            span,
+        } => {
+            assert_eq!(from.kind, ExprKind::Ident("pkgs".to_string()));
+            assert_eq!(attrs.len(), 2);
+            assert_eq!(
+                &src[span.start..span.end],
+                "inherit (pkgs) hello \"world\";"
+            );
+        }
+        other => panic!("{:?}", other),
+    }
+    match &bindings[2] {
+        Binding::Attr { path, value, .. } => {
+            let kinds: Vec<_> = path.attrs.iter().map(|a| &a.kind).collect();
+            assert!(matches!(kinds[0], AttrKind::Ident(a) if a == "a"));
+            assert!(matches!(kinds[1], AttrKind::Dynamic(_)));
+            assert!(matches!(kinds[2], AttrKind::Str(parts) if parts.len() == 2));
+            assert!(matches!(kinds[3], AttrKind::Ident(a) if a == "or"));
+            assert_eq!(&src[value.span.start..value.span.end], "1");
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn test_functions() {
+    let src = "args @ { a, b ? a + 1, ... }: a";
+    match syntax::parse(src).unwrap().kind {
+        ExprKind::Lambda {
+            param:
+                Param::Pattern {
+                    formals,
+                    ellipsis,
+                    bind,
+                },
+            ..
+        } => {
+            assert_eq!(bind.as_deref(), Some("args"));
+            assert!(ellipsis);
+            assert_eq!(formals.len(), 2);
+            assert!(formals[1].default.is_some());
+            assert_eq!(
+                &src[formals[1].span.start..formals[1].span.end],
+                "b ? a + 1"
+            );
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(parse_show("{ }: 1"), "(fn 1)");
+    assert_eq!(parse_show("{ a }@args: 1"), "(fn 1)");
+    assert_eq!(parse_show("{ a = 1; }"), "{1}");
+    assert_eq!(parse_show("{ }"), "{0}");
+}
+
+#[test]
+fn test_strings() {
+    for (src, expected) in [
+        (r#""a${b}c""#, r#""a"+${b}+"c""#),
+        (r#""${"${x}"}""#, "${${x}}"),
+        (r#""\${x} $${x}""#, r#""${x} $${x}""#),
+        ("''\n  a ${b}\n    c\n''", r#""a "+${b}+"\n  c\n""#),
+        ("''${x}''", "${x}"),
+        ("./foo/${bar}.nix", r#""./foo/"+${bar}+".nix""#),
+        ("~/.config", r#""~/.config""#),
+        (r#"{ a = "}"; }.a"#, "({1}.1)"),
+    ] {
+        assert_eq!(parse_show(src), expected, "{}", src);
+    }
+}
+
+#[test]
+fn test_spans() {
+    let src = "let\n  x = [ 1 2 ];\nin x ++ [ 3 ]";
+    let expr = syntax::parse(src).unwrap();
+    assert_eq!((expr.span.start, expr.span.end), (0, src.len()));
+    match expr.kind {
+        ExprKind::Let { bindings, body } => {
+            assert_eq!(&src[body.span.start..body.span.end], "x ++ [ 3 ]");
+            match &bindings[0] {
+                Binding::Attr { value, span, .. } => {
+                    assert_eq!(&src[value.span.start..value.span.end], "[ 1 2 ]");
+                    assert_eq!(&src[span.start..span.end], "x = [ 1 2 ];");
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn test_syntax_errors() {
+    for src in [
+        "",
+        "1 +",
+        "a == b == c",
+        "{ a = 1 }",
+        "{ if = 1; }",
+        "let a = 1; a",
+        "[ 1 -2 ]",
+        "\"${a\"",
+        "./foo/",
+        "(1",
+        "f x:",
+        "1 +\u{a0}2",
+        "[\u{2003}]",
+    ] {
+        match syntax::parse(src) {
+            Err(Error::Syntax { .. }) => {}
+            other => panic!("{:?}: {:?}", src, other),
+        }
+    }
+    assert_eq!(
+        syntax::parse("{\n  a = 1 +;\n}").unwrap_err().to_string(),
+        "expected an expression, found `;` at line 2 column 10",
+    );
+}
+
+#[test]
+fn test_serializer_output_parses() {
+    use std::collections::BTreeMap;
+    let mut m = BTreeMap::new();
+    m.insert("if", vec![-1.5, 2.0]);
+    m.insert("a b", vec![]);
+    for s in [
+        serde_nix::to_string(&m).unwrap(),
+        serde_nix::to_string_pretty(&m).unwrap(),
+        serde_nix::to_string(&vec![i64::MIN, -1]).unwrap(),
+        serde_nix::to_string(&"${x}\n\"").unwrap(),
+    ] {
+        syntax::parse(&s).unwrap_or_else(|e| panic!("{}: {}", s, e));
+    }
+}
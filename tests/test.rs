@@ -3,7 +3,35 @@ use std::collections::HashMap;
 use quickcheck_macros::quickcheck;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_nix::ser::Error;
+use serde_nix::ser::{AttrOrder, AttrPaths, Error, Formatter, MultilineStrings, Serializer};
+
+// Writes `$value` in the compact and the pretty layout, with both serializers set up by the
+// `$configure` closure. The `write:` form writes with a closure of its own instead. This is a
+// macro so that the one closure can be used with both formatters.
+macro_rules! to_strings {
+    ($configure:expr, write: $write:expr) => {
+        write_with(Serializer::new(Vec::new()), $configure, $write).and_then(|compact| {
+            let pretty = write_with(Serializer::pretty(Vec::new()), $configure, $write)?;
+            Ok([compact, pretty])
+        })
+    };
+    ($value:expr, $configure:expr) => {
+        to_strings!($configure, write: |ser| Serialize::serialize($value, ser))
+    };
+}
+
+fn write_with<F>(
+    ser: Serializer<Vec<u8>, F>,
+    configure: impl FnOnce(Serializer<Vec<u8>, F>) -> Serializer<Vec<u8>, F>,
+    write: impl FnOnce(&mut Serializer<Vec<u8>, F>) -> Result<(), Error>,
+) -> Result<String, Error>
+where
+    F: Formatter,
+{
+    let mut ser = configure(ser);
+    write(&mut ser)?;
+    Ok(String::from_utf8(ser.into_inner()).unwrap())
+}
 
 #[test]
 fn test_write_bool() {
@@ -36,7 +64,7 @@ fn test_hashmaps() {
     m.insert("foo", "bar");
     m.insert("bar", "baz");
     let s = serde_nix::to_string(&m).unwrap();
-    let correct = vec![
+    let correct = [
         r#"{ foo = "bar"; bar = "baz"; }"#.to_string(),
         r#"{ bar = "baz"; foo = "bar"; }"#.to_string(),
     ];
@@ -123,67 +151,1825 @@ fn test_arrays() {
     );
 }
 
-#[cfg(test)]
-fn round_trip<'de, T>(v: T) -> Result<(), Error>
-where
-    T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
-{
-    let nix_str = serde_nix::to_string(&v)?;
-    println!("nix str: {}", nix_str);
-    // evaluate with the nix interpreter, convert to json, and parse that json. :|
-    let json = std::process::Command::new("nix-instantiate")
-        .args(&["--eval", "--json", "-E", &nix_str])
-        .output()
-        .expect("could not run nix-instantiate");
+#[test]
+fn test_negative_list_elements() {
+    assert_eq!(&serde_nix::to_string(&vec![-1, 2]).unwrap(), "[ (-1) 2 ]");
+    assert_eq!(&serde_nix::to_string(&(-1i8, 2u8)).unwrap(), "[ (-1) 2 ]");
+    assert_eq!(
+        &serde_nix::to_string(&vec![vec![-1], vec![]]).unwrap(),
+        "[ [ (-1) ] [ ] ]",
+    );
+    assert_eq!(
+        &serde_nix::to_string(&vec![Some(-1), None]).unwrap(),
+        "[ (-1) null ]",
+    );
+    // only list elements need the parentheses
+    assert_eq!(&serde_nix::to_string(&-1).unwrap(), "-1");
+    let mut m = HashMap::new();
+    m.insert("a", vec![-1]);
+    assert_eq!(&serde_nix::to_string(&m).unwrap(), "{ a = [ (-1) ]; }");
+    let mut m = HashMap::new();
+    m.insert("a", -1);
+    assert_eq!(&serde_nix::to_string(&vec![m]).unwrap(), "[ { a = -1; } ]");
+    assert_eq!(
+        &serde_nix::to_string(&vec![Shape::Circle(1), Shape::Move(-1, -2)]).unwrap(),
+        "[ { Circle = 1; } { Move = [ (-1) (-2) ]; } ]",
+    );
+    assert_eq!(
+        serde_nix::to_string_pretty(&vec![-1]).unwrap(),
+        "[\n  (-1)\n]",
+    );
+}
 
-    let json_str = String::from_utf8(json.stdout).unwrap();
-    let obj: T = serde_json::from_str(&json_str).unwrap();
+#[test]
+fn test_floats() {
+    for (f, expected) in [
+        (1.0, "1.0"),
+        (0.0, "0.0"),
+        (-1.0, "-1.0"),
+        (1.5, "1.5"),
+        (0.1, "0.1"),
+        (100.0, "100.0"),
+        (0.00001, "0.00001"),
+        (1e-7, "1.0e-7"),
+        (1.5e-7, "1.5e-7"),
+        (1e15, "1000000000000000.0"),
+        (1e16, "1.0e16"),
+        (f64::MAX, "1.7976931348623157e308"),
+        (f64::MIN_POSITIVE, "2.2250738585072014e-308"),
+    ] {
+        assert_eq!(serde_nix::to_string(&f).unwrap(), expected);
+    }
+    assert_eq!(serde_nix::to_string(&0.1f32).unwrap(), "0.1");
+    assert_eq!(serde_nix::to_string(&1e20f32).unwrap(), "1.0e20");
+    assert_eq!(
+        serde_nix::to_string(&vec![-0.5, 0.5]).unwrap(),
+        "[ (-0.5) 0.5 ]"
+    );
 
-    assert_eq!(v, obj);
+    for f in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        assert!(matches!(
+            serde_nix::to_string(&f),
+            Err(Error::NonFiniteFloat(_))
+        ));
+    }
+    assert!(matches!(
+        serde_nix::to_string(&f32::NAN),
+        Err(Error::NonFiniteFloat(_))
+    ));
 
-    Ok(())
+    let mut out = Vec::new();
+    let mut ser = serde_nix::ser::Serializer::new(&mut out)
+        .non_finite_floats(serde_nix::ser::NonFiniteFloats::Null);
+    vec![1.0, f64::NAN, -f64::INFINITY]
+        .serialize(&mut ser)
+        .unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "[ 1.0 null null ]");
 }
 
 #[test]
-fn test_round_trip_through_nix() {
-    round_trip(1).unwrap();
-    round_trip(vec![1, 2, 3, 4]).unwrap();
-    round_trip("foo".to_string()).unwrap();
-    round_trip(Person {
-        age: 10,
-        name: "foo".to_string(),
-    })
-    .unwrap();
-    round_trip((1, 2, 3)).unwrap();
+fn test_integer_range() {
+    use serde_nix::ser::{IntegerOverflow, Serializer};
 
-    round_trip("if".to_string()).unwrap();
-    round_trip(vec!["if".to_string()]).unwrap();
-    let m = {
-        let mut m = HashMap::new();
-        // https://github.com/NixOS/nix/blob/master/src/libexpr/lexer.l#L109-L118
-        for kw in vec![
-            "if", "then", "else", "assert", "with", "let", "in", "rec", "inherit", "or",
-        ] {
-            m.insert(kw.to_string(), kw.to_string());
+    assert_eq!(
+        serde_nix::to_string(&i64::MAX).unwrap(),
+        "9223372036854775807"
+    );
+    assert_eq!(
+        serde_nix::to_string(&i64::MIN).unwrap(),
+        "-9223372036854775807 - 1"
+    );
+    assert_eq!(
+        serde_nix::to_string(&vec![i64::MIN]).unwrap(),
+        "[ (-9223372036854775807 - 1) ]"
+    );
+    assert_eq!(
+        serde_nix::to_string(&(i64::MAX as u64)).unwrap(),
+        "9223372036854775807"
+    );
+    assert_eq!(serde_nix::to_string(&-5i128).unwrap(), "-5");
+    assert_eq!(serde_nix::to_string(&5u128).unwrap(), "5");
+
+    for result in [
+        serde_nix::to_string(&u64::MAX),
+        serde_nix::to_string(&(i64::MAX as i128 + 1)),
+        serde_nix::to_string(&(i64::MIN as i128 - 1)),
+        serde_nix::to_string(&u128::MAX),
+    ] {
+        assert!(matches!(result, Err(Error::IntegerOutOfRange(_))));
+    }
+    assert_eq!(
+        serde_nix::to_string(&u64::MAX).unwrap_err().to_string(),
+        "18446744073709551615 does not fit in a nix integer",
+    );
+
+    let values = (u64::MAX, i128::MIN, 1u128);
+    let mut out = Vec::new();
+    let mut ser = Serializer::new(&mut out).integer_overflow(IntegerOverflow::String);
+    values.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        r#"[ "18446744073709551615" "-170141183460469231731687303715884105728" 1 ]"#,
+    );
+
+    let mut out = Vec::new();
+    let mut ser = Serializer::new(&mut out).integer_overflow(IntegerOverflow::Float);
+    values.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "[ 1.8446744073709552e19 (-1.7014118346046923e38) 1 ]",
+    );
+}
+
+#[test]
+fn test_bytes() {
+    use serde_bytes::Bytes as ByteSlice;
+    use serde_nix::ser::Bytes;
+
+    assert_eq!(
+        serde_nix::to_string(ByteSlice::new(b"hi")).unwrap(),
+        "[ 104 105 ]"
+    );
+    assert_eq!(
+        to_strings!(ByteSlice::new(b"hi"), |ser| ser.bytes(Bytes::Utf8)).unwrap()[0],
+        r#""hi""#
+    );
+    assert_eq!(
+        to_strings!(ByteSlice::new(b"hi"), |ser| ser.bytes(Bytes::Base64)).unwrap()[0],
+        r#""aGk=""#
+    );
+    assert_eq!(
+        to_strings!(ByteSlice::new(b"hi!"), |ser| ser.bytes(Bytes::Base64)).unwrap()[0],
+        r#""aGkh""#
+    );
+    assert_eq!(
+        to_strings!(ByteSlice::new(b"h"), |ser| ser.bytes(Bytes::Base64)).unwrap()[0],
+        r#""aA==""#
+    );
+    assert_eq!(
+        to_strings!(ByteSlice::new(b""), |ser| ser.bytes(Bytes::Base64)).unwrap()[0],
+        r#""""#
+    );
+    assert_eq!(
+        to_strings!(ByteSlice::new(b"\x00\xff"), |ser| ser.bytes(Bytes::Hex)).unwrap()[0],
+        r#""00ff""#
+    );
+    assert_eq!(
+        to_strings!(ByteSlice::new(&[0; 32]), |ser| ser.bytes(Bytes::Sri)).unwrap()[0],
+        r#""sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=""#,
+    );
+    let [sha512, _] = to_strings!(ByteSlice::new(&[0; 64]), |ser| ser.bytes(Bytes::Sri)).unwrap();
+    assert!(sha512.starts_with(r#""sha512-"#));
+
+    assert!(matches!(
+        to_strings!(ByteSlice::new(b"hi\xff"), |ser| ser.bytes(Bytes::Utf8)),
+        Err(Error::InvalidUtf8(2))
+    ));
+    assert!(matches!(
+        to_strings!(ByteSlice::new(b"\0"), |ser| ser.bytes(Bytes::Utf8)),
+        Err(Error::UnencodableNullString(_))
+    ));
+    assert!(matches!(
+        to_strings!(ByteSlice::new(b"hi"), |ser| ser.bytes(Bytes::Sri)),
+        Err(Error::NotAHash(2))
+    ));
+
+    // deduplicating goes through a Value, which encodes them the same way
+    let keys = vec![vec![ByteSlice::new(b"hi")], vec![ByteSlice::new(b"hi")]];
+    assert_eq!(
+        to_strings!(|ser| ser.bytes(Bytes::Hex), write: |ser| ser
+            .serialize_deduplicated(&keys, 2))
+        .unwrap()[0],
+        r#"let v0 = [ "6869" ]; in [ v0 v0 ]"#,
+    );
+}
+
+// What nix makes of a double quoted string literal, following its lexer rather than this crate's
+// parser: only `\n`, `\t` and `\r` are escapes, a backslash before anything else stands for that
+// character, a raw `\r` (or `\r\n`) is read as `\n` and a null ends the string.
+// https://github.com/NixOS/nix/blob/2.18.1/src/libexpr/lexer.l
+fn nix_string_literal(literal: &str) -> String {
+    let body = literal
+        .strip_prefix('"')
+        .and_then(|body| body.strip_suffix('"'))
+        .unwrap_or_else(|| panic!("not a string literal: {:?}", literal));
+    let mut chars = body.chars().peekable();
+    let mut s = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\0' => break,
+            '\\' => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some(c) => s.push(c),
+                None => panic!("escaped closing quote in {:?}", literal),
+            },
+            '\r' => {
+                s.push('\n');
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+            }
+            '"' => panic!("unescaped quote in {:?}", literal),
+            '$' => {
+                s.push('$');
+                // `$` is read together with anything after it but `{`, `"` or `\`, so `$${` isn't
+                // an interpolation.
+                match chars.peek() {
+                    Some('{') => panic!("interpolation in {:?}", literal),
+                    Some('"') | Some('\\') | None => {}
+                    Some(_) => s.push(chars.next().unwrap()),
+                }
+            }
+            c => s.push(c),
         }
-        m
-    };
-    round_trip(m).unwrap();
+    }
+    s
+}
+
+// Every character but null, followed by the ones that need care next to each other.
+fn all_chars() -> String {
+    let mut s: String = (1..=char::MAX as u32).filter_map(char::from_u32).collect();
+    s += "$${ ''${ '''' \r\n \\${ $\\ $";
+    s
+}
+
+#[test]
+fn test_strings_against_reference() {
+    let s = all_chars();
+    assert_eq!(nix_string_literal(&serde_nix::to_string(&s).unwrap()), s);
+    for nix in to_strings!(&s, |ser| ser.multiline_strings(MultilineStrings::Indented)).unwrap() {
+        assert_eq!(serde_nix::from_str::<String>(&nix).unwrap(), s);
+    }
+    let mut m = HashMap::new();
+    m.insert(s, 1);
+    let nix = serde_nix::to_string(&m).unwrap();
+    let parsed: HashMap<String, i32> = serde_nix::from_str(&nix).unwrap();
+    assert_eq!(parsed, m);
 }
 
 #[quickcheck]
-fn quickcheck_hashmap_keys(m: HashMap<String, String>) -> bool {
-    // nix can't do null keys, don't bother
-    if m.keys().chain(m.values()).any(|k| {
-        k.chars().any(|c| match c {
-            // nix doesn't deal with null, form feed, or backspace well
-            '\0' | '\u{c}' | '\u{8}' => true,
-            _ => false,
-        })
-    }) {
-        return true;
+fn quickcheck_strings_against_reference(s: String) -> bool {
+    use serde_nix::ser::{Serializer, UnrepresentableChars};
+
+    let mut ser = Serializer::new(Vec::new()).unrepresentable_chars(UnrepresentableChars::Replace);
+    s.serialize(&mut ser).unwrap();
+    let nix = String::from_utf8(ser.into_inner()).unwrap();
+    nix_string_literal(&nix) == s.replace('\0', "\u{fffd}")
+}
+
+#[test]
+fn test_unrepresentable_chars() {
+    use serde_nix::options::Nested;
+    use serde_nix::ser::UnrepresentableChars;
+    use serde_nix::{Commented, Path};
+
+    let mut hosts = HashMap::new();
+    hosts.insert("example.org", vec!["a", "b\0c"]);
+    let config = Nested::new(["services", "nginx", "virtualHosts"], hosts);
+    let err = to_strings!(&config, |ser| ser
+        .multiline_strings(MultilineStrings::Indented)
+        .unrepresentable_chars(UnrepresentableChars::Error))
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        r#"nix strings may not contain null bytes (at services.nginx.virtualHosts."example.org"[1])"#,
+    );
+    assert_eq!(
+        to_strings!(&config, |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Drop))
+        .unwrap()[0],
+        r#"{ services = { nginx = { virtualHosts = { "example.org" = [ "a" "bc" ]; }; }; }; }"#,
+    );
+    assert_eq!(
+        to_strings!(&config, |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Replace))
+        .unwrap()[0],
+        r#"{ services = { nginx = { virtualHosts = { "example.org" = [ "a" "b�c" ]; }; }; }; }"#,
+    );
+
+    let mut m = HashMap::new();
+    m.insert("a\0b", 1);
+    assert!(matches!(
+        to_strings!(&m, |ser| ser.multiline_strings(MultilineStrings::Indented).unrepresentable_chars(UnrepresentableChars::Error)),
+        Err(Error::UnencodableNullString(path)) if path == r#""a\0b""#
+    ));
+    assert_eq!(
+        to_strings!(&m, |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Drop))
+        .unwrap()[0],
+        "{ ab = 1; }"
+    );
+    assert_eq!(
+        to_strings!(&m, |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Replace))
+        .unwrap()[0],
+        r#"{ "a�b" = 1; }"#
+    );
+
+    assert!(matches!(
+        to_strings!(&"\0", |ser| ser.multiline_strings(MultilineStrings::Indented).unrepresentable_chars(UnrepresentableChars::Error)),
+        Err(Error::UnencodableNullString(path)) if path.is_empty()
+    ));
+    assert_eq!(
+        to_strings!(&"a\n\0", |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Drop))
+        .unwrap()[0],
+        "''\na\n''"
+    );
+    assert_eq!(
+        to_strings!(&Path("/a\0b"), |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Drop))
+        .unwrap()[0],
+        "/ab"
+    );
+    assert_eq!(
+        to_strings!(&Path("/a\0b"), |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Replace))
+        .unwrap()[0],
+        r#"/. + "/a�b""#
+    );
+    assert_eq!(
+        to_strings!(&vec![Commented::new("a\0", 1)], |ser| ser
+            .multiline_strings(MultilineStrings::Indented)
+            .unrepresentable_chars(UnrepresentableChars::Drop))
+        .unwrap()[0],
+        "[ /* a */ 1 ]"
+    );
+    // values converted for deduplication get them dealt with the same way
+    let named = (&m, Path("/a\0b"));
+    for policy in [UnrepresentableChars::Drop, UnrepresentableChars::Replace] {
+        assert_eq!(
+            to_strings!(|ser| ser.unrepresentable_chars(policy), write: |ser| ser
+                .serialize_deduplicated(&named, 2))
+            .unwrap(),
+            to_strings!(&named, |ser| ser.unrepresentable_chars(policy)).unwrap(),
+        );
+    }
+
+    #[derive(Serialize)]
+    enum Greeting {
+        Hello(String),
+    }
+    assert!(matches!(
+        to_strings!(&Greeting::Hello("\0".to_string()), |ser| ser.multiline_strings(MultilineStrings::Indented).unrepresentable_chars(UnrepresentableChars::Error)),
+        Err(Error::UnencodableNullString(path)) if path == "Hello"
+    ));
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+enum Shape {
+    Empty,
+    Circle(u32),
+    Move(i32, i32),
+    Point { x: i32, y: i32 },
+}
+
+#[test]
+fn test_enums() {
+    assert_eq!(&serde_nix::to_string(&Shape::Empty).unwrap(), r#""Empty""#);
+    assert_eq!(
+        &serde_nix::to_string(&Shape::Circle(3)).unwrap(),
+        "{ Circle = 3; }",
+    );
+    assert_eq!(
+        &serde_nix::to_string(&Shape::Move(1, 2)).unwrap(),
+        "{ Move = [ 1 2 ]; }",
+    );
+    assert_eq!(
+        &serde_nix::to_string(&Shape::Point { x: 1, y: 2 }).unwrap(),
+        "{ Point = { x = 1; y = 2; }; }",
+    );
+    assert_eq!(
+        &serde_nix::to_string(&vec![Shape::Move(1, 2), Shape::Point { x: 3, y: 4 }]).unwrap(),
+        "[ { Move = [ 1 2 ]; } { Point = { x = 3; y = 4; }; } ]",
+    );
+}
+
+#[derive(Serialize)]
+struct Service {
+    name: String,
+    ports: Vec<u16>,
+    env: Vec<(String, String)>,
+    owner: Person,
+    tags: Vec<String>,
+    shape: Shape,
+}
+
+#[test]
+fn test_pretty() {
+    let s = Service {
+        name: "nginx".to_string(),
+        ports: vec![80, 443],
+        env: vec![("A".to_string(), "b".to_string())],
+        owner: Person {
+            name: "foo".to_string(),
+            age: 20,
+        },
+        tags: vec![],
+        shape: Shape::Point { x: 1, y: 2 },
+    };
+    assert_eq!(
+        serde_nix::to_string_pretty(&s).unwrap(),
+        r#"{
+  name = "nginx";
+  ports = [
+    80
+    443
+  ];
+  env = [
+    [
+      "A"
+      "b"
+    ]
+  ];
+  owner = {
+    name = "foo";
+    age = 20;
+  };
+  tags = [ ];
+  shape = {
+    Point = {
+      x = 1;
+      y = 2;
+    };
+  };
+}"#,
+    );
+
+    assert_eq!(
+        serde_nix::to_string_pretty(&HashMap::<String, ()>::new()).unwrap(),
+        "{ }",
+    );
+    assert_eq!(serde_nix::to_string_pretty(&1).unwrap(), "1");
+
+    let mut out = Vec::new();
+    let mut ser = serde_nix::ser::Serializer::with_indent(&mut out, 4);
+    vec![Shape::Move(1, 2)].serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        r#"[
+    {
+        Move = [
+            1
+            2
+        ];
+    }
+]"#,
+    );
+}
+
+// Lays attrsets out one attribute per line but keeps lists on a single line, with no padding inside
+// the brackets.
+struct TightListFormatter {
+    depth: usize,
+}
+
+impl serde_nix::ser::Formatter for TightListFormatter {
+    fn begin_attrs<W>(&mut self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        self.depth += 1;
+        writer.write_all(b"{")
+    }
+
+    fn end_attrs<W>(&mut self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        self.depth -= 1;
+        write!(writer, "\n{}}}", "\t".repeat(self.depth))
+    }
+
+    fn begin_attr_key<W>(&mut self, writer: &mut W, _first: bool) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        write!(writer, "\n{}", "\t".repeat(self.depth))
+    }
+
+    fn end_attr_value<W>(&mut self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        writer.write_all(b";")
+    }
+
+    fn begin_list<W>(&mut self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        writer.write_all(b"[")
+    }
+
+    fn begin_list_value<W>(&mut self, writer: &mut W, first: bool) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        if !first {
+            writer.write_all(b" ")?;
+        }
+        Ok(())
+    }
+
+    fn end_list_value<W>(&mut self, _writer: &mut W) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_custom_formatter() {
+    let mut out = Vec::new();
+    let mut ser =
+        serde_nix::ser::Serializer::with_formatter(&mut out, TightListFormatter { depth: 0 });
+    Shape::Point { x: 1, y: 2 }.serialize(&mut ser).unwrap();
+    (vec![1, 2, 3], Person::default())
+        .serialize(&mut ser)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "{\n\tPoint = {\n\t\tx = 1;\n\t\ty = 2;\n\t};\n}[[1 2 3] {\n\tname = \"\";\n\tage = 0;\n}]",
+    );
+}
+
+#[derive(Serialize)]
+struct Unit {
+    description: String,
+    script: serde_nix::IndentedString<&'static str>,
+}
+
+#[test]
+fn test_indented_strings() {
+    let unit = Unit {
+        description: "a\nb".to_string(),
+        script: serde_nix::IndentedString("set -e\n\nif true; then\n  echo '${HOME}'\nfi\n"),
+    };
+    assert_eq!(
+        serde_nix::to_string_pretty(&unit).unwrap(),
+        r#"{
+  description = "a\nb";
+  script = ''
+    set -e
+
+    if true; then
+      echo ''\'''${HOME}'
+    fi
+  '';
+}"#,
+    );
+    assert_eq!(
+        to_strings!(&unit, |ser| ser
+            .multiline_strings(MultilineStrings::Indented))
+        .unwrap()[1],
+        r#"{
+  description = ''
+    a
+    b'';
+  script = ''
+    set -e
+
+    if true; then
+      echo ''\'''${HOME}'
+    fi
+  '';
+}"#,
+    );
+    assert_eq!(
+        to_strings!(&vec!["  a\n  b\n", "x"], |ser| ser
+            .multiline_strings(MultilineStrings::Indented))
+        .unwrap()[0],
+        "[ ''\n''\\  a\n  b\n'' \"x\" ]",
+    );
+
+    for s in [
+        "",
+        "\n",
+        "a\n  ",
+        "  \n",
+        "  a\n    b",
+        "'",
+        "''",
+        "'''",
+        "a'\nb'",
+        "'${x}",
+        "$${x} $$${x} $",
+        "''${x}",
+        "a\r\nb\r\n",
+        "\ttab\n  \n",
+        "'\r",
+    ] {
+        for nix in to_strings!(&vec![vec![s]], |ser| ser
+            .multiline_strings(MultilineStrings::Indented))
+        .unwrap()
+        {
+            let parsed: Vec<Vec<String>> = serde_nix::from_str(&nix).unwrap();
+            assert_eq!(parsed, vec![vec![s]], "{}", nix);
+        }
+        let nix = serde_nix::to_string(&serde_nix::IndentedString(s)).unwrap();
+        assert_eq!(serde_nix::from_str::<String>(&nix).unwrap(), s, "{}", nix);
+    }
+}
+
+#[test]
+fn test_indented_strings_through_nix() {
+    for s in ["a\n  b\n", "  x\n  y", "'${x}'\n''", "a\n   "] {
+        let [compact, pretty] = to_strings!(&vec![s], |ser| ser
+            .multiline_strings(MultilineStrings::Indented))
+        .unwrap();
+        let quoted = serde_nix::to_string(&vec![s]).unwrap();
+        for nix in [compact, pretty] {
+            assert_eq!(
+                nix_eval(&format!("{} == {}", nix, quoted)),
+                "true",
+                "{}",
+                nix
+            );
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Host {
+    #[serde(with = "serde_nix::path")]
+    config: std::path::PathBuf,
+    extra: Vec<serde_nix::Path<String>>,
+}
+
+#[test]
+fn test_paths() {
+    let host = Host {
+        config: "./hardware-configuration.nix".into(),
+        extra: vec![
+            serde_nix::Path("/etc/nixos/secrets".to_string()),
+            serde_nix::Path("/mnt/My Files".to_string()),
+            serde_nix::Path("relative".to_string()),
+        ],
+    };
+    let nix = serde_nix::to_string(&host).unwrap();
+    assert_eq!(
+        nix,
+        r#"{ config = ./hardware-configuration.nix; extra = [ /etc/nixos/secrets (/. + "/mnt/My Files") ./relative ]; }"#,
+    );
+    assert_eq!(
+        serde_nix::to_string_pretty(&serde_nix::Path("/a b")).unwrap(),
+        r#"/. + "/a b""#,
+    );
+    assert!(matches!(
+        serde_nix::to_string(&serde_nix::Path("~/My Files")),
+        Err(Error::InvalidPath(_))
+    ));
+
+    // other serializers see a string
+    assert_eq!(
+        serde_json::to_string(&host).unwrap(),
+        r#"{"config":"./hardware-configuration.nix","extra":["/etc/nixos/secrets","/mnt/My Files","relative"]}"#,
+    );
+}
+
+#[test]
+fn test_paths_through_nix() {
+    for path in ["/etc/nixos", "/mnt/My Files", "/", "/a/${b}\"c"] {
+        let nix = serde_nix::to_string(&vec![serde_nix::Path(path)]).unwrap();
+        assert_eq!(
+            nix_eval(&format!(
+                "map (p: [ (builtins.typeOf p) (toString p) ]) {}",
+                nix
+            )),
+            format!("[ [ \"path\" {} ] ]", serde_nix::to_string(&path).unwrap()),
+            "{}",
+            nix
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct Packages {
+    environment: Vec<serde_nix::RawExpr>,
+    default: serde_nix::RawExpr,
+}
+
+#[test]
+fn test_raw_exprs() {
+    use serde_nix::RawExpr;
+
+    let packages = Packages {
+        environment: vec![
+            RawExpr::new("pkgs.hello"),
+            RawExpr::new("lib.mkForce true"),
+            RawExpr::new("import ./foo.nix"),
+            RawExpr::new("(x: x) 1"),
+            RawExpr::new("-1"),
+            RawExpr::new("{ a = 1; }"),
+        ],
+        default: RawExpr::new("lib.mkForce true"),
+    };
+    assert_eq!(
+        serde_nix::to_string(&packages).unwrap(),
+        "{ environment = [ pkgs.hello (lib.mkForce true) (import ./foo.nix) ((x: x) 1) (-1) { a = 1; } ]; default = lib.mkForce true; }",
+    );
+    assert_eq!(
+        serde_nix::to_string_pretty(&packages.environment[..2]).unwrap(),
+        "[\n  pkgs.hello\n  (lib.mkForce true)\n]",
+    );
+
+    // text that doesn't parse is trusted, but kept apart from its neighbours
+    assert_eq!(
+        serde_nix::to_string(&vec![RawExpr::new("a b c ("), RawExpr::new("a")]).unwrap(),
+        "[ (a b c () a ]",
+    );
+
+    assert_eq!(
+        RawExpr::validated("lib.mkIf cfg.enable { }")
+            .unwrap()
+            .as_str(),
+        "lib.mkIf cfg.enable { }"
+    );
+    for bad in [
+        "1; b = 2",
+        "",
+        "a)",
+        "pkgs.hello # pinned",
+        "pkgs.hello /* pinned */",
+    ] {
+        assert!(
+            matches!(RawExpr::validated(bad), Err(Error::Syntax { .. })),
+            "{:?}",
+            bad
+        );
+    }
+    // comments in front are fine
+    RawExpr::validated("# the default\npkgs.hello").unwrap();
+
+    // other serializers see a struct
+    assert_eq!(
+        serde_json::to_string(&RawExpr::new("pkgs.hello")).unwrap(),
+        r#"{"$serde_nix::private::RawExpr":"pkgs.hello"}"#,
+    );
+}
+
+#[test]
+fn test_sorted_attrs() {
+    use serde_nix::ser::{AttrOrder, Serializer};
+
+    let keys = ["foo", "bar", "Baz", "a b", "a-b", "_", "ab"];
+    let m: HashMap<_, _> = keys.iter().zip(0..).collect();
+    assert_eq!(
+        to_strings!(&m, |ser| ser.attr_order(AttrOrder::Sorted)).unwrap()[0],
+        r#"{ Baz = 2; _ = 5; "a b" = 3; a-b = 4; ab = 6; bar = 1; foo = 0; }"#,
+    );
+
+    // structs, nested attrsets and variants are sorted too
+    #[derive(Serialize)]
+    struct Listener {
+        port: u16,
+        addresses: Vec<&'static str>,
+        options: Option<HashMap<&'static str, bool>>,
+    }
+    let listeners = vec![
+        Listener {
+            port: 80,
+            addresses: vec!["0.0.0.0", "::"],
+            options: None,
+        },
+        Listener {
+            port: 443,
+            addresses: vec![],
+            options: Some(HashMap::new()),
+        },
+    ];
+    assert_eq!(
+        to_strings!(&listeners, |ser| ser.attr_order(AttrOrder::Sorted)).unwrap(),
+        [
+            r#"[ { addresses = [ "0.0.0.0" "::" ]; options = null; port = 80; } { addresses = [ ]; options = { }; port = 443; } ]"#,
+            r#"[
+  {
+    addresses = [
+      "0.0.0.0"
+      "::"
+    ];
+    options = null;
+    port = 80;
+  }
+  {
+    addresses = [ ];
+    options = { };
+    port = 443;
+  }
+]"#,
+        ]
+    );
+    assert_eq!(
+        to_strings!(&Shape::Point { y: 1, x: 2 }, |ser| ser
+            .attr_order(AttrOrder::Sorted))
+        .unwrap()[0],
+        "{ Point = { x = 2; y = 1; }; }",
+    );
+    assert_eq!(
+        to_strings!(&Person::default(), |ser| ser.attr_order(AttrOrder::Sorted)).unwrap()[1],
+        "{\n  age = 0;\n  name = \"\";\n}",
+    );
+
+    // a value that fails leaves nothing behind
+    let mut out = Vec::new();
+    let mut ser = Serializer::new(&mut out).attr_order(AttrOrder::Sorted);
+    let mut m = HashMap::new();
+    m.insert("a", f64::NAN);
+    assert!(matches!(
+        m.serialize(&mut ser),
+        Err(Error::NonFiniteFloat(_))
+    ));
+    assert_eq!(String::from_utf8(out).unwrap(), "{ ");
+}
+
+#[test]
+fn test_sorted_attrs_through_nix() {
+    let keys = ["foo", "bar", "Baz", "_", "a-b", "ab", "a'"];
+    let m: HashMap<_, _> = keys.iter().zip(0..).collect();
+    let nix = &to_strings!(&m, |ser| ser.attr_order(AttrOrder::Sorted)).unwrap()[0];
+    assert_eq!(&nix_eval(nix), nix);
+}
+
+#[derive(Serialize)]
+struct NixosConfig {
+    services: Services,
+    networking: Networking,
+}
+
+#[derive(Serialize)]
+struct Services {
+    nginx: Nginx,
+}
+
+#[derive(Serialize)]
+struct Nginx {
+    enable: bool,
+    #[serde(rename = "virtualHosts")]
+    virtual_hosts: HashMap<String, HashMap<String, bool>>,
+}
+
+#[derive(Serialize)]
+struct Networking {
+    #[serde(rename = "hostName")]
+    host_name: String,
+    firewall: HashMap<String, Vec<u16>>,
+}
+
+#[test]
+fn test_dotted_attr_paths() {
+    let mut vhost = HashMap::new();
+    vhost.insert("forceSSL".to_string(), true);
+    let mut virtual_hosts = HashMap::new();
+    virtual_hosts.insert("example.com".to_string(), vhost);
+    let mut firewall = HashMap::new();
+    firewall.insert("allowedTCPPorts".to_string(), vec![80, 443]);
+    let config = NixosConfig {
+        services: Services {
+            nginx: Nginx {
+                enable: true,
+                virtual_hosts,
+            },
+        },
+        networking: Networking {
+            host_name: "web".to_string(),
+            firewall,
+        },
+    };
+    assert_eq!(
+        to_strings!(&config, |ser| ser
+            .attr_paths(AttrPaths::Dotted { max_attrs: 1 }))
+        .unwrap(),
+        [
+            r#"{ services.nginx = { enable = true; virtualHosts."example.com".forceSSL = true; }; networking = { hostName = "web"; firewall.allowedTCPPorts = [ 80 443 ]; }; }"#,
+            r#"{
+  services.nginx = {
+    enable = true;
+    virtualHosts."example.com".forceSSL = true;
+  };
+  networking = {
+    hostName = "web";
+    firewall.allowedTCPPorts = [
+      80
+      443
+    ];
+  };
+}"#,
+        ]
+    );
+    assert_eq!(
+        to_strings!(&config, |ser| ser
+            .attr_paths(AttrPaths::Dotted { max_attrs: 2 }))
+        .unwrap()[0],
+        r#"{ services.nginx.enable = true; services.nginx.virtualHosts."example.com".forceSSL = true; networking.hostName = "web"; networking.firewall.allowedTCPPorts = [ 80 443 ]; }"#,
+    );
+
+    // empty attrsets, list elements, variants and raw expressions keep their braces
+    let mut m = HashMap::new();
+    m.insert("a", vec![HashMap::<&str, HashMap<&str, i32>>::new()]);
+    assert_eq!(
+        to_strings!(&m, |ser| ser.attr_paths(AttrPaths::Dotted { max_attrs: 1 })).unwrap()[0],
+        "{ a = [ { } ]; }"
+    );
+    m.get_mut("a").unwrap()[0].insert("b", HashMap::new());
+    assert_eq!(
+        to_strings!(&m, |ser| ser.attr_paths(AttrPaths::Dotted { max_attrs: 1 })).unwrap()[0],
+        "{ a = [ { b = { }; } ]; }"
+    );
+    let b = m.get_mut("a").unwrap()[0].get_mut("b").unwrap();
+    b.insert("c", 1);
+    assert_eq!(
+        to_strings!(&m, |ser| ser.attr_paths(AttrPaths::Dotted { max_attrs: 1 })).unwrap()[0],
+        "{ a = [ { b.c = 1; } ]; }"
+    );
+    let mut m = HashMap::new();
+    m.insert("shape", Shape::Point { x: 1, y: 2 });
+    assert_eq!(
+        to_strings!(&m, |ser| ser.attr_paths(AttrPaths::Dotted { max_attrs: 1 })).unwrap()[0],
+        "{ shape = { Point = { x = 1; y = 2; }; }; }"
+    );
+    let mut m = HashMap::new();
+    m.insert("a", serde_nix::RawExpr::new("{ b = 1; }"));
+    assert_eq!(
+        to_strings!(&m, |ser| ser.attr_paths(AttrPaths::Dotted { max_attrs: 1 })).unwrap()[0],
+        "{ a = { b = 1; }; }"
+    );
+
+    // with sorting, the paths are sorted by their first name
+    let mut inner = HashMap::new();
+    inner.insert("z", 1);
+    inner.insert("y", 2);
+    let mut m = HashMap::new();
+    m.insert("b", inner.clone());
+    m.insert("a-b", inner.clone());
+    m.insert("a", inner);
+    let mut ser = serde_nix::ser::Serializer::new(Vec::new())
+        .attr_order(serde_nix::ser::AttrOrder::Sorted)
+        .attr_paths(serde_nix::ser::AttrPaths::Dotted { max_attrs: 2 });
+    m.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "{ a.y = 2; a.z = 1; a-b.y = 2; a-b.z = 1; b.y = 2; b.z = 1; }"
+    );
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+struct Firewall {
+    enable: Option<bool>,
+    ports: Vec<Option<u16>>,
+    #[serde(rename = "logRefused")]
+    log_refused: serde_nix::OmitNull<Option<bool>>,
+    extra: Option<HashMap<String, Option<String>>>,
+}
+
+#[test]
+fn test_null_attrs() {
+    use serde_nix::ser::{AttrOrder, AttrPaths, NullAttrs, Serializer};
+    use serde_nix::OmitNull;
+
+    let firewall = Firewall {
+        enable: None,
+        ports: vec![Some(22), None],
+        log_refused: OmitNull(None),
+        extra: Some(HashMap::new()),
+    };
+    // the wrapper works on its own
+    assert_eq!(
+        serde_nix::to_string(&firewall).unwrap(),
+        "{ enable = null; ports = [ 22 null ]; extra = { }; }"
+    );
+    assert_eq!(serde_nix::to_string(&OmitNull(None::<u8>)).unwrap(), "null");
+
+    // list elements and top level values stay
+    let mut ser = Serializer::new(Vec::new()).null_attrs(NullAttrs::Omit);
+    firewall.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "{ ports = [ 22 null ]; extra = { }; }"
+    );
+    let mut ser = Serializer::new(Vec::new()).null_attrs(NullAttrs::Omit);
+    ().serialize(&mut ser).unwrap();
+    assert_eq!(String::from_utf8(ser.into_inner()).unwrap(), "null");
+
+    // an attrset that only had nulls in it is still there, and never becomes a dotted path
+    let mut extra = HashMap::new();
+    extra.insert("a".to_string(), None);
+    let firewall = Firewall {
+        enable: Some(true),
+        ports: vec![],
+        log_refused: OmitNull(Some(false)),
+        extra: Some(extra),
+    };
+    let mut ser = Serializer::pretty(Vec::new())
+        .null_attrs(NullAttrs::Omit)
+        .attr_paths(AttrPaths::Dotted { max_attrs: 1 })
+        .attr_order(AttrOrder::Sorted);
+    firewall.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "{\n  enable = true;\n  extra = { };\n  logRefused = false;\n  ports = [ ];\n}"
+    );
+
+    // missing attributes read back as None
+    let firewall: Firewall = serde_nix::from_str("{ ports = [ ]; }").unwrap();
+    assert_eq!(
+        firewall,
+        Firewall {
+            enable: None,
+            ports: vec![],
+            log_refused: OmitNull(None),
+            extra: None,
+        }
+    );
+}
+
+#[test]
+fn test_map_keys() {
+    use serde_nix::ser::{MapKeys, NullAttrs};
+    use std::collections::BTreeMap;
+
+    struct FloatKeys(Vec<(f64, i32)>);
+
+    impl Serialize for FloatKeys {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+        }
+    }
+
+    let mut ports = BTreeMap::new();
+    ports.insert(80u16, "http");
+    ports.insert(8080, "alt");
+    let mut flags = BTreeMap::new();
+    flags.insert(false, 0);
+    flags.insert(true, 1);
+    let mut routes = BTreeMap::new();
+    routes.insert(("a".to_string(), 1u8), 2);
+    routes.insert(("b".to_string(), 0), 3);
+
+    // scalars become strings
+    assert_eq!(
+        to_strings!(&ports, |ser| ser.map_keys(MapKeys::Stringify)).unwrap()[0],
+        r#"{ "80" = "http"; "8080" = "alt"; }"#
+    );
+    assert_eq!(
+        to_strings!(&flags, |ser| ser.map_keys(MapKeys::Stringify)).unwrap()[0],
+        "{ false = 0; true = 1; }"
+    );
+    assert_eq!(
+        to_strings!(&FloatKeys(vec![(1.0, 1), (-0.5, 2)]), |ser| ser
+            .map_keys(MapKeys::Stringify))
+        .unwrap()[0],
+        r#"{ "1.0" = 1; "-0.5" = 2; }"#
+    );
+    assert!(matches!(
+        to_strings!(&FloatKeys(vec![(f64::NAN, 1)]), |ser| ser
+            .map_keys(MapKeys::Stringify)),
+        Err(Error::NonFiniteFloat(_))
+    ));
+    for policy in [MapKeys::Error, MapKeys::Stringify] {
+        assert!(matches!(
+            to_strings!(&routes, |ser| ser.map_keys(policy)),
+            Err(Error::MapKeyMustBeAString)
+        ));
+    }
+    assert!(matches!(
+        to_strings!(&ports, |ser| ser.map_keys(MapKeys::Error)),
+        Err(Error::MapKeyMustBeAString)
+    ));
+
+    // and can make dotted paths
+    let mut services = HashMap::new();
+    services.insert("ports", &ports);
+    assert_eq!(
+        to_strings!(&services, |ser| ser
+            .map_keys(MapKeys::Stringify)
+            .attr_paths(AttrPaths::Dotted { max_attrs: 2 }))
+        .unwrap()[0],
+        r#"{ ports."80" = "http"; ports."8080" = "alt"; }"#
+    );
+
+    // any key goes in a list of pairs, while string keys keep their attrsets
+    assert_eq!(
+        to_strings!(&routes, |ser| ser.map_keys(MapKeys::Pairs)).unwrap()[0],
+        r#"[ { name = [ "a" 1 ]; value = 2; } { name = [ "b" 0 ]; value = 3; } ]"#
+    );
+    assert_eq!(
+        to_strings!(&services, |ser| ser.map_keys(MapKeys::Pairs)).unwrap()[0],
+        r#"{ ports = [ { name = 80; value = "http"; } { name = 8080; value = "alt"; } ]; }"#
+    );
+    assert_eq!(
+        to_strings!(&BTreeMap::<u8, u8>::new(), |ser| ser
+            .map_keys(MapKeys::Pairs))
+        .unwrap()[0],
+        "{ }"
+    );
+    let mut nulls = BTreeMap::new();
+    nulls.insert(1, None::<u8>);
+    assert_eq!(
+        to_strings!(&nulls, |ser| ser.map_keys(MapKeys::Pairs)).unwrap()[0],
+        "[ { name = 1; value = null; } ]"
+    );
+    // the keys after the first decide too
+    #[derive(Serialize, PartialEq, Eq, PartialOrd, Ord)]
+    enum Key {
+        A,
+        B(u8),
+    }
+    let mut mixed = BTreeMap::new();
+    mixed.insert(Key::A, vec![1]);
+    mixed.insert(Key::B(2), vec![3]);
+    assert_eq!(
+        to_strings!(&mixed, |ser| ser.map_keys(MapKeys::Pairs)).unwrap(),
+        [
+            r#"[ { name = "A"; value = [ 1 ]; } { name = { B = 2; }; value = [ 3 ]; } ]"#,
+            r#"[
+  {
+    name = "A";
+    value = [
+      1
+    ];
+  }
+  {
+    name = {
+      B = 2;
+    };
+    value = [
+      3
+    ];
+  }
+]"#
+        ]
+    );
+    let mut omitted = HashMap::new();
+    omitted.insert("a", None::<u8>);
+    assert_eq!(
+        to_strings!(&omitted, |ser| ser
+            .map_keys(MapKeys::Pairs)
+            .null_attrs(NullAttrs::Omit))
+        .unwrap(),
+        ["{ }", "{ }"]
+    );
+    omitted.insert("c", Some(1));
+    omitted.insert("b", Some(2));
+    assert_eq!(
+        to_strings!(&omitted, |ser| ser
+            .map_keys(MapKeys::Pairs)
+            .null_attrs(NullAttrs::Omit)
+            .attr_order(AttrOrder::Sorted))
+        .unwrap(),
+        ["{ b = 2; c = 1; }", "{\n  b = 2;\n  c = 1;\n}"]
+    );
+    let mut bad = BTreeMap::new();
+    bad.insert(1, vec!["ok", "\0"]);
+    assert_eq!(
+        to_strings!(&bad, |ser| ser.map_keys(MapKeys::Pairs))
+            .unwrap_err()
+            .to_string(),
+        "nix strings may not contain null bytes (at [0].value[1])"
+    );
+
+    assert_eq!(
+        to_strings!(&routes, |ser| ser.map_keys(MapKeys::Pairs)).unwrap()[1],
+        r#"[
+  {
+    name = [
+      "a"
+      1
+    ];
+    value = 2;
+  }
+  {
+    name = [
+      "b"
+      0
+    ];
+    value = 3;
+  }
+]"#
+    );
+
+    // and deduplicated values take the same keys
+    let hosts = vec![&ports, &ports];
+    assert_eq!(
+        to_strings!(|ser| ser.map_keys(MapKeys::Stringify), write: |ser| ser
+            .serialize_deduplicated(&hosts, 2))
+        .unwrap()[0],
+        r#"let v0 = { "80" = "http"; "8080" = "alt"; }; in [ v0 v0 ]"#
+    );
+    assert_eq!(
+        to_strings!(|ser| ser.map_keys(MapKeys::Pairs), write: |ser| ser
+            .serialize_deduplicated(&hosts, 2))
+        .unwrap()[0],
+        r#"let v0 = [ { name = 80; value = "http"; } { name = 8080; value = "alt"; } ]; in [ v0 v0 ]"#
+    );
+    let entries = (&mixed, &nulls, &omitted);
+    assert_eq!(
+        to_strings!(|ser| ser.map_keys(MapKeys::Pairs).null_attrs(NullAttrs::Omit), write: |ser| ser
+            .serialize_deduplicated(&entries, 100))
+        .unwrap(),
+        to_strings!(&entries, |ser| ser
+            .map_keys(MapKeys::Pairs)
+            .null_attrs(NullAttrs::Omit))
+        .unwrap(),
+    );
+
+    // each value is serialized once, however deep the maps are nested
+    struct Counted<'a>(&'a std::cell::Cell<usize>);
+
+    impl Serialize for Counted<'_> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.set(self.0.get() + 1);
+            serializer.serialize_unit()
+        }
+    }
+
+    let count = std::cell::Cell::new(0);
+    let mut nested = BTreeMap::new();
+    nested.insert(
+        "a",
+        BTreeMap::from([("b", BTreeMap::from([("c", Counted(&count))]))]),
+    );
+    Serializer::new(Vec::new())
+        .map_keys(MapKeys::Pairs)
+        .serialize_deduplicated(&nested, 2)
+        .unwrap();
+    assert_eq!(count.get(), 1);
+    count.set(0);
+    nested
+        .serialize(&mut Serializer::new(Vec::new()).map_keys(MapKeys::Pairs))
+        .unwrap();
+    assert_eq!(count.get(), 1);
+
+    // stringified keys read back as what they were
+    let [nix, _] = to_strings!(&ports, |ser| ser.map_keys(MapKeys::Stringify)).unwrap();
+    let parsed: BTreeMap<u16, String> = serde_nix::from_str(&nix).unwrap();
+    assert_eq!(parsed[&8080], "alt");
+    let [nix, _] = to_strings!(&flags, |ser| ser.map_keys(MapKeys::Stringify)).unwrap();
+    let parsed: BTreeMap<bool, i32> = serde_nix::from_str(&nix).unwrap();
+    assert_eq!(parsed, flags);
+}
+
+#[test]
+fn test_deduplicate() {
+    use serde_nix::ser::{IntegerOverflow, NonFiniteFloats, UnrepresentableChars};
+    use serde_nix::{nix, RawExpr};
+
+    let platforms = vec!["x86_64-linux", "aarch64-linux"];
+    let meta = nix!({
+        license = "mit";
+        platforms = platforms.clone();
+    });
+    let packages = nix!({
+        foo = {
+            version = 1;
+            meta = meta.clone();
+        };
+        bar = {
+            version = 2;
+            meta = meta.clone();
+        };
+        baz = [(platforms.clone())];
+    });
+    assert_eq!(
+        to_strings!(|ser| ser, write: |ser| ser.serialize_deduplicated(&packages, 3)).unwrap(),
+        [
+            r#"let v0 = { license = "mit"; platforms = v1; }; v1 = [ "x86_64-linux" "aarch64-linux" ]; in { foo = { version = 1; meta = v0; }; bar = { version = 2; meta = v0; }; baz = [ v1 ]; }"#,
+            r#"let
+  v0 = {
+    license = "mit";
+    platforms = v1;
+  };
+  v1 = [
+    "x86_64-linux"
+    "aarch64-linux"
+  ];
+in
+{
+  foo = {
+    version = 1;
+    meta = v0;
+  };
+  bar = {
+    version = 2;
+    meta = v0;
+  };
+  baz = [
+    v1
+  ];
+}"#,
+        ]
+    );
+    assert!(serde_nix::syntax::parse(
+        &to_strings!(|ser| ser, write: |ser| ser.serialize_deduplicated(&packages, 3)).unwrap()[1]
+    )
+    .is_ok());
+
+    // the list inside the repeated attrset only occurs once more, so it's too small on its own
+    assert_eq!(
+        serde_nix::to_string_deduplicated(&packages, 4).unwrap(),
+        r#"let v0 = { license = "mit"; platforms = [ "x86_64-linux" "aarch64-linux" ]; }; in { foo = { version = 1; meta = v0; }; bar = { version = 2; meta = v0; }; baz = [ [ "x86_64-linux" "aarch64-linux" ] ]; }"#,
+    );
+    // nothing big enough repeats
+    assert_eq!(
+        serde_nix::to_string_deduplicated(&packages, 6).unwrap(),
+        serde_nix::to_string(&packages).unwrap(),
+    );
+    // attribute order matters, and scalars are never bound
+    let value = nix!([ { a = 1; b = 2; } { b = 2; a = 1; } "long string" "long string" ]);
+    assert_eq!(
+        serde_nix::to_string_deduplicated(&value, 0).unwrap(),
+        serde_nix::to_string(&value).unwrap(),
+    );
+
+    // names used in raw expressions are skipped
+    let value = vec![
+        nix!([ 1 2 ]),
+        nix!([ 1 2 ]),
+        nix!([(RawExpr::new("v0 ++ v2"))]),
+        nix!([(RawExpr::new("v0 ++ v2"))]),
+        nix!([3]),
+        nix!([3]),
+    ];
+    assert_eq!(
+        serde_nix::to_string_deduplicated(&value, 2).unwrap(),
+        "let v1 = [ 1 2 ]; v3 = [ (v0 ++ v2) ]; v4 = [ 3 ]; in [ v1 v1 v3 v3 v4 v4 ]",
+    );
+
+    assert!(matches!(
+        serde_nix::to_string_deduplicated(&vec![f64::NAN], 2),
+        Err(Error::NonFiniteFloat(_))
+    ));
+
+    // the settings for what values become apply before deduplicating
+    let overflow = vec![vec![u64::MAX]];
+    assert_eq!(
+        to_strings!(|ser| ser.integer_overflow(IntegerOverflow::String), write: |ser| ser
+            .serialize_deduplicated(&overflow, 2))
+        .unwrap(),
+        to_strings!(&overflow, |ser| ser
+            .integer_overflow(IntegerOverflow::String))
+        .unwrap(),
+    );
+    let nan = vec![vec![f64::NAN]];
+    assert_eq!(
+        to_strings!(|ser| ser.non_finite_floats(NonFiniteFloats::Null), write: |ser| ser
+            .serialize_deduplicated(&nan, 2))
+        .unwrap(),
+        to_strings!(&nan, |ser| ser.non_finite_floats(NonFiniteFloats::Null)).unwrap(),
+    );
+    let nul = vec![vec!["a\0b"]];
+    assert_eq!(
+        to_strings!(|ser| ser.unrepresentable_chars(UnrepresentableChars::Replace), write: |ser| ser
+            .serialize_deduplicated(&nul, 2))
+        .unwrap(),
+        to_strings!(&nul, |ser| ser.unrepresentable_chars(UnrepresentableChars::Replace)).unwrap(),
+    );
+}
+
+#[test]
+fn test_deduplicate_through_nix() {
+    let tags = vec!["a", "b", "c"];
+    let value = serde_nix::nix!({
+        x = [(tags.clone())(tags.clone())];
+        y.z = [(tags.clone())];
+    });
+    let to_json = |nix: String| nix_eval(&format!("builtins.toJSON ({})", nix));
+    assert_eq!(
+        to_json(serde_nix::to_string_deduplicated(&value, 2).unwrap()),
+        to_json(serde_nix::to_string(&value).unwrap()),
+    );
+}
+
+#[derive(Serialize)]
+struct CommentedService {
+    enable: serde_nix::Commented<bool>,
+    ports: Vec<serde_nix::Commented<i32>>,
+    user: serde_nix::Commented<Option<String>>,
+}
+
+#[test]
+fn test_comments() {
+    use serde_nix::ser::{AttrOrder, NullAttrs, Serializer};
+    use serde_nix::{Commented, Value};
+
+    let service = CommentedService {
+        enable: Commented::new("managed by deploy.sh\ndo not edit", true),
+        ports: vec![Commented::new("http", 80), Commented::new("", -443)],
+        user: Commented::new("*/ oops /*", None),
+    };
+    let mut pretty = Serializer::pretty(Vec::new());
+    service.serialize(&mut pretty).unwrap();
+    let pretty = String::from_utf8(pretty.into_inner()).unwrap();
+    assert_eq!(
+        pretty,
+        r#"{
+  # managed by deploy.sh
+  # do not edit
+  enable = true;
+  ports = [
+    # http
+    80
+    (-443)
+  ];
+  # */ oops /*
+  user = null;
+}"#,
+    );
+    let compact = serde_nix::to_string(&service).unwrap();
+    assert_eq!(
+        compact,
+        "{ /* managed by deploy.sh do not edit */ enable = true; ports = [ /* http */ 80 (-443) ]; /* * / oops /* */ user = null; }",
+    );
+    // comments don't change the value
+    let plain = r#"{ enable = true; ports = [ 80 (-443) ]; user = null; }"#;
+    let plain: Value = serde_nix::from_str(plain).unwrap();
+    assert_eq!(serde_nix::from_str::<Value>(&pretty).unwrap(), plain);
+    assert_eq!(serde_nix::from_str::<Value>(&compact).unwrap(), plain);
+    assert_eq!(serde_nix::to_value(&service).unwrap(), plain);
+
+    // every kind of line break starts a new line comment
+    let value = Commented::new("a\r\nb\rc\n\nd\n", 1);
+    let mut ser = Serializer::pretty(Vec::new());
+    value.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "# a\n# b\n# c\n#\n# d\n1",
+    );
+    assert_eq!(serde_nix::from_str::<i32>("# a\r1").unwrap(), 1);
+    assert_eq!(serde_nix::to_string(&value).unwrap(), "/* a b c  d */ 1");
+
+    // comments stay with their attribute when it's moved, and go when it's left out
+    let mut ser = Serializer::pretty(Vec::new())
+        .attr_order(AttrOrder::Sorted)
+        .null_attrs(NullAttrs::Omit);
+    service.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "{\n  # managed by deploy.sh\n  # do not edit\n  enable = true;\n  ports = [\n    # http\n    80\n    (-443)\n  ];\n}",
+    );
+
+    // a header goes before everything, including a let
+    let mut ser = Serializer::pretty(Vec::new()).header("Generated by test_comments.");
+    ser.serialize_deduplicated(&vec![vec![1, 2], vec![1, 2]], 2)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "# Generated by test_comments.\nlet\n  v0 = [\n    1\n    2\n  ];\nin\n[\n  v0\n  v0\n]",
+    );
+    let mut ser = Serializer::new(Vec::new()).header("*/");
+    Commented::new("x", Some(1)).serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "/* * / */ /* x */ 1"
+    );
+
+    assert_eq!(
+        serde_json::to_string(&Commented::new("c", 1)).unwrap(),
+        r#"{"$serde_nix::private::Commented":"c","value":1}"#,
+    );
+}
+
+#[test]
+fn test_modules() {
+    use serde_nix::ser::{AttrPaths, MapKeys, Serializer};
+    use serde_nix::{nix, Commented, Module, RawExpr, Value};
+    use std::collections::BTreeMap;
+
+    let person = Person {
+        name: "foo".to_string(),
+        age: 20,
+    };
+    assert_eq!(
+        serde_nix::to_module_string(&person, &Module::new()).unwrap(),
+        r#"{ config, lib, pkgs, ... }: { name = "foo"; age = 20; }"#,
+    );
+    let empty = HashMap::<String, u8>::new();
+    assert_eq!(
+        serde_nix::to_module_string(&empty, &Module::new().args(None::<&str>)).unwrap(),
+        "{ ... }: { }",
+    );
+
+    let module = Module::new()
+        .import(Value::Path("./hardware-configuration.nix".to_string()))
+        .import(RawExpr::new("inputs.foo.nixosModules.default"));
+    let pretty = serde_nix::to_module_string_pretty(&person, &module).unwrap();
+    assert_eq!(
+        pretty,
+        r#"{ config, lib, pkgs, ... }:
+{
+  imports = [
+    ./hardware-configuration.nix
+    inputs.foo.nixosModules.default
+  ];
+  name = "foo";
+  age = 20;
+}"#,
+    );
+    assert!(serde_nix::syntax::parse(&pretty).is_ok());
+    assert_eq!(
+        serde_nix::to_module_string(&empty, &module).unwrap(),
+        "{ config, lib, pkgs, ... }: { imports = [ ./hardware-configuration.nix inputs.foo.nixosModules.default ]; }",
+    );
+
+    // dotted paths continue after the imports, and a header goes before the arguments
+    let mut ser = Serializer::new(Vec::new())
+        .attr_paths(AttrPaths::Dotted { max_attrs: 1 })
+        .header("generated");
+    let config = nix!({
+        services.nginx.enable = true;
+    });
+    ser.serialize_module(&config, &module.clone().args(["lib"]))
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "/* generated */ { lib, ... }: { imports = [ ./hardware-configuration.nix inputs.foo.nixosModules.default ]; services.nginx.enable = true; }",
+    );
+
+    let options = nix!({
+        services.foo.enable = (RawExpr::new("lib.mkEnableOption \"foo\""));
+    });
+    let pretty = serde_nix::to_module_string_pretty(&config, &module.options(options)).unwrap();
+    assert_eq!(
+        pretty,
+        r#"{ config, lib, pkgs, ... }:
+{
+  imports = [
+    ./hardware-configuration.nix
+    inputs.foo.nixosModules.default
+  ];
+  options = {
+    services = {
+      foo = {
+        enable = lib.mkEnableOption "foo";
+      };
+    };
+  };
+  config = {
+    services = {
+      nginx = {
+        enable = true;
+      };
+    };
+  };
+}"#,
+    );
+    assert!(serde_nix::syntax::parse(&pretty).is_ok());
+
+    assert!(matches!(
+        serde_nix::to_module_string(&person, &Module::new().args(["pkgs", "with"])),
+        Err(Error::InvalidModuleArg(arg)) if arg == "with"
+    ));
+    assert!(matches!(
+        serde_nix::to_module_string(&vec![1], &Module::new()),
+        Err(Error::ModuleMustBeAnAttrset)
+    ));
+    // a commented attrset is still an attrset, and so is a map whose keys get stringified
+    assert_eq!(
+        serde_nix::to_module_string(&Commented::new("foo", &person), &Module::new()).unwrap(),
+        r#"{ config, lib, pkgs, ... }: /* foo */ { name = "foo"; age = 20; }"#,
+    );
+    assert_eq!(
+        serde_nix::to_module_string(
+            &Commented::new("foo", &person),
+            &Module::new().import(Value::Path("./a.nix".into()))
+        )
+        .unwrap(),
+        r#"{ config, lib, pkgs, ... }: { imports = [ ./a.nix ]; /* foo */ name = "foo"; age = 20; }"#,
+    );
+    let mut ports = BTreeMap::new();
+    ports.insert(80, "http");
+    assert_eq!(
+        to_strings!(|ser| ser.map_keys(MapKeys::Stringify), write: |ser| ser
+            .serialize_module(&ports, &Module::new().args(None::<&str>)))
+        .unwrap()[0],
+        r#"{ ... }: { "80" = "http"; }"#,
+    );
+}
+
+#[test]
+fn test_module_functions() {
+    use serde_nix::ser::{AttrPaths, Serializer};
+    use serde_nix::{
+        nix, MkAfter, MkBefore, MkDefault, MkForce, MkIf, MkMerge, MkOverride, RawExpr, Value,
+    };
+
+    assert_eq!(
+        serde_nix::to_string(&MkDefault("foo")).unwrap(),
+        r#"lib.mkDefault "foo""#
+    );
+    // calls and their arguments get parentheses where they need them
+    assert_eq!(
+        serde_nix::to_string(&(MkBefore(vec![1]), MkAfter(vec![-2]))).unwrap(),
+        "[ (lib.mkBefore [ 1 ]) (lib.mkAfter [ (-2) ]) ]",
+    );
+    assert_eq!(
+        serde_nix::to_string(&MkIf(true, MkOverride(-10, -1.5))).unwrap(),
+        "lib.mkIf true (lib.mkOverride (-10) (-1.5))",
+    );
+    let cond = RawExpr::new("config.a && !config.b");
+    let merged = MkMerge(vec![
+        serde_nix::to_value(&MkIf(
+            cond,
+            nix!({
+                x = 1;
+            }),
+        ))
+        .unwrap(),
+        serde_nix::to_value(&MkForce(nix!({
+            y = [(MkDefault(2))];
+        })))
+        .unwrap(),
+        nix!({
+            z = null;
+        }),
+    ]);
+    assert_eq!(
+        serde_nix::to_string(&merged).unwrap(),
+        "lib.mkMerge [ (lib.mkIf (config.a && !config.b) { x = 1; }) (lib.mkForce { y = [ (lib.mkDefault 2) ]; }) { z = null; } ]",
+    );
+
+    // a call is never split into dotted paths
+    let mut services = HashMap::new();
+    services.insert(
+        "nginx",
+        MkForce(nix!({
+            enable = true;
+        })),
+    );
+    let mut config = HashMap::new();
+    config.insert("services", services);
+    let mut ser = Serializer::pretty(Vec::new()).attr_paths(AttrPaths::Dotted { max_attrs: 1 });
+    config.serialize(&mut ser).unwrap();
+    assert_eq!(
+        String::from_utf8(ser.into_inner()).unwrap(),
+        "{\n  services.nginx = lib.mkForce {\n    enable = true;\n  };\n}",
+    );
+
+    assert_eq!(
+        serde_nix::to_value(&MkOverride(900, vec![1])).unwrap(),
+        Value::Raw(RawExpr::new("lib.mkOverride 900 [ 1 ]")),
+    );
+    assert_eq!(
+        serde_json::to_string(&MkOverride(900, true)).unwrap(),
+        r#"{"$serde_nix::private::Apply":"lib.mkOverride","priority":900,"value":true}"#,
+    );
+}
+
+#[cfg(test)]
+fn round_trip<T>(v: T) -> Result<(), Error>
+where
+    T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    let nix_str = serde_nix::to_string(&v)?;
+    println!("nix str: {}", nix_str);
+    // evaluate with the nix interpreter, convert to json, and parse that json. :|
+    let json = std::process::Command::new("nix-instantiate")
+        .args(["--eval", "--json", "-E", &nix_str])
+        .output()
+        .expect("could not run nix-instantiate");
+
+    let json_str = String::from_utf8(json.stdout).unwrap();
+    let obj: T = serde_json::from_str(&json_str).unwrap();
+
+    assert_eq!(v, obj);
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn nix_eval(expr: &str) -> String {
+    let out = std::process::Command::new("nix-instantiate")
+        .args(["--eval", "-E", expr])
+        .output()
+        .expect("could not run nix-instantiate");
+    String::from_utf8(out.stdout).unwrap().trim().to_string()
+}
+
+#[test]
+fn test_floats_through_nix() {
+    for f in [1.0, 0.0, -2.0, 1e-7, 1e16, 1e300, -1.5e-300, 123.456] {
+        let nix_str = serde_nix::to_string(&f).unwrap();
+        assert_eq!(
+            nix_eval(&format!("builtins.typeOf ({})", nix_str)),
+            r#""float""#,
+            "{}",
+            nix_str,
+        );
+    }
+    assert_eq!(
+        nix_eval(&format!(
+            "map builtins.typeOf {}",
+            serde_nix::to_string(&vec![1.0, -1.0]).unwrap()
+        )),
+        r#"[ "float" "float" ]"#,
+    );
+    round_trip(vec![1.0, -1.0, 1e-7, 1e16, f64::MAX, f64::MIN_POSITIVE]).unwrap();
+}
+
+#[test]
+fn test_round_trip_through_nix() {
+    round_trip(1).unwrap();
+    round_trip(vec![1, 2, 3, 4]).unwrap();
+    round_trip("foo".to_string()).unwrap();
+    round_trip(Person {
+        age: 10,
+        name: "foo".to_string(),
+    })
+    .unwrap();
+    round_trip((1, 2, 3)).unwrap();
+    round_trip(vec![-1, 2, -3]).unwrap();
+    round_trip(vec![i64::MIN, i64::MAX]).unwrap();
+    round_trip(vec![-1.5, 2.5]).unwrap();
+    round_trip(Shape::Empty).unwrap();
+    round_trip(Shape::Circle(1)).unwrap();
+    round_trip(Shape::Move(1, 2)).unwrap();
+    round_trip(Shape::Point { x: 1, y: 2 }).unwrap();
+    round_trip(vec![Shape::Move(1, 2), Shape::Point { x: 3, y: 4 }]).unwrap();
+
+    round_trip("if".to_string()).unwrap();
+    round_trip(vec!["if".to_string()]).unwrap();
+    let m = {
+        let mut m = HashMap::new();
+        // https://github.com/NixOS/nix/blob/master/src/libexpr/lexer.l#L109-L118
+        for kw in vec![
+            "if", "then", "else", "assert", "with", "let", "in", "rec", "inherit", "or",
+        ] {
+            m.insert(kw.to_string(), kw.to_string());
+        }
+        m
+    };
+    round_trip(m).unwrap();
+}
+
+#[quickcheck]
+fn quickcheck_hashmap_keys(m: HashMap<String, String>) -> bool {
+    // nix can't do null keys, don't bother
+    if m.keys().chain(m.values()).any(|k| k.contains('\0')) {
+        return true;
+    }
+    println!("Debug: {:?}", m);
+    round_trip(m).unwrap();
+    true
+}
+
+#[quickcheck]
+fn quickcheck_int_lists(v: Vec<i64>) -> bool {
+    round_trip(v).unwrap();
+    true
+}
+
+#[quickcheck]
+fn quickcheck_float_lists(v: Vec<f64>) -> bool {
+    // nix has no way to write these
+    if v.iter().any(|f| !f.is_finite()) {
+        return true;
+    }
+    round_trip(v).unwrap();
+    true
+}
+
+#[quickcheck]
+fn quickcheck_indented_strings(m: HashMap<String, Vec<String>>) -> bool {
+    if m.values().flatten().any(|s| s.contains('\0')) {
+        return true;
+    }
+    for nix in to_strings!(&m, |ser| ser.multiline_strings(MultilineStrings::Indented)).unwrap() {
+        let parsed: HashMap<String, Vec<String>> = serde_nix::from_str(&nix).unwrap();
+        assert_eq!(parsed, m, "{}", nix);
+    }
+    true
+}
+
+#[quickcheck]
+fn quickcheck_dotted_attr_paths(paths: Vec<(u8, u8, String, u8)>) -> bool {
+    if paths.iter().any(|(_, _, k, _)| k.contains('\0')) {
+        return true;
+    }
+    // few enough names on the upper levels that attrsets of every size come up
+    let mut m: HashMap<String, HashMap<String, HashMap<String, u8>>> = HashMap::new();
+    for (a, b, c, v) in paths {
+        m.entry(format!("a{}", a % 4))
+            .or_default()
+            .entry(format!("b{}", b % 4))
+            .or_default()
+            .insert(c, v);
+    }
+    for max_attrs in 1..4 {
+        for nix in to_strings!(&m, |ser| ser.attr_paths(AttrPaths::Dotted { max_attrs })).unwrap() {
+            let parsed: HashMap<String, HashMap<String, HashMap<String, u8>>> =
+                serde_nix::from_str(&nix).unwrap();
+            assert_eq!(parsed, m, "{}", nix);
+        }
     }
-    println!("Debug: {:?}", m);
-    round_trip(m).unwrap();
     true
 }
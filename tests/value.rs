@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_nix::ser::Error;
+use serde_nix::value::Map;
+use serde_nix::{to_value, Value};
+
+#[derive(Serialize)]
+struct Service {
+    name: String,
+    port: u16,
+    weight: f32,
+    user: Option<String>,
+    args: Vec<&'static str>,
+    mode: Mode,
+}
+
+#[derive(Serialize)]
+enum Mode {
+    Simple,
+    Forking { pid_file: String },
+}
+
+#[test]
+fn test_to_value() {
+    let service = Service {
+        name: "nginx".to_string(),
+        port: 80,
+        weight: 0.5,
+        user: None,
+        args: vec!["-g", "daemon off;"],
+        mode: Mode::Forking {
+            pid_file: "/run/nginx.pid".to_string(),
+        },
+    };
+    let value = to_value(&service).unwrap();
+
+    let mut mode = Map::new();
+    mode.insert(
+        "Forking".to_string(),
+        vec![("pid_file", "/run/nginx.pid")].into_iter().collect(),
+    );
+    let expected: Value = vec![
+        ("name", Value::from("nginx")),
+        ("port", Value::Int(80)),
+        ("weight", Value::Float(0.5)),
+        ("user", Value::Null),
+        ("args", Value::from(vec!["-g", "daemon off;"])),
+        ("mode", Value::Attrs(mode)),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(value, expected);
+
+    // attributes keep their order
+    let keys: Vec<_> = value.as_attrs().unwrap().keys().cloned().collect();
+    assert_eq!(keys, vec!["name", "port", "weight", "user", "args", "mode"]);
+
+    assert_eq!(
+        to_value(&Mode::Simple).unwrap(),
+        Value::String("Simple".to_string())
+    );
+    assert_eq!(to_value(&b"hi"[..]).unwrap(), Value::from(vec![104, 105]));
+
+    // floats print as they would be written directly
+    for f in [0.1f32, 1.0e-7, 3.4028235e38, -2.5] {
+        assert_eq!(
+            to_value(&f).unwrap().to_string(),
+            serde_nix::to_string(&f).unwrap()
+        );
+        assert_eq!(Value::from(f), to_value(&f).unwrap());
+    }
+}
+
+#[test]
+fn test_to_value_errors() {
+    assert!(matches!(
+        to_value("a\0b"),
+        Err(Error::UnencodableNullString(_))
+    ));
+    let mut m = BTreeMap::new();
+    m.insert("a\0b", 1);
+    assert!(matches!(to_value(&m), Err(Error::UnencodableNullString(_))));
+    let mut m = BTreeMap::new();
+    m.insert(1, 1);
+    assert!(matches!(to_value(&m), Err(Error::MapKeyMustBeAString)));
+    assert!(matches!(
+        to_value(&u64::MAX),
+        Err(Error::IntegerOutOfRange(_))
+    ));
+    assert!(matches!(to_value(&f64::NAN), Err(Error::NonFiniteFloat(_))));
+}
+
+#[test]
+fn test_value_display() {
+    let mut value = to_value(&Service {
+        name: "nginx".to_string(),
+        port: 80,
+        weight: 1.0,
+        user: Some("www".to_string()),
+        args: vec![],
+        mode: Mode::Simple,
+    })
+    .unwrap();
+    let rendered = r#"{ name = "nginx"; port = 80; weight = 1.0; user = "www"; args = [ ]; mode = "Simple"; }"#;
+    assert_eq!(value.to_string(), rendered);
+    assert_eq!(serde_nix::to_string(&value).unwrap(), rendered);
+
+    // post-process before writing
+    let attrs = value.as_attrs_mut().unwrap();
+    attrs.shift_remove("weight");
+    attrs.insert("if".to_string(), Value::from(vec![-1, 2]));
+    attrs.insert(
+        "configFile".to_string(),
+        Value::Path("./nginx.conf".to_string()),
+    );
+    *value.get_mut("port").unwrap() = Value::Int(8080);
+    assert_eq!(
+        value.to_string(),
+        r#"{ name = "nginx"; port = 8080; user = "www"; args = [ ]; mode = "Simple"; "if" = [ (-1) 2 ]; configFile = ./nginx.conf; }"#,
+    );
+    assert_eq!(
+        format!("{:#}", Value::from(vec![1])),
+        "[\n  1\n]".to_string()
+    );
+
+    // what to_string can't write still displays
+    let value = Value::from(vec![
+        Value::Float(f64::NAN),
+        Value::String("a\0".to_string()),
+        Value::Path(String::new()),
+        Value::Path("/".to_string()),
+    ]);
+    assert!(serde_nix::to_string(&value).is_err());
+    assert_eq!(value.to_string(), "[ null \"a\u{fffd}\" \"\" /. ]");
+}
+
+#[test]
+fn test_paths() {
+    for (path, literal) in [
+        ("./foo.nix", "./foo.nix"),
+        ("/etc/nixos/", "/etc/nixos"),
+        ("/", "/."),
+        ("foo", "./foo"),
+        ("foo/bar", "foo/bar"),
+        ("../a-b_c+d.e", "../a-b_c+d.e"),
+        ("~/.config", "~/.config"),
+    ] {
+        assert_eq!(
+            Value::Path(path.to_string()).to_string(),
+            literal,
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        serde_nix::to_string(&Value::from(vec![Value::Path("/a".to_string())])).unwrap(),
+        "[ /a ]",
+    );
+    for (path, expr) in [
+        ("/a b", r#"/. + "/a b""#),
+        ("a//b", r#"./. + "/a//b""#),
+        ("./a b", r#"./. + "/a b""#),
+        ("/a/${b}", r#"/. + "/a/\${b}""#),
+    ] {
+        assert_eq!(Value::Path(path.to_string()).to_string(), expr, "{}", path);
+    }
+    for path in ["", "~", "~/a b"] {
+        assert!(matches!(
+            serde_nix::to_string(&Value::Path(path.to_string())),
+            Err(Error::InvalidPath(_))
+        ));
+    }
+    // other serializers see a string
+    assert_eq!(
+        serde_json::to_string(&Value::Path("/a".to_string())).unwrap(),
+        r#""/a""#,
+    );
+}
+
+#[test]
+fn test_raw_exprs() {
+    use serde_nix::RawExpr;
+
+    let mut attrs = Map::new();
+    attrs.insert(
+        "package".to_string(),
+        Value::Raw(RawExpr::new("pkgs.hello")),
+    );
+    attrs.insert(
+        "list".to_string(),
+        Value::from(vec![Value::from(RawExpr::new("lib.mkForce true"))]),
+    );
+    let value = Value::Attrs(attrs);
+    assert_eq!(
+        value.to_string(),
+        "{ package = pkgs.hello; list = [ (lib.mkForce true) ]; }"
+    );
+    assert_eq!(to_value(&value).unwrap(), value);
+    assert_eq!(
+        to_value(&RawExpr::new("pkgs.hello")).unwrap(),
+        Value::Raw(RawExpr::new("pkgs.hello"))
+    );
+
+    // there's nothing to read back out of an unevaluated expression
+    assert_eq!(
+        serde_nix::from_value::<String>(Value::Raw(RawExpr::new("pkgs.hello")))
+            .unwrap_err()
+            .to_string(),
+        "invalid type: raw nix expression, expected a string",
+    );
+}
+
+#[test]
+fn test_omit_null() {
+    use serde_nix::OmitNull;
+
+    #[derive(Serialize)]
+    struct Options {
+        a: OmitNull<Option<i32>>,
+        b: OmitNull<Option<i32>>,
+        c: Option<i32>,
+    }
+    let value = to_value(&Options {
+        a: OmitNull(None),
+        b: OmitNull(Some(1)),
+        c: None,
+    })
+    .unwrap();
+    assert_eq!(value.to_string(), "{ b = 1; c = null; }");
+}